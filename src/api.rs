@@ -1,12 +1,26 @@
-use crate::state::AsyncState;
-use axum::{extract::State as AxumState, routing::get, Json, Router};
+use crate::session::AsyncSessions;
+use crate::settings::{AppConfig, OutputConfig};
+use crate::state::{send_synthetic_updates, AsyncState};
+use crate::update::{
+    format_peer_type, format_rd, format_update_json, is_ipv4, map_to_ipv6, Update,
+};
+use axum::extract::{Query, State as AxumState};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use chrono::Utc;
 use core::net::IpAddr;
-use metrics::{Key, Label, Recorder};
-use metrics_exporter_prometheus::PrometheusBuilder;
+use core::net::SocketAddr;
+use futures_util::Stream;
+use ipnet::IpNet;
+use metrics::counter;
+use metrics_exporter_prometheus::PrometheusHandle;
 use serde::{Deserialize, Serialize};
-
-static METADATA: metrics::Metadata =
-    metrics::Metadata::new(module_path!(), metrics::Level::INFO, Some(module_path!()));
+use std::convert::Infallible;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct APIRouter {
@@ -17,30 +31,130 @@ struct APIRouter {
 #[derive(Debug, Serialize, Deserialize)]
 struct APIPeer {
     peer_addr: IpAddr,
+    peer_type: String,
     ipv4: usize,
     ipv6: usize,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct APISession {
+    socket: SocketAddr,
+    bmp_version: Option<u8>,
+    sys_name: Option<String>,
+    connected_at: i64,
+    uptime_secs: i64,
+    messages: u64,
+    peers: Vec<APISessionPeer>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct APISessionPeer {
+    peer_addr: IpAddr,
+    peer_rd: Option<String>,
+    established_at: i64,
+    session_uptime_secs: i64,
+}
+
+// Live stream filters, given as query parameters
+// e.g. /stream?router=192.0.2.1&prefix=2001:db8::/32
+#[derive(Debug, Deserialize)]
+struct StreamFilter {
+    router: Option<IpAddr>,
+    peer: Option<IpAddr>,
+    prefix: Option<IpNet>,
+}
+
+impl StreamFilter {
+    fn matches(&self, update: &Update) -> bool {
+        self.router
+            .is_none_or(|router| map_to_ipv6(router) == map_to_ipv6(update.router_addr))
+            && self
+                .peer
+                .is_none_or(|peer| map_to_ipv6(peer) == map_to_ipv6(update.peer_addr))
+            && self
+                .prefix
+                .is_none_or(|prefix| prefix.contains(&update.prefix.prefix))
+    }
+}
+
+// Peers to remove from the state, all the peers of the router if none is given
+// e.g. DELETE /state?router=192.0.2.1&peer=2001:db8::1
+#[derive(Debug, Deserialize)]
+struct StateFilter {
+    router: IpAddr,
+    peer: Option<IpAddr>,
+}
+
+// Address or prefix to look up in the RIB of a router peer
+// e.g. /lookup?router=192.0.2.1&peer=2001:db8::1&addr=198.51.100.1
+#[derive(Debug, Deserialize)]
+struct LookupFilter {
+    router: IpAddr,
+    peer: IpAddr,
+    addr: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct APIMatch {
+    prefix: IpNet,
+    path_id: u32,
+    is_post_policy: bool,
+    is_adj_rib_out: bool,
+    timestamp: i64,
+    stale: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct APIRemoved {
+    withdrawn: usize,
+}
+
 #[derive(Clone)]
 struct AppState {
     state: AsyncState,
+    sessions: AsyncSessions,
+    prometheus: PrometheusHandle,
+    stream_tx: Option<broadcast::Sender<Update>>,
+    tx: Sender<Vec<Update>>,
+    admin_enable: bool,
+    output: OutputConfig,
+    config: Arc<AppConfig>,
 }
 
-pub fn app(state: AsyncState) -> Router {
+pub fn app(
+    state: AsyncState,
+    sessions: AsyncSessions,
+    prometheus: PrometheusHandle,
+    stream_tx: Option<broadcast::Sender<Update>>,
+    tx: Sender<Vec<Update>>,
+    cfg: AppConfig,
+) -> Router {
     let app_state = AppState {
         state: state.clone(),
+        sessions,
+        prometheus,
+        stream_tx,
+        tx,
+        admin_enable: cfg.api.admin_enable,
+        output: cfg.output.clone(),
+        config: Arc::new(cfg),
     };
 
     Router::new()
         .route("/", get(root).with_state(app_state.clone()))
+        .route("/routers", get(routers).with_state(app_state.clone()))
         .route("/metrics", get(metrics).with_state(app_state.clone()))
+        .route("/stream", get(stream).with_state(app_state.clone()))
+        .route("/lookup", get(lookup).with_state(app_state.clone()))
+        .route("/state", delete(remove_state).with_state(app_state.clone()))
+        .route("/config", get(config).with_state(app_state.clone()))
 }
 
 async fn format(state: AsyncState) -> Vec<APIRouter> {
     let mut api_routers: Vec<APIRouter> = Vec::new();
     let state = state.lock().unwrap();
 
-    for (router_addr, peer_addr, update_prefix) in state.get_all().unwrap() {
+    for (router_addr, peer_addr, peer_type, update_prefix) in state.get_all().unwrap() {
         // Find the router in the list of routers
         let mut router = None;
         for r in &mut api_routers {
@@ -78,6 +192,7 @@ async fn format(state: AsyncState) -> Vec<APIRouter> {
             None => {
                 let p = APIPeer {
                     peer_addr,
+                    peer_type: format_peer_type(peer_type).to_string(),
                     ipv4: 0,
                     ipv6: 0,
                 };
@@ -86,7 +201,7 @@ async fn format(state: AsyncState) -> Vec<APIRouter> {
             }
         };
 
-        if update_prefix.prefix.prefix.addr().is_ipv4() {
+        if is_ipv4(update_prefix.prefix.prefix.addr()) {
             peer.ipv4 += 1;
         } else {
             peer.ipv6 += 1;
@@ -100,39 +215,157 @@ async fn root(AxumState(AppState { state, .. }): AxumState<AppState>) -> Json<Ve
     Json(api_routers)
 }
 
-async fn metrics(AxumState(AppState { state }): AxumState<AppState>) -> String {
-    let recorder = PrometheusBuilder::new().build_recorder();
-    let api_routers = format(state).await;
+async fn routers(
+    AxumState(AppState { sessions, .. }): AxumState<AppState>,
+) -> Json<Vec<APISession>> {
+    let now = Utc::now();
+    let sessions = sessions.lock().unwrap().get_all();
+    let api_sessions = sessions
+        .into_iter()
+        .map(|session| APISession {
+            socket: session.socket,
+            bmp_version: session.bmp_version,
+            sys_name: session.sys_name,
+            connected_at: session.connected_at.timestamp_millis(),
+            uptime_secs: (now - session.connected_at).num_seconds(),
+            messages: session.messages,
+            peers: session
+                .established_peers
+                .iter()
+                .map(|(peer, established_at)| APISessionPeer {
+                    peer_addr: peer.peer_addr,
+                    peer_rd: format_rd(peer.peer_distinguisher),
+                    established_at: established_at.timestamp_millis(),
+                    session_uptime_secs: (now - *established_at).num_seconds().max(0),
+                })
+                .collect(),
+        })
+        .collect();
+    Json(api_sessions)
+}
 
-    recorder.describe_gauge(
-        "risotto_bgp_peers".into(),
-        None,
-        "Number of BGP peers per router".into(),
-    );
-    for api_router in &api_routers {
-        let labels = vec![Label::new("router", api_router.router_addr.to_string())];
-        let key = Key::from_parts("risotto_bgp_peers", labels);
-        recorder
-            .register_gauge(&key, &METADATA)
-            .set(api_router.peers.len() as f64);
+// Longest prefix match of an address (or a prefix) in the RIB of a router peer, for a looking glass
+// A prefix monitored in several RIBs (e.g. pre and post-policy) has one match per RIB
+async fn lookup(
+    AxumState(AppState { state, .. }): AxumState<AppState>,
+    Query(filter): Query<LookupFilter>,
+) -> Result<Json<Vec<APIMatch>>, StatusCode> {
+    let prefix = match filter.addr.parse::<IpAddr>() {
+        Ok(addr) => IpNet::from(addr),
+        Err(_) => filter
+            .addr
+            .parse::<IpNet>()
+            .map_err(|_| StatusCode::BAD_REQUEST)?,
+    };
+
+    let matches = state
+        .lock()
+        .unwrap()
+        .longest_match(&filter.router, &filter.peer, &prefix);
+    let api_matches = matches
+        .into_iter()
+        .map(|prefix| APIMatch {
+            prefix: prefix.prefix.prefix,
+            path_id: prefix.prefix.path_id,
+            is_post_policy: prefix.is_post_policy,
+            is_adj_rib_out: prefix.is_adj_rib_out,
+            timestamp: prefix.timestamp,
+            stale: prefix.stale,
+        })
+        .collect();
+    Ok(Json(api_matches))
+}
+
+// Remove stale peers (e.g. of a decommissioned router) from the state
+// As this mutates the state over HTTP, it must be enabled in the configuration
+async fn remove_state(
+    AxumState(AppState {
+        state,
+        tx,
+        admin_enable,
+        ..
+    }): AxumState<AppState>,
+    Query(filter): Query<StateFilter>,
+) -> Result<Json<APIRemoved>, StatusCode> {
+    if !admin_enable {
+        return Err(StatusCode::FORBIDDEN);
     }
 
-    recorder.describe_gauge(
-        "risotto_bgp_updates".into(),
-        None,
-        "Number of BGP updates per (router, peer)".into(),
+    let (cfg, synthetic_updates) = {
+        let mut state = state.lock().unwrap();
+        let synthetic_updates = state.remove_peers(&filter.router, filter.peer.as_ref());
+        (state.config().clone(), synthetic_updates)
+    };
+
+    let withdrawn = synthetic_updates.len();
+    log::info!(
+        "api - state removal - {} - {} emitting {} synthetic withdraw updates",
+        filter.router,
+        filter
+            .peer
+            .map_or("all peers".to_string(), |peer| peer.to_string()),
+        withdrawn
+    );
+
+    // Sent to the event pipeline
+    tokio::spawn(async move { send_synthetic_updates(&cfg, synthetic_updates, tx).await });
+    Ok(Json(APIRemoved { withdrawn }))
+}
+
+async fn metrics(
+    AxumState(AppState {
+        sessions,
+        prometheus,
+        ..
+    }): AxumState<AppState>,
+) -> String {
+    // The gauges are maintained by the state, so the scrape does not walk it
+    // Only the peer uptimes are set on scrape, as they change continuously
+    sessions.lock().unwrap().record_uptimes();
+    prometheus.render()
+}
+
+// Effective configuration, to check the defaults in effect, with the secrets redacted
+async fn config(AxumState(AppState { config, .. }): AxumState<AppState>) -> Json<AppConfig> {
+    Json(config.as_ref().clone())
+}
+
+// Server-sent events of the emitted updates, as JSON lines
+// A client lagging behind is disconnected rather than back-pressuring the pipeline
+async fn stream(
+    AxumState(AppState {
+        stream_tx, output, ..
+    }): AxumState<AppState>,
+    Query(filter): Query<StreamFilter>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let Some(stream_tx) = stream_tx else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let rx = stream_tx.subscribe();
+    let events = futures_util::stream::unfold(
+        (rx, filter, output),
+        |(mut rx, filter, output)| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(update) if filter.matches(&update) => {
+                        let event = Event::default().data(format_update_json(&update, &output));
+                        return Some((Ok(event), (rx, filter, output)));
+                    }
+                    Ok(_) => (),
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!(
+                            "api - stream client lagging behind by {} updates, disconnecting",
+                            skipped
+                        );
+                        counter!("risotto_stream_dropped_clients_total").increment(1);
+                        return None;
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
     );
-    for api_router in &api_routers {
-        for api_peer in &api_router.peers {
-            let total = api_peer.ipv4 + api_peer.ipv6;
-            let labels = vec![
-                Label::new("router", api_router.router_addr.to_string()),
-                Label::new("peer", api_peer.peer_addr.to_string()),
-            ];
-            let key = Key::from_parts("risotto_bgp_updates", labels);
-            recorder.register_gauge(&key, &METADATA).set(total as f64);
-        }
-    }
 
-    recorder.handle().render()
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
 }
@@ -0,0 +1,390 @@
+use arrow::array::{
+    ArrayRef, BinaryArray, BooleanArray, ListBuilder, RecordBatch, StringArray, StringBuilder,
+    StructBuilder, TimestampMillisecondArray, UInt16Array, UInt16Builder, UInt32Array,
+    UInt32Builder, UInt64Array, UInt8Array,
+};
+use arrow::datatypes::{DataType, Field, Fields, Schema, SchemaRef, TimeUnit};
+use chrono::Utc;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::error::Error;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_graceful::ShutdownGuard;
+
+use crate::settings::{OutputConfig, ParquetConfig};
+use crate::update::{
+    construct_as_path, construct_communities, construct_ext_communities, format_peer_type,
+    format_rd, output_addr, output_bgp_id, output_family, Update,
+};
+
+fn community_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("asn", DataType::UInt32, false),
+        Field::new("value", DataType::UInt16, false),
+    ])
+}
+
+// Columns follow the CSV schema emitted to Kafka (see `format_update`)
+fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("router_addr", DataType::Utf8, false),
+        Field::new("router_port", DataType::UInt16, false),
+        Field::new("peer_addr", DataType::Utf8, false),
+        Field::new("peer_bgp_id", DataType::Utf8, false),
+        Field::new("peer_asn", DataType::UInt32, false),
+        Field::new("prefix_addr", DataType::Utf8, false),
+        Field::new("prefix_len", DataType::UInt8, false),
+        Field::new("is_post_policy", DataType::Boolean, false),
+        Field::new("is_adj_rib_out", DataType::Boolean, false),
+        Field::new("announced", DataType::Boolean, false),
+        Field::new("origin", DataType::Utf8, false),
+        Field::new(
+            "path",
+            DataType::List(Arc::new(Field::new("item", DataType::UInt32, true))),
+            false,
+        ),
+        Field::new(
+            "communities",
+            DataType::List(Arc::new(Field::new(
+                "item",
+                DataType::Struct(community_fields()),
+                true,
+            ))),
+            false,
+        ),
+        Field::new("synthetic", DataType::Boolean, false),
+        Field::new("peer_type", DataType::Utf8, false),
+        Field::new("peer_distinguisher", DataType::UInt64, false),
+        Field::new("rpki_status", DataType::Utf8, true),
+        Field::new("as_path_has_loop", DataType::Boolean, false),
+        Field::new("header_time_valid", DataType::Boolean, false),
+        Field::new("otc_leak_suspect", DataType::Boolean, false),
+        Field::new("raw_pdu", DataType::Binary, true),
+        Field::new("as_path_truncated", DataType::Boolean, false),
+        Field::new("prefix_sid_label_index", DataType::UInt32, true),
+        Field::new("prefix_sid_srv6_sid", DataType::Utf8, true),
+        Field::new("seq", DataType::UInt64, false),
+        Field::new(
+            "seq_epoch",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("peer_rd", DataType::Utf8, true),
+        Field::new("as_path_prepend_count", DataType::UInt8, false),
+        Field::new(
+            "ext_communities",
+            DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))),
+            false,
+        ),
+        Field::new("med", DataType::UInt32, true),
+        Field::new("local_pref", DataType::UInt32, true),
+        Field::new("only_to_customer", DataType::UInt32, true),
+    ]))
+}
+
+fn to_record_batch(
+    schema: SchemaRef,
+    output: &OutputConfig,
+    updates: &[Update],
+) -> Result<RecordBatch, Box<dyn Error>> {
+    let mut path_builder = ListBuilder::new(UInt32Builder::new());
+    for update in updates {
+        path_builder
+            .values()
+            .append_slice(&construct_as_path(update.path.clone()));
+        path_builder.append(true);
+    }
+
+    let mut ext_communities_builder = ListBuilder::new(StringBuilder::new());
+    for update in updates {
+        for community in construct_ext_communities(&update.communities) {
+            ext_communities_builder.values().append_value(community);
+        }
+        ext_communities_builder.append(true);
+    }
+
+    let mut communities_builder = ListBuilder::new(StructBuilder::new(
+        community_fields(),
+        vec![
+            Box::new(UInt32Builder::new()),
+            Box::new(UInt16Builder::new()),
+        ],
+    ));
+    for update in updates {
+        let communities = communities_builder.values();
+        for (asn, value) in construct_communities(&update.communities) {
+            communities
+                .field_builder::<UInt32Builder>(0)
+                .unwrap()
+                .append_value(asn);
+            communities
+                .field_builder::<UInt16Builder>(1)
+                .unwrap()
+                .append_value(value);
+            communities.append(true);
+        }
+        communities_builder.append(true);
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(
+            TimestampMillisecondArray::from_iter_values(
+                updates.iter().map(|u| u.timestamp.timestamp_millis()),
+            )
+            .with_timezone("UTC"),
+        ),
+        Arc::new(StringArray::from_iter_values(
+            updates
+                .iter()
+                .map(|u| output_addr(u.router_addr, output).to_string()),
+        )),
+        Arc::new(UInt16Array::from_iter_values(
+            updates.iter().map(|u| u.router_port),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            updates
+                .iter()
+                .map(|u| output_addr(u.peer_addr, output).to_string()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            updates
+                .iter()
+                .map(|u| output_bgp_id(u.peer_bgp_id, output).to_string()),
+        )),
+        Arc::new(UInt32Array::from_iter_values(
+            updates.iter().map(|u| u.peer_asn.to_u32()),
+        )),
+        Arc::new(StringArray::from_iter_values(updates.iter().map(|u| {
+            output_family(u.prefix.prefix.addr(), output).to_string()
+        }))),
+        Arc::new(UInt8Array::from_iter_values(
+            updates.iter().map(|u| u.prefix.prefix.prefix_len()),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            updates.iter().map(|u| Some(u.is_post_policy)),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            updates.iter().map(|u| Some(u.is_adj_rib_out)),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            updates.iter().map(|u| Some(u.announced)),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            updates.iter().map(|u| u.origin.to_string()),
+        )),
+        Arc::new(path_builder.finish()),
+        Arc::new(communities_builder.finish()),
+        Arc::new(BooleanArray::from_iter(
+            updates.iter().map(|u| Some(u.synthetic)),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            updates.iter().map(|u| format_peer_type(u.peer_type)),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            updates.iter().map(|u| u.peer_distinguisher),
+        )),
+        Arc::new(StringArray::from_iter(
+            updates
+                .iter()
+                .map(|u| u.rpki_status.map(|status| status.as_str())),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            updates.iter().map(|u| Some(u.as_path_has_loop)),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            updates.iter().map(|u| Some(u.header_time_valid)),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            updates.iter().map(|u| Some(u.otc_leak_suspect)),
+        )),
+        Arc::new(BinaryArray::from_iter(
+            updates.iter().map(|u| u.raw_pdu.as_deref()),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            updates.iter().map(|u| Some(u.as_path_truncated)),
+        )),
+        Arc::new(UInt32Array::from_iter(
+            updates
+                .iter()
+                .map(|u| u.prefix_sid.and_then(|sid| sid.label_index)),
+        )),
+        Arc::new(StringArray::from_iter(updates.iter().map(|u| {
+            u.prefix_sid
+                .and_then(|sid| sid.srv6_sid)
+                .map(|sid| sid.to_string())
+        }))),
+        Arc::new(UInt64Array::from_iter_values(updates.iter().map(|u| u.seq))),
+        Arc::new(
+            TimestampMillisecondArray::from_iter_values(
+                updates.iter().map(|u| u.seq_epoch.timestamp_millis()),
+            )
+            .with_timezone("UTC"),
+        ),
+        Arc::new(StringArray::from_iter(
+            updates.iter().map(|u| format_rd(u.peer_distinguisher)),
+        )),
+        Arc::new(UInt8Array::from_iter_values(
+            updates.iter().map(|u| u.as_path_prepend_count),
+        )),
+        Arc::new(ext_communities_builder.finish()),
+        Arc::new(UInt32Array::from_iter(updates.iter().map(|u| u.med))),
+        Arc::new(UInt32Array::from_iter(updates.iter().map(|u| u.local_pref))),
+        Arc::new(UInt32Array::from_iter(
+            updates.iter().map(|u| u.only_to_customer),
+        )),
+    ];
+
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+// A Parquet file being written, renamed to its final name once closed
+struct ArchiveFile {
+    writer: ArrowWriter<File>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    opened_at: Instant,
+}
+
+impl ArchiveFile {
+    fn create(cfg: &ParquetConfig, schema: SchemaRef) -> Result<ArchiveFile, Box<dyn Error>> {
+        let name = format!("risotto-{}.parquet", Utc::now().format("%Y%m%dT%H%M%S%.3f"));
+        let path = PathBuf::from(&cfg.output_dir).join(name);
+        let tmp_path = path.with_extension("parquet.inprogress");
+
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let file = File::create(&tmp_path)?;
+        let writer = ArrowWriter::try_new(file, schema, Some(props))?;
+
+        Ok(ArchiveFile {
+            writer,
+            tmp_path,
+            path,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.writer.bytes_written() + self.writer.in_progress_size()
+    }
+
+    fn close(self) -> Result<PathBuf, Box<dyn Error>> {
+        self.writer.close()?;
+        std::fs::rename(&self.tmp_path, &self.path)?;
+        Ok(self.path)
+    }
+}
+
+fn rotate(file: Option<ArchiveFile>) {
+    if let Some(file) = file {
+        match file.close() {
+            Ok(path) => log::info!("archive - closed {}", path.display()),
+            Err(e) => log::error!("archive - failed to close parquet file: {}", e),
+        }
+    }
+}
+
+pub async fn handle(
+    cfg: &ParquetConfig,
+    output: &OutputConfig,
+    rx: Receiver<Vec<Update>>,
+    guard: ShutdownGuard,
+) {
+    if let Err(e) = std::fs::create_dir_all(&cfg.output_dir) {
+        log::error!(
+            "archive - failed to create output directory {}: {}",
+            cfg.output_dir,
+            e
+        );
+        return;
+    }
+
+    let schema = schema();
+    let rotate_size = (cfg.rotate_mb * 1024 * 1024) as usize;
+    let rotate_interval = Duration::from_secs(cfg.rotate_interval);
+    let mut current: Option<ArchiveFile> = None;
+
+    loop {
+        // Wait the batch wait time to collect updates, or the shutdown
+        let shutting_down = tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(cfg.batch_interval)) => false,
+            _ = guard.cancelled() => true,
+        };
+        let mut updates = Vec::new();
+        loop {
+            // Collect all of the updates from BMP handler
+            match rx.try_recv() {
+                Ok(u) => updates.extend(u),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    log::error!("archive - BMP handler disconnected");
+                    rotate(current.take());
+                    return;
+                }
+            }
+        }
+
+        // Close the current file if it is due for rotation
+        if let Some(file) = &current {
+            if file.size() >= rotate_size || file.opened_at.elapsed() >= rotate_interval {
+                rotate(current.take());
+            }
+        }
+
+        if !updates.is_empty() {
+            write(cfg, schema.clone(), output, &mut current, &updates);
+        }
+
+        // The updates collected so far are written, and the current file closed,
+        // so that no file is left in progress
+        // The task then waits for the collector to end, rather than ending it
+        if shutting_down {
+            rotate(current.take());
+            drop(guard);
+            return std::future::pending().await;
+        }
+    }
+}
+
+fn write(
+    cfg: &ParquetConfig,
+    schema: SchemaRef,
+    output: &OutputConfig,
+    current: &mut Option<ArchiveFile>,
+    updates: &[Update],
+) {
+    if current.is_none() {
+        match ArchiveFile::create(cfg, schema.clone()) {
+            Ok(file) => *current = Some(file),
+            Err(e) => {
+                log::error!("archive - failed to create parquet file: {}", e);
+                return;
+            }
+        }
+    }
+
+    let batch = match to_record_batch(schema, output, updates) {
+        Ok(batch) => batch,
+        Err(e) => {
+            log::error!("archive - failed to build record batch: {}", e);
+            return;
+        }
+    };
+
+    let file = current.as_mut().unwrap();
+    match file.writer.write(&batch) {
+        Ok(_) => log::debug!("archive - wrote {} updates", updates.len()),
+        Err(e) => log::error!("archive - failed writing updates: {}", e),
+    }
+}
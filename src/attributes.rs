@@ -0,0 +1,120 @@
+use core::net::Ipv6Addr;
+
+// BMP common header, BMP per-peer header and BGP header lengths
+const BMP_COMMON_HEADER_LEN: usize = 6;
+const BMP_HEADERS_LEN: usize = 6 + 42;
+const BGP_HEADER_LEN: usize = 19;
+const BGP_MARKER: [u8; 16] = [0xff; 16];
+const BGP_UPDATE: u8 = 2;
+
+// BGP Prefix-SID path attribute
+// https://datatracker.ietf.org/doc/html/rfc8669#section-3
+const BGP_PREFIX_SID: u8 = 40;
+const LABEL_INDEX_TLV: u8 = 1;
+const SRV6_L3_SERVICE_TLV: u8 = 5;
+const SRV6_L2_SERVICE_TLV: u8 = 6;
+const SRV6_SID_INFORMATION_SUB_TLV: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrefixSid {
+    pub label_index: Option<u32>,
+    pub srv6_sid: Option<Ipv6Addr>,
+}
+
+pub fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buf.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// BGP message of a BMP Route Monitoring message
+// Some routers send no per-peer header, the BGP marker is then right after the common header
+fn bgp_message(raw: &[u8]) -> Option<&[u8]> {
+    let offset = match raw.get(BMP_COMMON_HEADER_LEN..)?.starts_with(&BGP_MARKER) {
+        true => BMP_COMMON_HEADER_LEN,
+        false => BMP_HEADERS_LEN,
+    };
+    raw.get(offset..)
+}
+
+// Get the path attributes (type and value) of a BMP Route Monitoring message
+// Used for the attributes the BGP parser discards
+pub fn path_attributes(raw: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let bgp = bgp_message(raw)?;
+    if *bgp.get(BGP_HEADER_LEN - 1)? != BGP_UPDATE {
+        return None;
+    }
+
+    // Skip the withdrawn routes
+    let withdrawn_len = read_u16(bgp, BGP_HEADER_LEN)? as usize;
+    let offset = BGP_HEADER_LEN + 2 + withdrawn_len;
+    let attributes_len = read_u16(bgp, offset)? as usize;
+    let mut data = bgp.get(offset + 2..offset + 2 + attributes_len)?;
+
+    let mut attributes = Vec::new();
+    while data.len() >= 3 {
+        let flags = data[0];
+        let attr_type = data[1];
+        let (value_offset, length) = if flags & 0x10 != 0 {
+            (4, read_u16(data, 2)? as usize)
+        } else {
+            (3, data[2] as usize)
+        };
+        attributes.push((attr_type, data.get(value_offset..value_offset + length)?));
+        data = &data[value_offset + length..];
+    }
+    Some(attributes)
+}
+
+// Iterate over type-length-value entries, with a 2-byte length
+fn tlvs(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let tlv_type = *data.first()?;
+        let length = read_u16(data, 1)? as usize;
+        let value = data.get(3..3 + length)?;
+        data = &data[3 + length..];
+        Some((tlv_type, value))
+    })
+}
+
+// SRv6 SID Information Sub-TLV of an SRv6 Service TLV
+// https://datatracker.ietf.org/doc/html/rfc9252#section-3.1
+fn srv6_sid(value: &[u8]) -> Option<Ipv6Addr> {
+    // Skip the reserved byte
+    let sub_tlvs = value.get(1..)?;
+    for (sub_tlv_type, sub_tlv) in tlvs(sub_tlvs) {
+        if sub_tlv_type == SRV6_SID_INFORMATION_SUB_TLV {
+            let sid: [u8; 16] = sub_tlv.get(1..17)?.try_into().ok()?;
+            return Some(Ipv6Addr::from(sid));
+        }
+    }
+    None
+}
+
+// Get the Label-Index and the SRv6 SID of the BGP Prefix-SID attribute, if any
+pub fn prefix_sid(raw: &[u8]) -> Option<PrefixSid> {
+    let attributes = path_attributes(raw)?;
+    let (_, value) = attributes
+        .into_iter()
+        .find(|(attr_type, _)| *attr_type == BGP_PREFIX_SID)?;
+
+    let mut prefix_sid = PrefixSid {
+        label_index: None,
+        srv6_sid: None,
+    };
+    for (tlv_type, tlv) in tlvs(value) {
+        match tlv_type {
+            // Reserved (1 byte) and flags (2 bytes) precede the label index
+            LABEL_INDEX_TLV => prefix_sid.label_index = read_u32(tlv, 3),
+            SRV6_L3_SERVICE_TLV | SRV6_L2_SERVICE_TLV => {
+                prefix_sid.srv6_sid = prefix_sid.srv6_sid.or(srv6_sid(tlv))
+            }
+            _ => (),
+        }
+    }
+    Some(prefix_sid)
+}
@@ -0,0 +1,156 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use config::Config;
+use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::error::Error;
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bmp::{decode_bmp_message, process_bmp_packet};
+use crate::rpki;
+use crate::settings;
+use crate::state;
+use crate::update::format_update;
+
+const ROUTER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 4000);
+
+// Build a BMP Route Monitoring message announcing IPv4 /24 prefixes
+// https://datatracker.ietf.org/doc/html/rfc7854#section-4.6
+fn route_monitoring(
+    peer_addr: Ipv4Addr,
+    peer_asn: u32,
+    first_prefix: u32,
+    n_prefixes: u32,
+) -> Bytes {
+    let mut attributes = BytesMut::new();
+    // ORIGIN: IGP
+    attributes.put_slice(&[0x40, 1, 1, 0]);
+    // AS_PATH: a single AS_SEQUENCE segment
+    let path = [peer_asn, 64512, 64513 + first_prefix % 1000];
+    attributes.put_slice(&[0x40, 2, 2 + 4 * path.len() as u8, 2, path.len() as u8]);
+    for asn in path {
+        attributes.put_u32(asn);
+    }
+    // NEXT_HOP
+    attributes.put_slice(&[0x40, 3, 4]);
+    attributes.put_slice(&peer_addr.octets());
+
+    let mut nlri = BytesMut::new();
+    for n in first_prefix..first_prefix + n_prefixes {
+        // Distinct /24 prefixes, from 1.0.0.0/24 onwards
+        nlri.put_u8(24);
+        nlri.put_slice(&0x01000000u32.wrapping_add(n << 8).to_be_bytes()[..3]);
+    }
+
+    let mut bgp = BytesMut::new();
+    bgp.put_slice(&[0xff; 16]);
+    bgp.put_u16((19 + 4 + attributes.len() + nlri.len()) as u16);
+    bgp.put_u8(2);
+    bgp.put_u16(0);
+    bgp.put_u16(attributes.len() as u16);
+    bgp.put(attributes);
+    bgp.put(nlri);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let mut message = BytesMut::new();
+    message.put_u8(3);
+    message.put_u32((6 + 42 + bgp.len()) as u32);
+    message.put_u8(0);
+    // Per-peer header: global instance peer, pre-policy Adj-RIB-In
+    message.put_slice(&[0, 0]);
+    message.put_u64(0);
+    message.put_slice(&[0; 12]);
+    message.put_slice(&peer_addr.octets());
+    message.put_u32(peer_asn);
+    message.put_slice(&peer_addr.octets());
+    message.put_u32(now.as_secs() as u32);
+    message.put_u32(now.subsec_micros());
+    message.put(bgp);
+    message.freeze()
+}
+
+fn percentile(latencies: &[Duration], percentile: f64) -> Duration {
+    let index = ((latencies.len() as f64 * percentile).ceil() as usize).saturating_sub(1);
+    latencies.get(index).copied().unwrap_or_default()
+}
+
+// Feed synthetic Route Monitoring messages through the decoding, the state and the serialization
+// The updates are serialized then discarded, to measure the collector alone
+pub async fn run(
+    cfg: &Config,
+    messages: u32,
+    peers: u32,
+    prefixes: u32,
+) -> Result<(), Box<dyn Error>> {
+    let state_config = settings::get_state_config(cfg)?;
+    let rpki_config = settings::get_rpki_config(cfg)?;
+    let decode_config = Arc::new(settings::get_decode_config(cfg)?);
+    let output_config = settings::get_output_config(cfg)?;
+    let state = state::new_state(&state_config);
+    let validator = rpki::new_validator(&rpki_config);
+
+    // Null sink, serializing the updates
+    let (tx, rx) = channel();
+    let sink = std::thread::spawn(move || {
+        let mut n_updates = 0;
+        let mut n_bytes = 0;
+        while let Ok(updates) = rx.recv() {
+            for update in updates {
+                n_bytes += format_update(&update, &output_config).len();
+                n_updates += 1;
+            }
+        }
+        (n_updates, n_bytes)
+    });
+
+    log::info!(
+        "bench - sending {} messages of {} prefixes from {} peers",
+        messages,
+        prefixes,
+        peers
+    );
+
+    let mut latencies = Vec::with_capacity(messages as usize);
+    let start = Instant::now();
+    for n in 0..messages {
+        let peer = n % peers.max(1);
+        let peer_addr = Ipv4Addr::from(0xc6336400 + peer);
+        let raw = route_monitoring(
+            peer_addr,
+            65000 + peer,
+            (n / peers.max(1)) * prefixes,
+            prefixes,
+        );
+
+        let message_start = Instant::now();
+        let (message, raw) = decode_bmp_message(raw.to_vec(), ROUTER.ip(), &decode_config)?;
+        process_bmp_packet(
+            state.clone(),
+            validator.clone(),
+            decode_config.clone(),
+            tx.clone(),
+            ROUTER,
+            message,
+            raw,
+        )
+        .await;
+        latencies.push(message_start.elapsed());
+    }
+    let elapsed = start.elapsed();
+
+    drop(tx);
+    let (n_updates, n_bytes) = sink.join().map_err(|_| "null sink panicked")?;
+    latencies.sort();
+
+    println!("messages:      {}", messages);
+    println!("updates:       {}", n_updates);
+    println!("serialized:    {} bytes", n_bytes);
+    println!("elapsed:       {:.3} s", elapsed.as_secs_f64());
+    println!(
+        "throughput:    {:.0} updates/s",
+        n_updates as f64 / elapsed.as_secs_f64()
+    );
+    println!("p50 latency:   {:?}", percentile(&latencies, 0.5));
+    println!("p99 latency:   {:?}", percentile(&latencies, 0.99));
+    Ok(())
+}
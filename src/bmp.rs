@@ -1,68 +1,356 @@
-use crate::state::{self, AsyncState};
-use crate::update::{decode_updates, format_update, UpdateHeader};
-use bgpkit_parser::bmp::messages::PerPeerFlags;
-use bgpkit_parser::models::Peer;
+use crate::events;
+use crate::evpn;
+use crate::rpki::AsyncValidator;
+use crate::session::AsyncSessions;
+use crate::settings::{get_decode_config, BMPConfig, DecodeConfig, MissingPeerHeader};
+use crate::sink;
+use crate::state::{self, AsyncState, PeerKey};
+use crate::throttle;
+use crate::update::{
+    decode_updates, format_update_text, map_to_ipv6, stable_hash, Update, UpdateHeader,
+};
+use bgpkit_parser::bmp::messages::{BmpPerPeerHeader, InitiationTlvType, PerPeerFlags};
+use bgpkit_parser::models::capabilities::BgpCapabilityType;
+use bgpkit_parser::models::{BgpMessage, ParamValue, Peer};
 use bgpkit_parser::parse_bmp_msg;
 use bgpkit_parser::parser::bmp::messages::{BmpMessage, BmpMessageBody};
 use bytes::Bytes;
-use core::net::IpAddr;
+use chrono::{TimeZone, Utc};
+use core::net::{IpAddr, SocketAddr};
+use ipnet::IpNet;
+use metrics::{counter, histogram};
+use socket2::{SockRef, TcpKeepalive};
 use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncReadExt;
 use tokio::net::TcpStream;
 
-pub async fn unmarshal_bmp_packet(socket: &mut TcpStream) -> Result<BmpMessage> {
-    // Get minimal packet length to get how many bytes to remove from the socket
-    let mut min_buff = [0; 6];
-    socket.peek(&mut min_buff).await?;
+// BMP common header: version (1 byte), message length (4 bytes) and message type (1 byte)
+// https://datatracker.ietf.org/doc/html/rfc7854#section-4.1
+const COMMON_HEADER_LENGTH: usize = 6;
+const MAX_MESSAGE_LENGTH: usize = 4096;
+const MAX_MESSAGE_TYPE: u8 = 6;
 
-    // Get the packet length from the `Message Length` BMP field
-    let packet_length = u32::from_be_bytes(min_buff[1..5].try_into().unwrap());
-    let packet_length = usize::try_from(packet_length).unwrap();
+// Route Monitoring message type, and its per-peer header
+// https://datatracker.ietf.org/doc/html/rfc7854#section-4.2
+const ROUTE_MONITORING: u8 = 0;
+const PER_PEER_HEADER_LENGTH: usize = 42;
+
+// Per-peer header of a Local RIB peer, with no address, ASN, BGP ID nor timestamp
+// https://datatracker.ietf.org/doc/html/rfc9069#section-4.1
+const LOCAL_RIB_PEER_HEADER: [u8; PER_PEER_HEADER_LENGTH] = {
+    let mut header = [0; PER_PEER_HEADER_LENGTH];
+    header[0] = 3;
+    header
+};
+
+// How far to look for the next BMP message after a corrupted header
+const MAX_RESYNC_DISTANCE: usize = 65536;
+
+// How long to wait for the rest of a header the router started to send
+const HEADER_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A corrupted header has an unknown version, an impossible length, or an unknown message type
+// Only BMPv3 and BMPv4 are valid, so the resync does not stop on random bytes
+fn is_valid_header(header: &[u8]) -> bool {
+    let length = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+    matches!(header[0], 3 | 4)
+        && (COMMON_HEADER_LENGTH..=MAX_MESSAGE_LENGTH).contains(&length)
+        && header[5] <= MAX_MESSAGE_TYPE
+}
+
+// Peek at least `min` bytes, or until the buffer is full
+// A peek returns as soon as some bytes are there, so it is retried until the router sends the rest
+async fn peek_at_least(socket: &mut TcpStream, buf: &mut [u8], min: usize) -> Result<usize> {
+    let start = Instant::now();
+    loop {
+        let n = socket.peek(buf).await?;
+        if n == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        if n >= min.min(buf.len()) {
+            return Ok(n);
+        }
+        if start.elapsed() >= HEADER_TIMEOUT {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("incomplete BMP header after {:?}", HEADER_TIMEOUT),
+            ));
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+// Skip the bytes up to the next plausible BMP header, after a corrupted one
+// Returns the number of bytes skipped, or an error if none is found within the resync distance
+async fn resync(socket: &mut TcpStream) -> Result<usize> {
+    let mut window = vec![0; MAX_RESYNC_DISTANCE + COMMON_HEADER_LENGTH];
+    let mut skipped = 0;
+    while skipped < MAX_RESYNC_DISTANCE {
+        let size = MAX_RESYNC_DISTANCE - skipped + COMMON_HEADER_LENGTH;
+        // A whole header is needed to tell whether it is a plausible one
+        let n = peek_at_least(socket, &mut window[..size], COMMON_HEADER_LENGTH).await?;
+
+        // The corrupted header itself is skipped
+        let start = if skipped == 0 { 1 } else { 0 };
+        let end = n + 1 - COMMON_HEADER_LENGTH;
+        let found = (start..end).find(|&i| is_valid_header(&window[i..]));
+
+        // Without a plausible header, the bytes which could start one are kept for the next read
+        let skip = found.unwrap_or(end);
+        socket.read_exact(&mut window[..skip]).await?;
+        skipped += skip;
+        if found.is_some() {
+            return Ok(skipped);
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        format!("no BMP message found within {} bytes", MAX_RESYNC_DISTANCE),
+    ))
+}
 
-    if packet_length == 0 {
-        return Err(Error::new(ErrorKind::NotFound, "BMP message length is 0"));
+// Peer address of the per-peer header of a raw BMP message
+// The address is IPv4 in the last 4 bytes of the field, unless the V flag is set
+fn raw_peer_address(raw: &[u8]) -> Option<IpAddr> {
+    let pph = raw.get(COMMON_HEADER_LENGTH..COMMON_HEADER_LENGTH + PER_PEER_HEADER_LENGTH)?;
+    let address: [u8; 16] = pph[10..26].try_into().ok()?;
+    match pph[1] & 0x80 != 0 {
+        true => Some(IpAddr::from(address)),
+        false => Some(IpAddr::from(<[u8; 4]>::try_from(&address[12..]).ok()?)),
     }
+}
+
+// Some implementations send the Route Monitoring messages of their Local RIB
+// with the BGP message (and its marker) right after the common header, without per-peer header
+fn is_missing_peer_header(raw: &[u8]) -> bool {
+    raw[5] == ROUTE_MONITORING && raw[COMMON_HEADER_LENGTH..].starts_with(&[0xff; 16])
+}
+
+// The message along with a synthesized Local RIB per-peer header, to decode its BGP message
+fn with_local_rib_peer_header(raw: &[u8]) -> Bytes {
+    let length = raw.len() + PER_PEER_HEADER_LENGTH;
+    let mut buf = Vec::with_capacity(length);
+    buf.push(raw[0]);
+    buf.extend((length as u32).to_be_bytes());
+    buf.push(raw[5]);
+    buf.extend(LOCAL_RIB_PEER_HEADER);
+    buf.extend(&raw[COMMON_HEADER_LENGTH..]);
+    Bytes::from(buf)
+}
+
+// Read the next BMP message of a router, returned along with its raw bytes
+pub async fn unmarshal_bmp_packet(
+    socket: &mut TcpStream,
+    decode: &DecodeConfig,
+) -> Result<(BmpMessage, Bytes)> {
+    // Get minimal packet length to get how many bytes to remove from the socket
+    let mut min_buff = [0; COMMON_HEADER_LENGTH];
+    peek_at_least(socket, &mut min_buff, COMMON_HEADER_LENGTH).await?;
 
-    if packet_length > 4096 {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            format!("BMP message length is too big: {} bytes", packet_length),
-        ));
+    // On a corrupted header (e.g. after a desync), look for the next message
+    // rather than closing the connection
+    while !is_valid_header(&min_buff) {
+        let skipped = resync(socket).await?;
+        let router_ip = socket.peer_addr()?.ip();
+        log::debug!(router:% = router_ip; "bmp - {} - skipped {} bytes to resync", router_ip, skipped);
+        throttle::log_kv(
+            log::Level::Warn,
+            &[("router", &router_ip.to_string())],
+            format!("bmp - {} - corrupted BMP header, resynced", router_ip),
+        );
+        counter!(
+            "risotto_bmp_resync_total",
+            "router" => map_to_ipv6(router_ip).to_string(),
+        )
+        .increment(1);
+        peek_at_least(socket, &mut min_buff, COMMON_HEADER_LENGTH).await?;
     }
 
+    // Get the packet length from the `Message Length` BMP field
+    let packet_length = u32::from_be_bytes(min_buff[1..5].try_into().unwrap());
+    let packet_length = usize::try_from(packet_length).unwrap();
+
     // Exactly read the number of bytes found in the BMP message
     let mut buf = vec![0; packet_length];
     socket.read_exact(&mut buf).await?;
 
-    let mut bytes = Bytes::copy_from_slice(&buf);
+    let router_ip = socket.peer_addr()?.ip();
+    decode_bmp_message(buf, router_ip, decode)
+}
+
+// Parse a whole BMP message of a router, returned along with its raw bytes
+pub fn decode_bmp_message(
+    buf: Vec<u8>,
+    router_ip: IpAddr,
+    decode: &DecodeConfig,
+) -> Result<(BmpMessage, Bytes)> {
+    let raw = Bytes::from(buf);
+
+    // BMPv4 keeps the BMPv3 common header, so we attempt to parse it as BMPv3
+    // Only the parsed copy is patched, the raw message is kept as sent by the router
+    let version = raw[0];
+    let parsed = match version {
+        4 => {
+            let mut buf = raw.to_vec();
+            buf[0] = 3;
+            Bytes::from(buf)
+        }
+        _ => raw.clone(),
+    };
+    let mut bytes = parsed.clone();
 
     // Parse the BMP message
     match parse_bmp_msg(&mut bytes) {
-        Ok(message) => Ok(message),
-        Err(_) => Err(Error::new(
-            ErrorKind::InvalidData,
-            "failed to parse BMP message".to_string(),
-        )),
+        Ok(mut message) => {
+            // Keep track of the version actually sent by the router
+            message.common_header.version = version;
+            Ok((message, raw))
+        }
+        Err(_) if is_missing_peer_header(&raw) => {
+            // Counted either way, to spot the routers concerned
+            counter!("risotto_bmp_missing_pph_total", "type" => "route_monitoring").increment(1);
+            if decode.missing_peer_header == MissingPeerHeader::Drop {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "route monitoring message with no per-peer header, skipped".to_string(),
+                ));
+            }
+
+            // Decoded as the Local RIB of the router, the raw message being kept as is
+            match parse_bmp_msg(&mut with_local_rib_peer_header(&parsed)) {
+                Ok(mut message) => {
+                    message.common_header.version = version;
+                    Ok((message, raw))
+                }
+                Err(_) => Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "malformed BGP message with no per-peer header, skipped".to_string(),
+                )),
+            }
+        }
+        Err(_) if version == 4 => {
+            counter!("risotto_bmp_unsupported_version_total", "version" => version.to_string())
+                .increment(1);
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "failed to parse BMPv4 message".to_string(),
+            ))
+        }
+        Err(_) => {
+            // The BMP framing being sound, a Route Monitoring message failing to parse
+            // has a malformed BGP message, which is skipped rather than closing the session
+            let peer_addr = match raw[5] {
+                ROUTE_MONITORING => raw_peer_address(&raw),
+                _ => None,
+            };
+            let Some(peer_addr) = peer_addr else {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "failed to parse BMP message".to_string(),
+                ));
+            };
+
+            counter!(
+                "risotto_bgp_parse_errors_total",
+                "router" => map_to_ipv6(router_ip).to_string(),
+                "peer" => map_to_ipv6(peer_addr).to_string(),
+            )
+            .increment(1);
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("malformed BGP message from {}, skipped", peer_addr),
+            ))
+        }
     }
 }
 
-async fn process_bmp_packet(
-    state: AsyncState,
-    tx: Sender<Vec<u8>>,
-    router_addr: IpAddr,
-    router_port: u16,
-    message: BmpMessage,
-) {
-    let mut state_lock = state.lock().unwrap();
+// TCP keepalive detects dead routers on long-lived, low-traffic sessions
+// TCP_NODELAY avoids delaying the small messages with Nagle's algorithm
+pub fn set_socket_options(socket: &TcpStream, cfg: &BMPConfig) -> Result<()> {
+    socket.set_nodelay(cfg.tcp_nodelay)?;
 
-    // Get peer information
-    let Some(pph) = message.per_peer_header else {
-        return;
+    if cfg.keepalive_idle > 0 {
+        let keepalive = TcpKeepalive::new()
+            .with_time(Duration::from_secs(cfg.keepalive_idle))
+            .with_interval(Duration::from_secs(cfg.keepalive_interval))
+            .with_retries(cfg.keepalive_count);
+        SockRef::from(socket).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+// Check a connection source against the allowlist, empty to allow all the sources
+pub fn is_allowed_source(source: IpAddr, cfg: &BMPConfig) -> bool {
+    // IPv4 sources are mapped on dual-stack listeners
+    let source = match source {
+        IpAddr::V6(addr) => addr.to_ipv4_mapped().map_or(source, IpAddr::V4),
+        IpAddr::V4(_) => source,
+    };
+    cfg.allowed_sources.is_empty()
+        || cfg
+            .allowed_sources
+            .iter()
+            .any(|allowed| allowed.contains(&source))
+}
+
+fn message_type(body: &BmpMessageBody) -> &'static str {
+    match body {
+        BmpMessageBody::PeerUpNotification(_) => "peer_up_notification",
+        BmpMessageBody::PeerDownNotification(_) => "peer_down_notification",
+        BmpMessageBody::InitiationMessage(_) => "initiation_message",
+        BmpMessageBody::TerminationMessage(_) => "termination_message",
+        BmpMessageBody::RouteMonitoring(_) => "route_monitoring",
+        BmpMessageBody::RouteMirroring(_) => "route_mirroring",
+        BmpMessageBody::StatsReport(_) => "stats_report",
+    }
+}
+
+// Get the restart time from the Graceful Restart capability of a BGP Open message
+// https://datatracker.ietf.org/doc/html/rfc4724#section-3
+fn graceful_restart_time(message: &BgpMessage) -> Option<u16> {
+    let BgpMessage::Open(open) = message else {
+        return None;
+    };
+
+    for param in &open.opt_params {
+        if let ParamValue::Capability(capability) = &param.param_value {
+            if capability.ty == BgpCapabilityType::GRACEFUL_RESTART_CAPABILITY
+                && capability.value.len() >= 2
+            {
+                let flags_and_time = u16::from_be_bytes([capability.value[0], capability.value[1]]);
+                return Some(flags_and_time & 0x0FFF);
+            }
+        }
+    }
+    None
+}
+
+// Sample 1 in `rate` prefixes, based on a hash of the prefix
+// So all the announcements of a prefix are consistently sampled in or out
+fn is_sampled(prefix: &IpNet, rate: u64) -> bool {
+    if rate <= 1 {
+        return true;
+    }
+    let mut data = match prefix.addr() {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
     };
-    let peer = Peer::new(pph.peer_bgp_id, pph.peer_ip, pph.peer_asn);
-    let timestamp = (pph.timestamp * 1000.0) as i64;
+    data.push(prefix.prefix_len());
+    stable_hash(&data).is_multiple_of(rate)
+}
 
+fn update_header(
+    router_addr: IpAddr,
+    router_port: u16,
+    pph: &BmpPerPeerHeader,
+    raw: Bytes,
+    received_at: Instant,
+) -> UpdateHeader {
     let is_post_policy = match pph.peer_flags {
         PerPeerFlags::PeerFlags(flags) => flags.is_post_policy(),
         PerPeerFlags::LocalRibPeerFlags(_) => false,
@@ -73,127 +361,823 @@ async fn process_bmp_packet(
         PerPeerFlags::LocalRibPeerFlags(_) => false,
     };
 
+    UpdateHeader {
+        router_addr,
+        router_port,
+        peer: Peer::new(pph.peer_bgp_id, pph.peer_ip, pph.peer_asn),
+        peer_type: pph.peer_type,
+        peer_distinguisher: pph.peer_distinguisher,
+        // A zero timestamp means the router did not provide one
+        timestamp: (pph.timestamp > 0.0).then_some((pph.timestamp * 1000.0) as i64),
+        is_post_policy,
+        is_adj_rib_out,
+        raw,
+        received_at,
+    }
+}
+
+// Returns false if the updates could not be sent, the sinks being disconnected
+pub async fn process_bmp_packet(
+    state: AsyncState,
+    validator: AsyncValidator,
+    decode: Arc<DecodeConfig>,
+    tx: Sender<Vec<Update>>,
+    router: SocketAddr,
+    message: BmpMessage,
+    raw: Bytes,
+) -> bool {
+    // The message was just read from the socket, before waiting for the state lock
+    let received_at = Instant::now();
+    let router_addr = router.ip();
+    let router_port = router.port();
+    let mut state_lock = state.lock().unwrap();
+
+    // Get peer information
+    let Some(pph) = message.per_peer_header else {
+        return true;
+    };
+    let header = update_header(router_addr, router_port, &pph, raw, received_at);
+    let peer = header.peer;
+    let peer_type = header.peer_type;
+    let peer_distinguisher = header.peer_distinguisher;
+
     match message.message_body {
         BmpMessageBody::PeerUpNotification(body) => {
             log::trace!("{:?}", body);
             log::info!(
+                router:% = router_addr, peer:% = peer.peer_address;
                 "bmp - PeerUpNotification: {} - {}",
                 router_addr,
                 peer.peer_address
             );
 
-            let spawn_state = state.clone();
-            tokio::spawn(async move {
-                state::peer_up_withdraws_handler(spawn_state, router_addr, peer, tx).await;
-            });
+            let restart_time = graceful_restart_time(&body.received_open);
+            let added = state_lock
+                .add_peer(
+                    &router_addr,
+                    &peer,
+                    peer_type,
+                    peer_distinguisher,
+                    restart_time,
+                )
+                .unwrap();
+            if !added {
+                return true;
+            }
+
+            state_lock.record_flap(&router_addr, &peer, peer_distinguisher);
+
+            state_lock.schedule_peer_up_withdraws(router_addr, peer, peer_distinguisher, tx);
         }
         BmpMessageBody::RouteMonitoring(body) => {
             log::trace!("{:?}", body);
-            let header = UpdateHeader {
-                timestamp,
-                is_post_policy,
-                is_adj_rib_out,
-            };
 
-            let potential_updates = decode_updates(body, header).unwrap_or_default();
+            // The updates of a peer beyond the cap of its router are not tracked, nor emitted
+            if !state_lock.admits_peer(&router_addr, &peer, peer_distinguisher) {
+                return true;
+            }
+
+            let start = Instant::now();
+            let potential_updates = {
+                let validator = validator.read().unwrap();
+                decode_updates(body, header, validator.as_ref(), &decode).unwrap_or_default()
+            };
+            histogram!(
+                "risotto_bmp_decode_duration_seconds",
+                "message_type" => "route_monitoring",
+            )
+            .record(start.elapsed().as_secs_f64());
 
             let mut legitimate_updates = Vec::new();
             for update in potential_updates {
+                // Route reflection attributes, to spot route reflection scope issues
+                if update.originator_id.is_some() {
+                    counter!(
+                        "risotto_updates_with_originator_total",
+                        "router" => map_to_ipv6(router_addr).to_string(),
+                        "peer" => map_to_ipv6(update.peer_addr).to_string(),
+                    )
+                    .increment(1);
+                }
+                if !update.cluster_list.is_empty() {
+                    counter!(
+                        "risotto_updates_with_cluster_list_total",
+                        "router" => map_to_ipv6(router_addr).to_string(),
+                        "peer" => map_to_ipv6(update.peer_addr).to_string(),
+                    )
+                    .increment(1);
+                }
+
                 let is_updated = state_lock.update(&router_addr, &peer, &update).unwrap();
-                if is_updated {
-                    legitimate_updates.push(update);
+                if !is_updated {
+                    continue;
+                }
+
+                // The state is kept accurate, but only a sample of the announcements is emitted
+                if update.announced && !is_sampled(&update.prefix.prefix, decode.sampling_rate) {
+                    counter!(
+                        "risotto_updates_sampled_out_total",
+                        "router" => map_to_ipv6(router_addr).to_string(),
+                        "peer" => map_to_ipv6(update.peer_addr).to_string(),
+                    )
+                    .increment(1);
+                    continue;
                 }
+
+                // The table of a flapping peer is only emitted once its cooldown ends
+                if state_lock.hold(&router_addr, &peer, &update, &tx) {
+                    continue;
+                }
+                if update.announced {
+                    state_lock.cancel_peer_down_withdraw(&router_addr, &update);
+                }
+                legitimate_updates.push(update);
             }
 
-            let mut buffer = vec![];
-            for mut update in legitimate_updates {
-                let update = format_update(router_addr, router_port, &peer, &mut update);
+            // Evict the least recently announced prefixes if the peer is above the cap
+            let evicted_updates = state_lock
+                .evict(&router_addr, router_port, &peer, peer_distinguisher)
+                .unwrap();
+            legitimate_updates.extend(evicted_updates);
+
+            for update in &legitimate_updates {
                 log::trace!("{:?}", update);
-                buffer.extend(update.as_bytes());
-                buffer.extend(b"\n");
             }
 
             // Sent to the event pipeline
-            tx.send(buffer).unwrap();
+            return sink::send(&tx, legitimate_updates);
         }
         BmpMessageBody::PeerDownNotification(body) => {
             log::trace!("{:?}", body);
+            // The NOTIFICATION tells why the session dropped (e.g. hold timer expired, shutdown)
+            let error = match events::notification(&body) {
+                Some(notification) => notification.error,
+                None => "none".to_string(),
+            };
             log::info!(
-                "bmp - PeerDownNotification: {} - {}",
+                router:% = router_addr, peer:% = peer.peer_address;
+                "bmp - PeerDownNotification: {} - {} - {:?} - {}",
                 router_addr,
-                peer.peer_address
+                peer.peer_address,
+                body.reason,
+                error
             );
+            counter!(
+                "risotto_peer_down_total",
+                "router" => map_to_ipv6(router_addr).to_string(),
+                "reason" => format!("{:?}", body.reason),
+                "notification" => error,
+            )
+            .increment(1);
+
+            state_lock.record_flap(&router_addr, &peer, peer_distinguisher);
+
+            // If the peer is graceful restart capable, mark its updates as stale
+            // They will be withdrawn if not refreshed within the restart time
+            if let Some((restart_time, generation)) = state_lock
+                .mark_stale(&router_addr, &peer, peer_distinguisher)
+                .unwrap()
+            {
+                log::info!(
+                    router:% = router_addr, peer:% = peer.peer_address;
+                    "bmp - {} - {} marked stale for {} seconds",
+                    router_addr,
+                    peer.peer_address,
+                    restart_time
+                );
+
+                let spawn_state = state.clone();
+                tokio::spawn(async move {
+                    state::stale_withdraws_handler(
+                        spawn_state,
+                        router_addr,
+                        router_port,
+                        peer,
+                        peer_distinguisher,
+                        restart_time,
+                        generation,
+                        tx,
+                    )
+                    .await;
+                });
+                return true;
+            }
 
             // Remove the peer and the associated updates from the state
             // We start by emiting synthetic withdraw updates
             let mut synthetic_updates = Vec::new();
-            let updates = state_lock.get_updates_by_peer(&router_addr, &peer).unwrap();
-            for prefix in updates {
-                synthetic_updates.push(state::synthesize_withdraw_update(prefix.clone()));
+            if let Some(state_peer) = state_lock
+                .get_peer(&router_addr, &peer, peer_distinguisher)
+                .unwrap()
+            {
+                for prefix in &state_peer.updates {
+                    if state_lock.is_shared(&router_addr, &peer, peer_distinguisher, prefix) {
+                        continue;
+                    }
+                    synthetic_updates.push(state::synthesize_withdraw_update(
+                        router_addr,
+                        router_port,
+                        &state_peer,
+                        prefix.clone(),
+                    ));
+                }
             }
 
             // Then update the state
-            state_lock.remove_updates(&router_addr, &peer).unwrap();
+            state_lock
+                .remove_updates(&router_addr, &peer, peer_distinguisher)
+                .unwrap();
 
-            let mut buffer = vec![];
-            for mut update in synthetic_updates {
-                let update = format_update(router_addr, router_port, &peer, &mut update);
+            for update in &synthetic_updates {
                 log::trace!("{:?}", update);
-                buffer.extend(update.as_bytes());
-                buffer.extend(b"\n");
             }
 
             // Finally send the synthetic updates to the event pipeline
-            tx.send(buffer).unwrap();
+            // They are queued, so that they are sent in order with the next updates of the peer
+            if state_lock.queue_peer_down_withdraws(
+                &router_addr,
+                &peer,
+                peer_distinguisher,
+                synthetic_updates,
+            ) {
+                let spawn_state = state.clone();
+                tokio::spawn(async move {
+                    state::peer_down_withdraws_handler(
+                        spawn_state,
+                        router_addr,
+                        peer,
+                        peer_distinguisher,
+                        tx,
+                    )
+                    .await;
+                });
+            }
         }
         _ => (),
     }
+    true
 }
 
-pub async fn handle(socket: &mut TcpStream, state: AsyncState, tx: Sender<Vec<u8>>) {
+pub async fn handle(
+    socket: &mut TcpStream,
+    state: AsyncState,
+    validator: AsyncValidator,
+    sessions: AsyncSessions,
+    decode: Arc<DecodeConfig>,
+    tx: Sender<Vec<Update>>,
+) -> Result<()> {
     // Get router IP information
     let socket_info = socket.peer_addr().unwrap();
     let router_ip = socket_info.ip();
     let router_port = socket_info.port();
 
+    sessions.lock().unwrap().open(socket_info);
+
+    // The session is closed on a malformed stream, which is reported to the caller
+    let mut result = Ok(());
+
+    // Set once the sinks are gone, as the updates of the router would be lost
+    let sinks_disconnected = Arc::new(AtomicBool::new(false));
+
     loop {
+        if sinks_disconnected.load(Ordering::Relaxed) {
+            log::error!(
+                router:% = router_ip;
+                "bmp - sinks disconnected, closing connection with {}:{}",
+                router_ip,
+                router_port
+            );
+            break;
+        }
+
         // Get BMP message
-        let message = match unmarshal_bmp_packet(socket).await {
-            Ok(message) => message,
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                // Empty message, continue
+        let (message, raw) = match unmarshal_bmp_packet(socket, &decode).await {
+            Ok(packet) => packet,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                // The router closed the connection
+                log::info!(router:% = router_ip; "bmp - {}:{} - {}", router_ip, router_port, e);
+                break;
+            }
+            Err(e) if e.kind() == ErrorKind::Unsupported => {
+                // Unparsable BMPv4 message or malformed BGP message, skip the message
+                throttle::log_kv(
+                    log::Level::Warn,
+                    &[("router", &router_ip.to_string())],
+                    format!("bmp - {}:{} - {}", router_ip, router_port, e),
+                );
                 continue;
             }
-            Err(e) if e.kind() == ErrorKind::InvalidData => {
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::InvalidInput) => {
                 // Invalid message, continue without processing
                 // From what I can see, it's often because of a packet length issue
                 // So for now, we will close the connection
-                log::error!("bmp - invalid BMP message: {}", e);
-                log::error!(
-                    "bmp - closing connection with {}:{}",
-                    router_ip,
-                    router_port
+                // A malformed router keeps reconnecting, the port is left out to collapse the lines
+                throttle::log_kv(
+                    log::Level::Error,
+                    &[("router", &router_ip.to_string())],
+                    format!(
+                        "bmp - {} - invalid BMP message, closing connection: {}",
+                        router_ip, e
+                    ),
                 );
+                result = Err(e);
                 break;
             }
             Err(e) => {
                 // Other errors are unexpected
                 // Close the connection
-                log::error!("bmp - failed to unmarshal BMP message: {}", e);
-                log::error!(
-                    "bmp - closing connection with {}:{}",
-                    router_ip,
-                    router_port
+                throttle::log_kv(
+                    log::Level::Error,
+                    &[("router", &router_ip.to_string())],
+                    format!(
+                        "bmp - {} - failed to unmarshal BMP message, closing connection: {}",
+                        router_ip, e
+                    ),
                 );
                 break;
             }
         };
 
+        // Update the session information
+        {
+            let mut sessions = sessions.lock().unwrap();
+            sessions.received(&socket_info, message.common_header.version);
+            match (&message.message_body, &message.per_peer_header) {
+                (BmpMessageBody::InitiationMessage(body), _) => {
+                    for tlv in &body.tlvs {
+                        if tlv.info_type == InitiationTlvType::SysName {
+                            sessions.set_sys_name(&socket_info, tlv.info.clone());
+                        }
+                    }
+                }
+                (BmpMessageBody::PeerUpNotification(body), Some(pph)) => {
+                    let established_at = Utc
+                        .timestamp_millis_opt(events::header_timestamp(pph))
+                        .single()
+                        .unwrap_or_else(Utc::now);
+                    let peer = PeerKey {
+                        peer_addr: pph.peer_ip,
+                        peer_distinguisher: pph.peer_distinguisher,
+                    };
+                    sessions.set_established(&socket_info, peer, Some(established_at));
+                    sessions.send_event(events::peer_up(socket_info, pph, body));
+                }
+                (BmpMessageBody::PeerDownNotification(body), Some(pph)) => {
+                    let peer = PeerKey {
+                        peer_addr: pph.peer_ip,
+                        peer_distinguisher: pph.peer_distinguisher,
+                    };
+                    sessions.set_established(&socket_info, peer, None);
+                    sessions.send_event(events::peer_down(socket_info, pph, body));
+                }
+                _ => (),
+            }
+        }
+
+        // EVPN routes are not decoded, but at least accounted
+        if let (BmpMessageBody::RouteMonitoring(_), Some(pph)) =
+            (&message.message_body, &message.per_peer_header)
+        {
+            evpn::account(router_ip, pph.peer_ip, &raw);
+        }
+
         // Process the BMP message
         let process_state = state.clone();
+        let process_validator = validator.clone();
+        let process_decode = decode.clone();
         let process_tx = tx.clone();
+        let process_sinks_disconnected = sinks_disconnected.clone();
         tokio::spawn(async move {
-            process_bmp_packet(process_state, process_tx, router_ip, router_port, message).await;
+            // Includes the time waiting for the state lock
+            let start = Instant::now();
+            let message_type = message_type(&message.message_body);
+            let sent = process_bmp_packet(
+                process_state,
+                process_validator,
+                process_decode,
+                process_tx,
+                socket_info,
+                message,
+                raw,
+            )
+            .await;
+            if !sent {
+                process_sinks_disconnected.store(true, Ordering::Relaxed);
+            }
+            histogram!(
+                "risotto_bmp_process_duration_seconds",
+                "message_type" => message_type,
+            )
+            .record(start.elapsed().as_secs_f64());
         });
     }
+
+    sessions.lock().unwrap().close(&socket_info);
+    result
+}
+
+// Decode the BMP messages of a router and print the updates to stdout
+// There is no state, RPKI validation or sink involved
+pub async fn print(socket: &mut TcpStream) {
+    let socket_info = socket.peer_addr().unwrap();
+    let router_ip = socket_info.ip();
+    let router_port = socket_info.port();
+
+    // Decoding with the default settings
+    let decode = get_decode_config(&config::Config::default()).unwrap();
+
+    loop {
+        let (message, raw) = match unmarshal_bmp_packet(socket, &decode).await {
+            Ok(packet) => packet,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) if e.kind() == ErrorKind::Unsupported => {
+                log::warn!("bmp - {}:{} - {}", router_ip, router_port, e);
+                continue;
+            }
+            Err(e) => {
+                log::error!("bmp - failed to unmarshal BMP message: {}", e);
+                log::error!(
+                    "bmp - closing connection with {}:{}",
+                    router_ip,
+                    router_port
+                );
+                break;
+            }
+        };
+
+        let Some(pph) = message.per_peer_header else {
+            continue;
+        };
+        let BmpMessageBody::RouteMonitoring(body) = message.message_body else {
+            continue;
+        };
+
+        let header = update_header(router_ip, router_port, &pph, raw, Instant::now());
+        for update in decode_updates(body, header, None, &decode).unwrap_or_default() {
+            println!("{}", format_update_text(&update));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attributes::PrefixSid;
+    use crate::rpki;
+    use crate::settings::{get_output_config, get_rpki_config, get_state_config};
+    use crate::update::{format_update, format_update_json};
+    use bgpkit_parser::bmp::messages::BmpPeerType;
+    use config::Config;
+    use core::net::Ipv4Addr;
+    use std::sync::mpsc::channel;
+
+    const ROUTER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 4000);
+    const PEER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const PEER_ASN: u32 = 65001;
+    // 2023-11-14T22:13:20Z
+    const TIMESTAMP: u32 = 1700000000;
+
+    fn bmp_message(message_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![3];
+        message.extend((COMMON_HEADER_LENGTH as u32 + body.len() as u32).to_be_bytes());
+        message.push(message_type);
+        message.extend(body);
+        message
+    }
+
+    // Global instance peer, pre-policy Adj-RIB-In
+    fn per_peer_header() -> Vec<u8> {
+        let mut header = vec![0, 0];
+        header.extend(0u64.to_be_bytes());
+        header.extend([0; 12]);
+        header.extend(PEER.octets());
+        header.extend(PEER_ASN.to_be_bytes());
+        header.extend(PEER.octets());
+        header.extend(TIMESTAMP.to_be_bytes());
+        header.extend(0u32.to_be_bytes());
+        header
+    }
+
+    fn bgp_message(message_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![0xff; 16];
+        message.extend((19 + body.len() as u16).to_be_bytes());
+        message.push(message_type);
+        message.extend(body);
+        message
+    }
+
+    fn bgp_open(asn: u16, bgp_id: Ipv4Addr) -> Vec<u8> {
+        let mut body = vec![4];
+        body.extend(asn.to_be_bytes());
+        body.extend(180u16.to_be_bytes());
+        body.extend(bgp_id.octets());
+        body.push(0);
+        bgp_message(1, &body)
+    }
+
+    fn peer_up() -> Vec<u8> {
+        let local = Ipv4Addr::new(10, 0, 0, 1);
+        let mut body = per_peer_header();
+        body.extend([0; 12]);
+        body.extend(local.octets());
+        body.extend(179u16.to_be_bytes());
+        body.extend(40000u16.to_be_bytes());
+        body.extend(bgp_open(65000, local));
+        body.extend(bgp_open(PEER_ASN as u16, PEER));
+        bmp_message(3, &body)
+    }
+
+    // Remote system closed the session without a notification
+    fn peer_down() -> Vec<u8> {
+        let mut body = per_peer_header();
+        body.push(4);
+        bmp_message(2, &body)
+    }
+
+    // Same message, monitored in the post-policy Adj-RIB-In
+    fn post_policy(mut message: Vec<u8>) -> Vec<u8> {
+        message[COMMON_HEADER_LENGTH + 1] |= 0x40;
+        message
+    }
+
+    fn nlri(prefixes: &[&str]) -> Vec<u8> {
+        let mut nlri = Vec::new();
+        for prefix in prefixes {
+            let prefix: ipnet::Ipv4Net = prefix.parse().unwrap();
+            let length = prefix.prefix_len();
+            nlri.push(length);
+            nlri.extend(&prefix.addr().octets()[..length.div_ceil(8) as usize]);
+        }
+        nlri
+    }
+
+    // BGP Update with the AS path 65001 65002, without per-peer header if `pph` is false
+    fn route_monitoring(announced: &[&str], withdrawn: &[&str], pph: bool) -> Vec<u8> {
+        route_monitoring_with(announced, withdrawn, pph, &[])
+    }
+
+    // Same, along with other raw path attributes
+    fn route_monitoring_with(
+        announced: &[&str],
+        withdrawn: &[&str],
+        pph: bool,
+        other_attributes: &[u8],
+    ) -> Vec<u8> {
+        let mut attributes = Vec::new();
+        if !announced.is_empty() {
+            attributes.extend([0x40, 1, 1, 0]);
+            attributes.extend([0x40, 2, 10, 2, 2]);
+            attributes.extend(PEER_ASN.to_be_bytes());
+            attributes.extend(65002u32.to_be_bytes());
+            attributes.extend([0x40, 3, 4]);
+            attributes.extend(PEER.octets());
+            attributes.extend(other_attributes);
+        }
+
+        let withdrawn = nlri(withdrawn);
+        let mut update = (withdrawn.len() as u16).to_be_bytes().to_vec();
+        update.extend(withdrawn);
+        update.extend((attributes.len() as u16).to_be_bytes());
+        update.extend(attributes);
+        update.extend(nlri(announced));
+
+        let mut body = if pph { per_peer_header() } else { vec![] };
+        body.extend(bgp_message(2, &update));
+        bmp_message(ROUTE_MONITORING, &body)
+    }
+
+    fn settings() -> Config {
+        Config::builder()
+            .set_override("state.enable", true)
+            .unwrap()
+            .set_override("state.path", "/nonexistent/risotto.json")
+            .unwrap()
+            .set_override("state.save_interval", 10)
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    // Feed raw BMP messages through the decoding and the processing, as read from the router
+    // Returns the updates emitted to the sinks
+    async fn emitted(decode: DecodeConfig, messages: &[Vec<u8>]) -> Vec<Update> {
+        feed(decode, messages).await.1
+    }
+
+    // Same, along with the resulting state
+    async fn feed(decode: DecodeConfig, messages: &[Vec<u8>]) -> (AsyncState, Vec<Update>) {
+        let settings = settings();
+        let state = state::new_state(&get_state_config(&settings).unwrap());
+        let validator = rpki::new_validator(&get_rpki_config(&settings).unwrap());
+        let decode = Arc::new(decode);
+        let (tx, rx) = channel();
+
+        for raw in messages {
+            let Ok((message, raw)) = decode_bmp_message(raw.clone(), ROUTER.ip(), &decode) else {
+                continue;
+            };
+            process_bmp_packet(
+                state.clone(),
+                validator.clone(),
+                decode.clone(),
+                tx.clone(),
+                ROUTER,
+                message,
+                raw,
+            )
+            .await;
+        }
+
+        // Let the synthetic withdraws be sent
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        (state, rx.try_iter().flatten().collect())
+    }
+
+    fn decode_config() -> DecodeConfig {
+        get_decode_config(&Config::default()).unwrap()
+    }
+
+    fn output_config() -> crate::settings::OutputConfig {
+        get_output_config(&Config::default()).unwrap()
+    }
+
+    // Serialized with the fields which do not depend on the time of the test
+    fn serialized(updates: &[Update]) -> Vec<String> {
+        let settings = Config::builder()
+            .set_override(
+                "output.fields",
+                vec![
+                    "timestamp",
+                    "router_addr",
+                    "peer_addr",
+                    "peer_asn",
+                    "prefix_addr",
+                    "prefix_len",
+                    "announced",
+                    "path",
+                    "synthetic",
+                    "peer_type",
+                ],
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        let output = get_output_config(&settings).unwrap();
+        let mut lines: Vec<String> = updates
+            .iter()
+            .map(|update| format_update(update, &output))
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[tokio::test]
+    async fn route_monitoring_emitted() {
+        let updates = emitted(
+            decode_config(),
+            &[
+                peer_up(),
+                route_monitoring(&["1.0.0.0/24", "1.0.1.0/24"], &[], true),
+            ],
+        )
+        .await;
+        assert_eq!(
+            serialized(&updates),
+            [
+                "1700000000000,::ffff:10.0.0.1,::ffff:192.0.2.1,65001,::ffff:1.0.0.0,24,true,\"[65001,65002]\",false,global",
+                "1700000000000,::ffff:10.0.0.1,::ffff:192.0.2.1,65001,::ffff:1.0.1.0,24,true,\"[65001,65002]\",false,global",
+            ]
+        );
+
+        // The JSON lines carry the same update
+        let json: serde_json::Value =
+            serde_json::from_str(&format_update_json(&updates[0], &output_config())).unwrap();
+        assert_eq!(json["router_port"], 4000);
+        assert_eq!(json["peer_bgp_id"], "192.0.2.1");
+        assert_eq!(json["header_time_valid"], true);
+    }
+
+    #[tokio::test]
+    async fn duplicates_and_spurious_withdraws_not_emitted() {
+        let updates = emitted(
+            decode_config(),
+            &[
+                peer_up(),
+                route_monitoring(&[], &["1.0.2.0/24"], true),
+                route_monitoring(&["1.0.0.0/24"], &[], true),
+                route_monitoring(&["1.0.0.0/24"], &[], true),
+                route_monitoring(&[], &["1.0.0.0/24"], true),
+            ],
+        )
+        .await;
+        assert_eq!(
+            serialized(&updates),
+            [
+                "1700000000000,::ffff:10.0.0.1,::ffff:192.0.2.1,65001,::ffff:1.0.0.0,24,false,\"[]\",false,global",
+                "1700000000000,::ffff:10.0.0.1,::ffff:192.0.2.1,65001,::ffff:1.0.0.0,24,true,\"[65001,65002]\",false,global",
+            ]
+        );
+    }
+
+    #[test]
+    fn sampling_stable() {
+        // Pinned, as the sampled prefixes must not change from one build to another
+        let sampled: Vec<u8> = (0..=255)
+            .filter(|i| is_sampled(&format!("1.0.{}.0/24", i).parse().unwrap(), 10))
+            .collect();
+        assert_eq!(&sampled[..4], [19, 28, 35, 36]);
+        assert_eq!(sampled.len(), 27);
+    }
+
+    #[tokio::test]
+    async fn pre_and_post_policy_emitted() {
+        let (state, updates) = feed(
+            decode_config(),
+            &[
+                peer_up(),
+                route_monitoring(&["1.0.0.0/24"], &[], true),
+                post_policy(route_monitoring(&["1.0.0.0/24"], &[], true)),
+            ],
+        )
+        .await;
+
+        // The same prefix in both RIBs is not a duplicate
+        assert_eq!(updates.len(), 2);
+        assert!(!updates[0].is_post_policy);
+        assert!(updates[1].is_post_policy);
+        assert_eq!(state.lock().unwrap().get_all().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn peer_down_withdraws_emitted() {
+        let updates = emitted(
+            decode_config(),
+            &[
+                peer_up(),
+                route_monitoring(&["1.0.0.0/24", "1.0.1.0/24"], &[], true),
+                peer_down(),
+            ],
+        )
+        .await;
+        let withdraws: Vec<&Update> = updates.iter().filter(|u| !u.announced).collect();
+        assert_eq!(withdraws.len(), 2);
+        assert!(withdraws.iter().all(|update| update.synthetic));
+    }
+
+    #[tokio::test]
+    async fn missing_peer_header() {
+        let messages = [route_monitoring(&["1.0.0.0/24"], &[], false)];
+
+        // Dropped by default
+        assert!(emitted(decode_config(), &messages).await.is_empty());
+
+        // Otherwise decoded as the Local RIB of the router
+        let mut decode = decode_config();
+        decode.missing_peer_header = MissingPeerHeader::LocalRib;
+        let updates = emitted(decode, &messages).await;
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].peer_type, BmpPeerType::LocalRib);
+        assert!(!updates[0].header_time_valid);
+    }
+
+    #[tokio::test]
+    async fn missing_peer_header_prefix_sid() {
+        // BGP Prefix-SID with a Label-Index TLV (reserved, flags, label index)
+        let mut prefix_sid = vec![0xc0, 40, 10, 1, 0, 7, 0, 0, 0];
+        prefix_sid.extend(100u32.to_be_bytes());
+        let message = route_monitoring_with(&["1.0.0.0/24"], &[], false, &prefix_sid);
+
+        // The attributes are found right after the common header
+        let mut decode = decode_config();
+        decode.missing_peer_header = MissingPeerHeader::LocalRib;
+        let updates = emitted(decode, &[message]).await;
+        assert_eq!(updates.len(), 1);
+        assert_eq!(
+            updates[0].prefix_sid,
+            Some(PrefixSid {
+                label_index: Some(100),
+                srv6_sid: None,
+            })
+        );
+
+        // And not reported when there is none
+        let message = route_monitoring(&["1.0.0.0/24"], &[], false);
+        let mut decode = decode_config();
+        decode.missing_peer_header = MissingPeerHeader::LocalRib;
+        let updates = emitted(decode, &[message]).await;
+        assert_eq!(updates[0].prefix_sid, None);
+    }
+
+    #[tokio::test]
+    async fn bmpv4_decoded() {
+        let mut message = route_monitoring(&["1.0.0.0/24"], &[], true);
+        message[0] = 4;
+        let (parsed, raw) =
+            decode_bmp_message(message.clone(), ROUTER.ip(), &decode_config()).unwrap();
+        assert_eq!(parsed.common_header.version, 4);
+
+        // The raw message is archived as sent by the router
+        assert_eq!(raw[0], 4);
+        assert_eq!(raw, message);
+    }
 }
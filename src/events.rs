@@ -0,0 +1,197 @@
+use bgpkit_parser::bmp::messages::{BmpPerPeerHeader, PeerDownNotification, PeerUpNotification};
+use bgpkit_parser::models::{BgpError, BgpMessage, ParamValue};
+use chrono::Utc;
+use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+use serde::Serialize;
+
+use crate::settings::OutputConfig;
+use crate::update::{map_to_ipv6, output_addr, output_bgp_id};
+
+// Peer state changes, emitted along the updates to reconstruct the session history downstream
+// Along with the heartbeats of the collector, to tell a quiet feed from a dead collector
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PeerEvent {
+    PeerUp {
+        #[serde(flatten)]
+        peer: EventPeer,
+        capabilities: Vec<String>,
+    },
+    PeerDown {
+        #[serde(flatten)]
+        peer: EventPeer,
+        reason: String,
+        notification: Option<Notification>,
+    },
+    Heartbeat {
+        timestamp: i64,
+        routers: usize,
+        prefixes: usize,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EventPeer {
+    timestamp: i64,
+    router_addr: IpAddr,
+    router_port: u16,
+    peer_addr: IpAddr,
+    peer_bgp_id: Ipv4Addr,
+    peer_asn: u32,
+}
+
+// BGP NOTIFICATION sent or received when the session was closed
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub code: u8,
+    pub subcode: u8,
+    pub error: String,
+    pub shutdown_message: Option<String>,
+}
+
+// NOTIFICATION message following the reason of a Peer Down notification, if any
+// https://datatracker.ietf.org/doc/html/rfc7854#section-4.9
+pub fn notification(body: &PeerDownNotification) -> Option<Notification> {
+    let data = body.data.as_deref()?;
+    // BGP header (marker, length, type), then the error code and subcode
+    // https://datatracker.ietf.org/doc/html/rfc4271#section-4.5
+    if data.len() < 21 || data[..16] != [0xff; 16] || data[18] != 3 {
+        return None;
+    }
+    let (code, subcode) = (data[19], data[20]);
+
+    // Administrative shutdown and reset may carry a message from the operator
+    // https://datatracker.ietf.org/doc/html/rfc9003#section-2
+    let shutdown_message = match (code, subcode, data.get(21)) {
+        (6, 2 | 4, Some(&length)) if length > 0 => data
+            .get(22..22 + length as usize)
+            .map(|message| String::from_utf8_lossy(message).into_owned()),
+        _ => None,
+    };
+
+    Some(Notification {
+        code,
+        subcode,
+        error: format!("{:?}", BgpError::new(code, subcode)),
+        shutdown_message,
+    })
+}
+
+// Time of the per-peer header, in milliseconds
+// A zero timestamp means the router did not provide one, the time of reception is used instead
+pub fn header_timestamp(pph: &BmpPerPeerHeader) -> i64 {
+    match pph.timestamp > 0.0 {
+        true => (pph.timestamp * 1000.0) as i64,
+        false => Utc::now().timestamp_millis(),
+    }
+}
+
+fn event_peer(router: SocketAddr, pph: &BmpPerPeerHeader) -> EventPeer {
+    EventPeer {
+        timestamp: header_timestamp(pph),
+        router_addr: map_to_ipv6(router.ip()),
+        router_port: router.port(),
+        peer_addr: map_to_ipv6(pph.peer_ip),
+        peer_bgp_id: pph.peer_bgp_id,
+        peer_asn: pph.peer_asn.to_u32(),
+    }
+}
+
+// Capabilities advertised by the peer in its BGP Open message
+fn capabilities(message: &BgpMessage) -> Vec<String> {
+    let BgpMessage::Open(open) = message else {
+        return Vec::new();
+    };
+
+    open.opt_params
+        .iter()
+        .filter_map(|param| match &param.param_value {
+            ParamValue::Capability(capability) => Some(format!("{:?}", capability.ty)),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn peer_up(router: SocketAddr, pph: &BmpPerPeerHeader, body: &PeerUpNotification) -> PeerEvent {
+    PeerEvent::PeerUp {
+        peer: event_peer(router, pph),
+        capabilities: capabilities(&body.received_open),
+    }
+}
+
+pub fn peer_down(
+    router: SocketAddr,
+    pph: &BmpPerPeerHeader,
+    body: &PeerDownNotification,
+) -> PeerEvent {
+    PeerEvent::PeerDown {
+        peer: event_peer(router, pph),
+        reason: format!("{:?}", body.reason),
+        notification: notification(body),
+    }
+}
+
+pub fn heartbeat(routers: usize, prefixes: usize) -> PeerEvent {
+    PeerEvent::Heartbeat {
+        timestamp: Utc::now().timestamp_millis(),
+        routers,
+        prefixes,
+    }
+}
+
+// Returns a JSON line of the event
+// The addresses are anonymized and emitted in the family of the output, as in the updates
+pub fn format_event(event: &PeerEvent, output: &OutputConfig) -> String {
+    let mut event = event.clone();
+    if let PeerEvent::PeerUp { peer, .. } | PeerEvent::PeerDown { peer, .. } = &mut event {
+        peer.router_addr = output_addr(peer.router_addr, output);
+        peer.peer_addr = output_addr(peer.peer_addr, output);
+        peer.peer_bgp_id = output_bgp_id(peer.peer_bgp_id, output);
+    }
+    serde_json::to_string(&event).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::AsnFormat;
+
+    fn peer_up() -> PeerEvent {
+        PeerEvent::PeerUp {
+            peer: EventPeer {
+                timestamp: 0,
+                router_addr: map_to_ipv6("192.0.2.254".parse().unwrap()),
+                router_port: 4000,
+                peer_addr: map_to_ipv6("192.0.2.1".parse().unwrap()),
+                peer_bgp_id: "192.0.2.1".parse().unwrap(),
+                peer_asn: 65001,
+            },
+            capabilities: Vec::new(),
+        }
+    }
+
+    fn output_config(anonymize_key: Option<&str>) -> OutputConfig {
+        OutputConfig {
+            asn_format: AsnFormat::Asplain,
+            fields: None,
+            anonymize_key: anonymize_key.map(|key| key.as_bytes().to_vec()),
+            check_serialization: false,
+            ipv4_mapped: true,
+        }
+    }
+
+    #[test]
+    fn anonymized_peer_event() {
+        let event = format_event(&peer_up(), &output_config(None));
+        assert!(event.contains("\"router_addr\":\"::ffff:192.0.2.254\""));
+        assert!(event.contains("\"peer_bgp_id\":\"192.0.2.1\""));
+
+        // None of the real addresses is left, the same key giving the same addresses
+        let anonymized = format_event(&peer_up(), &output_config(Some("secret")));
+        assert!(!anonymized.contains("192.0.2."));
+        assert_eq!(
+            anonymized,
+            format_event(&peer_up(), &output_config(Some("secret")))
+        );
+    }
+}
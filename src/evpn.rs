@@ -0,0 +1,92 @@
+use core::net::IpAddr;
+use metrics::counter;
+
+use crate::attributes::{path_attributes, read_u16};
+use crate::update::map_to_ipv6;
+
+// BGP MP_REACH_NLRI and MP_UNREACH_NLRI path attributes
+// https://datatracker.ietf.org/doc/html/rfc4760#section-3
+const MP_REACH_NLRI: u8 = 14;
+const MP_UNREACH_NLRI: u8 = 15;
+
+// L2VPN EVPN address family
+// https://datatracker.ietf.org/doc/html/rfc7432#section-7
+const AFI_L2VPN: u16 = 25;
+const SAFI_EVPN: u8 = 70;
+
+fn route_type_name(route_type: u8) -> &'static str {
+    match route_type {
+        1 => "ethernet-auto-discovery",
+        2 => "mac-ip-advertisement",
+        3 => "inclusive-multicast",
+        4 => "ethernet-segment",
+        5 => "ip-prefix",
+        _ => "unknown",
+    }
+}
+
+// Get the route types of the EVPN NLRI found in a list of NLRI
+// https://datatracker.ietf.org/doc/html/rfc7432#section-7
+fn route_types(mut nlri: &[u8]) -> Vec<u8> {
+    let mut route_types = Vec::new();
+    while nlri.len() >= 2 {
+        let route_type = nlri[0];
+        let length = nlri[1] as usize;
+        if nlri.len() < 2 + length {
+            break;
+        }
+        route_types.push(route_type);
+        nlri = &nlri[2 + length..];
+    }
+    route_types
+}
+
+// Get the route types of the EVPN routes (announced or not) of a BMP Route Monitoring message
+fn parse(raw: &[u8]) -> Option<Vec<(u8, bool)>> {
+    let mut routes = Vec::new();
+    for (attr_type, value) in path_attributes(raw)? {
+        if attr_type != MP_REACH_NLRI && attr_type != MP_UNREACH_NLRI {
+            continue;
+        }
+        if read_u16(value, 0)? != AFI_L2VPN || *value.get(2)? != SAFI_EVPN {
+            continue;
+        }
+
+        if attr_type == MP_REACH_NLRI {
+            // Skip the next hop and the reserved byte
+            let next_hop_len = *value.get(3)? as usize;
+            let nlri = value.get(4 + next_hop_len + 1..)?;
+            routes.extend(route_types(nlri).into_iter().map(|t| (t, true)));
+        } else {
+            let nlri = value.get(3..)?;
+            routes.extend(route_types(nlri).into_iter().map(|t| (t, false)));
+        }
+    }
+    Some(routes)
+}
+
+// The BGP parser discards the EVPN address family
+// Account for the EVPN routes of a Route Monitoring message rather than silently dropping them
+pub fn account(router_addr: IpAddr, peer_addr: IpAddr, raw: &[u8]) {
+    let Some(routes) = parse(raw) else {
+        return;
+    };
+
+    for (route_type, announced) in routes {
+        log::trace!(
+            "evpn - {} - {} - {} route {}",
+            router_addr,
+            peer_addr,
+            route_type_name(route_type),
+            if announced { "announced" } else { "withdrawn" }
+        );
+        counter!(
+            "risotto_evpn_routes_total",
+            "router" => map_to_ipv6(router_addr).to_string(),
+            "peer" => map_to_ipv6(peer_addr).to_string(),
+            "route_type" => route_type_name(route_type),
+            "announced" => announced.to_string(),
+        )
+        .increment(1);
+    }
+}
@@ -1,39 +1,128 @@
 mod api;
+mod archive;
+mod attributes;
+mod bench;
 mod bmp;
+mod events;
+mod evpn;
+mod mrt;
+mod nats;
 mod producer;
+mod rpki;
+mod session;
 mod settings;
+mod sink;
 mod state;
+mod throttle;
 mod update;
 
 use chrono::Local;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
 use config::Config;
 use env_logger::Builder;
+use log::kv::{self, Key, Value, VisitSource};
 use log::{debug, info};
+use metrics::counter;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::error::Error;
 use std::io::Write;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::TcpListener;
-use tokio_graceful::Shutdown;
+use tokio::net::{lookup_host, TcpListener};
+use tokio::sync::broadcast;
+use tokio_graceful::{Shutdown, ShutdownGuard};
 
+use crate::events::PeerEvent;
+use crate::mrt::MrtSink;
+use crate::nats::NatsSink;
+use crate::rpki::AsyncValidator;
+use crate::session::AsyncSessions;
+use crate::settings::{BMPConfig, DecodeConfig};
+use crate::sink::{CounterSink, FileSink, OriginSink, SinkChannel, StdoutSink, StreamSink};
 use crate::state::AsyncState;
+use crate::update::Update;
+
+#[derive(ValueEnum, Clone, Debug)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "Print a summary of a state dump file")]
+    Inspect {
+        state_file: String,
+
+        #[arg(long, help = "Also list every prefix of each peer")]
+        prefixes: bool,
+    },
+    #[command(about = "Benchmark the processing of synthetic BMP messages")]
+    Bench {
+        #[arg(
+            long,
+            default_value_t = 100000,
+            help = "Number of Route Monitoring messages"
+        )]
+        messages: u32,
+
+        #[arg(
+            long,
+            default_value_t = 10,
+            help = "Number of peers sending the messages"
+        )]
+        peers: u32,
+
+        #[arg(long, default_value_t = 10, help = "Number of prefixes per message")]
+        prefixes: u32,
+    },
+    #[command(about = "Print the updates received over BMP, without state or sinks")]
+    Print {
+        #[arg(default_value = "0.0.0.0:4000")]
+        address: String,
+    },
+}
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Cli {
-    #[arg(short, long)]
-    config: String,
+    #[arg(short, long, required = true)]
+    config: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    #[arg(
+        long,
+        help = "Exit at startup if the Kafka brokers are unreachable, instead of a warning"
+    )]
+    kafka_require_connect: bool,
 
     #[command(flatten)]
     verbose: Verbosity<InfoLevel>,
 }
 
+// Key-value pairs of a log record (e.g. the router and the peer), as fields of the JSON line
+struct JsonFields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), value.to_string().into());
+        Ok(())
+    }
+}
+
 fn set_logging(cli: &Cli) {
-    Builder::new()
-        .format(|buf, record| {
+    let mut builder = Builder::new();
+    match cli.log_format {
+        LogFormat::Text => builder.format(|buf, record| {
             writeln!(
                 buf,
                 "{} [{}] - {}",
@@ -41,7 +130,21 @@ fn set_logging(cli: &Cli) {
                 record.level(),
                 record.args()
             )
-        })
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let mut line = serde_json::json!({
+                "timestamp": Local::now().to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            if let Some(fields) = line.as_object_mut() {
+                let _ = record.key_values().visit(&mut JsonFields(fields));
+            }
+            writeln!(buf, "{}", line)
+        }),
+    };
+    builder
         .filter_module("risotto", cli.verbose.log_level_filter())
         .init();
 }
@@ -55,68 +158,478 @@ fn load_settings(config_path: &str) -> Arc<Config> {
     Arc::new(cfg)
 }
 
-async fn api_handler(state: AsyncState, cfg: Arc<Config>) {
-    let api_config = settings::get_api_config(&cfg).unwrap();
+// The benchmark runs with the state enabled, and the config if given
+fn load_bench_settings(config_path: Option<&str>) -> Result<Config, Box<dyn Error>> {
+    let mut builder = Config::builder()
+        .set_default("state.enable", true)?
+        .set_default("state.path", "")?
+        .set_default("state.save_interval", 0)?;
+    if let Some(config_path) = config_path {
+        builder = builder.add_source(config::File::with_name(config_path));
+    }
+    Ok(builder.build()?)
+}
 
-    debug!("api - binding listener to {}", api_config.host);
-    let api_listener = TcpListener::bind(api_config.host).await.unwrap();
+// Backlog of the listeners without a configured one
+const DEFAULT_BACKLOG: i32 = 1024;
 
-    let app = api::app(state.clone());
-    axum::serve(api_listener, app).await.unwrap();
+// SO_REUSEADDR lets a restarted collector bind again despite the connections in TIME_WAIT
+// The backlog holds the connections of the routers reconnecting all at once, until accepted
+// IPv6 sockets are IPv6 only, so that `::` and `0.0.0.0` can both be bound
+fn listen(addr: SocketAddr, backlog: i32) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    TcpListener::from_std(socket.into())
+}
+
+// Resolve the hosts and bind a listener to each of the resolved addresses
+async fn bind_listeners(
+    name: &str,
+    hosts: &[String],
+    backlog: i32,
+) -> Result<Vec<TcpListener>, Box<dyn Error>> {
+    let mut listeners = Vec::new();
+    for host in hosts {
+        let addrs = lookup_host(host)
+            .await
+            .map_err(|e| format!("{} - failed to resolve {}: {}", name, host, e))?;
+        for addr in addrs {
+            debug!("{} - binding listener to {}", name, addr);
+            let listener = listen(addr, backlog)
+                .map_err(|e| format!("{} - failed to bind {}: {}", name, addr, e))?;
+            listeners.push(listener);
+        }
+    }
+    Ok(listeners)
+}
+
+async fn api_handler(
+    api_listeners: Vec<TcpListener>,
+    state: AsyncState,
+    sessions: AsyncSessions,
+    cfg: Arc<Config>,
+    prometheus: PrometheusHandle,
+    stream_tx: Option<broadcast::Sender<Update>>,
+    tx: Sender<Vec<Update>>,
+) {
+    let app_config = settings::get_app_config(&cfg).unwrap();
+
+    let app = api::app(
+        state.clone(),
+        sessions,
+        prometheus,
+        stream_tx,
+        tx,
+        app_config,
+    );
+
+    let mut tasks = Vec::new();
+    for api_listener in api_listeners {
+        let app = app.clone();
+        tasks.push(tokio::spawn(async move {
+            axum::serve(api_listener, app).await.unwrap();
+        }));
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
 }
 
-async fn bmp_handler(state: AsyncState, cfg: Arc<Config>, tx: Sender<Vec<u8>>) {
+async fn bmp_handler(
+    bmp_listeners: Vec<TcpListener>,
+    state: AsyncState,
+    validator: AsyncValidator,
+    sessions: AsyncSessions,
+    cfg: Arc<Config>,
+    tx: Sender<Vec<Update>>,
+) {
     let bmp_config = settings::get_bmp_config(&cfg).unwrap();
+    let decode_config = Arc::new(settings::get_decode_config(&cfg).unwrap());
 
-    debug!("bmp - binding listener to {}", bmp_config.host);
-    let bmp_listener = TcpListener::bind(bmp_config.host).await.unwrap();
+    // All the listeners feed the same pipeline
+    let mut tasks = Vec::new();
+    for bmp_listener in bmp_listeners {
+        tasks.push(tokio::spawn(bmp_accept_handler(
+            bmp_listener,
+            bmp_config.clone(),
+            state.clone(),
+            validator.clone(),
+            sessions.clone(),
+            decode_config.clone(),
+            tx.clone(),
+        )));
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+}
 
+async fn bmp_accept_handler(
+    bmp_listener: TcpListener,
+    bmp_config: BMPConfig,
+    state: AsyncState,
+    validator: AsyncValidator,
+    sessions: AsyncSessions,
+    decode_config: Arc<DecodeConfig>,
+    tx: Sender<Vec<Update>>,
+) {
     loop {
-        let (mut bmp_socket, _) = bmp_listener.accept().await.unwrap();
+        let (mut bmp_socket, bmp_addr) = bmp_listener.accept().await.unwrap();
+
+        // The connection is closed right away if the source is not allowed
+        if !bmp::is_allowed_source(bmp_addr.ip(), &bmp_config) {
+            // A denied router keeps reconnecting, the port is left out to collapse the lines
+            throttle::log(
+                log::Level::Warn,
+                format!("bmp - {} - connection denied", bmp_addr.ip()),
+            );
+            counter!(
+                "risotto_bmp_connection_denied_total",
+                "source" => bmp_addr.ip().to_string(),
+            )
+            .increment(1);
+            continue;
+        }
+
+        // A source repeatedly sending malformed streams is refused for a while
+        if sessions.lock().unwrap().is_refused(&bmp_addr.ip()) {
+            debug!("bmp - {} - connection refused during backoff", bmp_addr);
+            continue;
+        }
+
+        if let Err(e) = bmp::set_socket_options(&bmp_socket, &bmp_config) {
+            log::warn!("bmp - {} - failed to set socket options: {}", bmp_addr, e);
+        }
+
         let bmp_state = state.clone();
+        let bmp_validator = validator.clone();
+        let bmp_sessions = sessions.clone();
+        let bmp_decode = decode_config.clone();
+        let bmp_config = bmp_config.clone();
         let tx = tx.clone();
 
         // Spawn a new task for each BMP connection
         tokio::spawn(async move {
-            bmp::handle(&mut bmp_socket, bmp_state.clone(), tx).await;
+            let result = bmp::handle(
+                &mut bmp_socket,
+                bmp_state.clone(),
+                bmp_validator,
+                bmp_sessions.clone(),
+                bmp_decode,
+                tx,
+            )
+            .await;
+            if result.is_err() {
+                bmp_sessions
+                    .lock()
+                    .unwrap()
+                    .record_failure(bmp_addr.ip(), &bmp_config);
+            }
         });
     }
 }
 
-async fn producer_handler(cfg: Arc<Config>, rx: Receiver<Vec<u8>>) {
+async fn print_handler(address: &str) -> Result<(), Box<dyn Error>> {
+    let bmp_listener = bind_listeners("bmp", &[address.to_string()], DEFAULT_BACKLOG)
+        .await?
+        .remove(0);
+
+    loop {
+        let (mut bmp_socket, _) = bmp_listener.accept().await.unwrap();
+
+        // Spawn a new task for each BMP connection
+        tokio::spawn(async move {
+            bmp::print(&mut bmp_socket).await;
+        });
+    }
+}
+
+async fn producer_handler(cfg: Arc<Config>, rx: Option<Receiver<Vec<Update>>>) {
+    let Some(rx) = rx else {
+        // Kafka producer is disabled, park the task
+        return std::future::pending().await;
+    };
+    let output = settings::get_output_config(&cfg).unwrap();
     let cfg = settings::get_kafka_config(&cfg).unwrap();
 
-    producer::handle(&cfg, rx).await;
+    producer::handle(&cfg, &output, rx).await;
+}
+
+async fn events_handler(cfg: Arc<Config>, rx: Option<Receiver<PeerEvent>>) {
+    let Some(rx) = rx else {
+        // Peer events are disabled, park the task
+        return std::future::pending().await;
+    };
+    let output = settings::get_output_config(&cfg).unwrap();
+    let cfg = settings::get_kafka_config(&cfg).unwrap();
+    let topic = cfg.events_topic.clone().unwrap();
+
+    producer::handle_events(&cfg, &output, &topic, rx).await;
+}
+
+async fn heartbeat_handler(state: AsyncState, sessions: AsyncSessions, cfg: Arc<Config>) {
+    let cfg = settings::get_kafka_config(&cfg).unwrap();
+    if !cfg.enable || cfg.heartbeat_interval == 0 {
+        // Heartbeats are disabled, park the task
+        return std::future::pending().await;
+    }
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(cfg.heartbeat_interval)).await;
+        let prefixes = state.lock().unwrap().n_prefixes();
+        let sessions = sessions.lock().unwrap();
+        sessions.send_event(events::heartbeat(sessions.n_routers(), prefixes));
+    }
+}
+
+async fn archive_handler(
+    cfg: Arc<Config>,
+    rx: Option<Receiver<Vec<Update>>>,
+    guard: ShutdownGuard,
+) {
+    let Some(rx) = rx else {
+        // Parquet archiving is disabled, park the task
+        return std::future::pending().await;
+    };
+    let output = settings::get_output_config(&cfg).unwrap();
+    let cfg = settings::get_parquet_config(&cfg).unwrap();
+
+    archive::handle(&cfg, &output, rx, guard).await;
+}
+
+async fn sinks_handler(sinks: Vec<SinkChannel>, guard: ShutdownGuard) {
+    if sinks.is_empty() {
+        // No additional sink is enabled, park the task
+        return std::future::pending().await;
+    }
+
+    let mut tasks = Vec::new();
+    for (sink, rx) in sinks {
+        tasks.push(tokio::spawn(sink::handle(sink, rx, guard.clone())));
+    }
+    // Each sink holds its own guard
+    drop(guard);
+    for task in tasks {
+        let _ = task.await;
+    }
+}
+
+async fn rpki_handler(validator: AsyncValidator, cfg: Arc<Config>) {
+    let cfg = settings::get_rpki_config(&cfg).unwrap();
+    if !cfg.enable {
+        // RPKI validation is disabled, park the task
+        return std::future::pending().await;
+    }
+
+    rpki::reload_handler(validator, cfg).await;
 }
 
 async fn state_handler(state: AsyncState, cfg: Arc<Config>) {
     let cfg = settings::get_state_config(&cfg).unwrap();
 
-    state::dump_handler(state.clone(), cfg.clone()).await;
+    // The dump handler only returns on persistent dump failures
+    tokio::select! {
+        _ = state::dump_handler(state.clone(), cfg.clone()) => (),
+        _ = state::purge_handler(state.clone(), cfg.clone()) => (),
+        _ = state::peer_up_withdraws_handler(state.clone(), cfg.clone()) => (),
+        _ = state::audit_handler(state.clone(), cfg.clone()) => (),
+        _ = state::dampening_handler(state.clone(), cfg.clone()) => (),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
-    let cfg = load_settings(&cli.config);
+    match &cli.command {
+        Some(Command::Inspect {
+            state_file,
+            prefixes,
+        }) => return state::inspect(state_file, *prefixes),
+        Some(Command::Bench {
+            messages,
+            peers,
+            prefixes,
+        }) => {
+            set_logging(&cli);
+            let cfg = load_bench_settings(cli.config.as_deref())?;
+            return bench::run(&cfg, *messages, *peers, *prefixes).await;
+        }
+        Some(Command::Print { address }) => {
+            set_logging(&cli);
+            return print_handler(address).await;
+        }
+        None => (),
+    }
+
+    // The config is required when no subcommand is given
+    let cfg = load_settings(cli.config.as_ref().unwrap());
     let state_config = settings::get_state_config(&cfg).unwrap();
+    let kafka_config = settings::get_kafka_config(&cfg).unwrap();
+    let parquet_config = settings::get_parquet_config(&cfg).unwrap();
+    let nats_config = settings::get_nats_config(&cfg).unwrap();
+    let sinks_config = settings::get_sinks_config(&cfg).unwrap();
+    let output_config = settings::get_output_config(&cfg).unwrap();
+    let rpki_config = settings::get_rpki_config(&cfg).unwrap();
     let state = state::new_state(&state_config);
     let shutdown: Shutdown = Shutdown::default();
 
     set_logging(&cli);
 
+    // Global recorder for the metrics exposed by the API
+    // Durations and sizes are exposed as histograms rather than summaries
+    let prometheus = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("duration_seconds".to_string()),
+            &[
+                0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+            ],
+        )?
+        .set_buckets_for_metric(
+            Matcher::Suffix("delay_seconds".to_string()),
+            &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0],
+        )?
+        .set_buckets_for_metric(
+            Matcher::Suffix("latency_seconds".to_string()),
+            &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0],
+        )?
+        .set_buckets_for_metric(
+            Matcher::Suffix("size_bytes".to_string()),
+            &[
+                256.0, 512.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0,
+            ],
+        )?
+        .set_buckets_for_metric(
+            Matcher::Suffix("batch_messages".to_string()),
+            &[1.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0, 10000.0],
+        )?
+        .set_buckets_for_metric(
+            Matcher::Full("risotto_kafka_batch_bytes".to_string()),
+            &[
+                1024.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0,
+            ],
+        )?
+        .install_recorder()?;
+    state::describe_metrics();
+
+    // Check that the Kafka brokers are reachable before accepting BMP connections
+    if kafka_config.enable {
+        if let Err(e) = producer::preflight(&kafka_config, Duration::from_secs(10)).await {
+            if cli.kafka_require_connect {
+                return Err(format!("kafka preflight failed: {}", e).into());
+            }
+            log::warn!("producer - kafka preflight failed: {}", e);
+        }
+    }
+
+    // Load the VRP set if enabled
+    let validator = rpki::new_validator(&rpki_config);
+    // Peer events are produced to their own Kafka topic, if given
+    let (events_tx, events_rx) = match &kafka_config.events_topic {
+        Some(_) if kafka_config.enable => {
+            let (events_tx, events_rx) = channel();
+            (Some(events_tx), Some(events_rx))
+        }
+        _ => (None, None),
+    };
+    let sessions = session::new_sessions(events_tx);
+
     // Load the state if enabled
     if state_config.enable {
         state::load(state.clone());
     }
 
-    // MPSC channel to communicate between BMP tasks and producer task
+    // MPSC channel to communicate between BMP tasks and the sinks
     let (tx, rx) = channel();
 
-    let api_task = shutdown.spawn_task(api_handler(state.clone(), cfg.clone()));
-    let bmp_task = shutdown.spawn_task(bmp_handler(state.clone(), cfg.clone(), tx.clone()));
-    let producer_task = shutdown.spawn_task(producer_handler(cfg.clone(), rx));
+    // Each enabled sink gets its own bounded channel
+    let mut sink_txs = Vec::new();
+    let mut sink_channel = |name: &'static str| {
+        let (sink_tx, sink_rx) = sync_channel(sinks_config.buffer_size);
+        sink_txs.push((name, sink_tx));
+        sink_rx
+    };
+
+    let producer_rx = kafka_config.enable.then(|| sink_channel("kafka"));
+    let archive_rx = parquet_config.enable.then(|| sink_channel("parquet"));
+
+    let mut sinks: Vec<SinkChannel> = Vec::new();
+    if nats_config.enable {
+        let nats_sink = NatsSink::new(nats_config, output_config.clone());
+        sinks.push((Box::new(nats_sink), sink_channel("nats")));
+    }
+    if sinks_config.file_enable {
+        let file_sink = FileSink::new(&sinks_config.file_path, output_config.clone())?;
+        sinks.push((Box::new(file_sink), sink_channel("file")));
+    }
+    if sinks_config.stdout_enable {
+        let stdout_sink = StdoutSink::new(output_config.clone());
+        sinks.push((Box::new(stdout_sink), sink_channel("stdout")));
+    }
+    if sinks_config.counter_enable {
+        sinks.push((Box::new(CounterSink), sink_channel("counter")));
+    }
+    if sinks_config.origins_enable {
+        let origin_sink = OriginSink::new(sinks_config.origins_top);
+        sinks.push((Box::new(origin_sink), sink_channel("origins")));
+    }
+    if sinks_config.mrt_enable {
+        let mrt_sink = MrtSink::new(&sinks_config, output_config.clone())?;
+        sinks.push((Box::new(mrt_sink), sink_channel("mrt")));
+    }
+    let stream_tx = sinks_config
+        .stream_enable
+        .then(|| broadcast::channel(sinks_config.buffer_size).0);
+    if let Some(stream_tx) = &stream_tx {
+        let stream_sink = StreamSink::new(stream_tx.clone());
+        sinks.push((Box::new(stream_sink), sink_channel("stream")));
+    }
+
+    std::thread::spawn(move || sink::fanout(rx, sink_txs));
+
+    // The listeners are bound upfront, so that an unavailable address stops the collector
+    let app_config = settings::get_app_config(&cfg)?;
+    let api_listeners = bind_listeners("api", &app_config.api.hosts, DEFAULT_BACKLOG).await?;
+    let bmp_config = settings::get_bmp_config(&cfg)?;
+    let bmp_listeners = bind_listeners("bmp", &bmp_config.hosts, bmp_config.backlog).await?;
+
+    let api_task = shutdown.spawn_task(api_handler(
+        api_listeners,
+        state.clone(),
+        sessions.clone(),
+        cfg.clone(),
+        prometheus,
+        stream_tx,
+        tx.clone(),
+    ));
+    let bmp_task = shutdown.spawn_task(bmp_handler(
+        bmp_listeners,
+        state.clone(),
+        validator.clone(),
+        sessions.clone(),
+        cfg.clone(),
+        tx.clone(),
+    ));
+    let producer_task = shutdown.spawn_task(producer_handler(cfg.clone(), producer_rx));
+    let events_task = shutdown.spawn_task(events_handler(cfg.clone(), events_rx));
+    let heartbeat_task = shutdown.spawn_task(heartbeat_handler(
+        state.clone(),
+        sessions.clone(),
+        cfg.clone(),
+    ));
+    // The archive and the sinks close their current file on shutdown
+    let archive_task = shutdown.spawn_task_fn({
+        let cfg = cfg.clone();
+        move |guard| archive_handler(cfg, archive_rx, guard)
+    });
+    let sinks_task = shutdown.spawn_task_fn(move |guard| sinks_handler(sinks, guard));
+    let rpki_task = shutdown.spawn_task(rpki_handler(validator.clone(), cfg.clone()));
     let state_task = shutdown.spawn_task(state_handler(state.clone(), cfg.clone()));
 
     tokio::select! {
@@ -132,6 +645,21 @@ async fn main() -> Result<(), Box<dyn Error>> {
         _ = producer_task => {
             info!("producer - handler shutdown");
         }
+        _ = events_task => {
+            info!("events - handler shutdown");
+        }
+        _ = heartbeat_task => {
+            info!("heartbeat - handler shutdown");
+        }
+        _ = archive_task => {
+            info!("archive - handler shutdown");
+        }
+        _ = sinks_task => {
+            info!("sinks - handler shutdown");
+        }
+        _ = rpki_task => {
+            info!("rpki - handler shutdown");
+        }
         _ = state_task => {
             info!("state - handler shutdown");
         }
@@ -0,0 +1,339 @@
+use bgpkit_parser::models::{
+    Asn, AttributeValue, Attributes, Bgp4MpEnum, Bgp4MpMessage, Bgp4MpType, BgpMessage,
+    BgpUpdateMessage, CommonHeader, EntryType, MetaCommunity, MrtMessage, Nlri,
+};
+use bytes::{Bytes, BytesMut};
+use chrono::Utc;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use ipnet::IpNet;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::settings::{OutputConfig, SinksConfig};
+use crate::sink::Sink;
+use crate::update::{output_addr, Update};
+
+// Attributes of an announcement, reconstructed from the update
+// The next hop is not tracked, so the unspecified address stands in for it
+fn attributes(update: &Update) -> Vec<AttributeValue> {
+    let mut attributes = vec![AttributeValue::Origin(update.origin)];
+    if let Some(path) = &update.path {
+        attributes.push(AttributeValue::AsPath {
+            path: path.clone(),
+            is_as4: false,
+        });
+    }
+    match update.prefix.prefix {
+        IpNet::V4(_) => attributes.push(AttributeValue::NextHop(Ipv4Addr::UNSPECIFIED.into())),
+        IpNet::V6(_) => attributes.push(AttributeValue::MpReachNlri(Nlri::new_reachable(
+            update.prefix,
+            Some(Ipv6Addr::UNSPECIFIED.into()),
+        ))),
+    }
+    if let Some(med) = update.med {
+        attributes.push(AttributeValue::MultiExitDiscriminator(med));
+    }
+    if let Some(local_pref) = update.local_pref {
+        attributes.push(AttributeValue::LocalPreference(local_pref));
+    }
+    if let Some(asn) = update.only_to_customer {
+        attributes.push(AttributeValue::OnlyToCustomer(Asn::new_32bit(asn)));
+    }
+
+    let (mut communities, mut extended, mut ipv6_extended, mut large) =
+        (vec![], vec![], vec![], vec![]);
+    for community in &update.communities {
+        match community {
+            MetaCommunity::Plain(c) => communities.push(*c),
+            MetaCommunity::Extended(c) => extended.push(*c),
+            MetaCommunity::Ipv6Extended(c) => ipv6_extended.push(*c),
+            MetaCommunity::Large(c) => large.push(*c),
+        }
+    }
+    if !communities.is_empty() {
+        attributes.push(AttributeValue::Communities(communities));
+    }
+    if !extended.is_empty() {
+        attributes.push(AttributeValue::ExtendedCommunities(extended));
+    }
+    if !ipv6_extended.is_empty() {
+        attributes.push(AttributeValue::Ipv6AddressSpecificExtendedCommunities(
+            ipv6_extended,
+        ));
+    }
+    if !large.is_empty() {
+        attributes.push(AttributeValue::LargeCommunities(large));
+    }
+    attributes
+}
+
+// BGP Update of a single prefix
+// The IPv4 prefixes are in the NLRI fields, the IPv6 ones in the multiprotocol attributes
+fn bgp_update(update: &Update) -> BgpUpdateMessage {
+    let prefix = update.prefix;
+    match (update.announced, prefix.prefix) {
+        (true, IpNet::V4(_)) => BgpUpdateMessage {
+            withdrawn_prefixes: vec![],
+            attributes: Attributes::from_iter(attributes(update)),
+            announced_prefixes: vec![prefix],
+        },
+        (true, IpNet::V6(_)) => BgpUpdateMessage {
+            withdrawn_prefixes: vec![],
+            attributes: Attributes::from_iter(attributes(update)),
+            announced_prefixes: vec![],
+        },
+        (false, IpNet::V4(_)) => BgpUpdateMessage {
+            withdrawn_prefixes: vec![prefix],
+            attributes: Attributes::default(),
+            announced_prefixes: vec![],
+        },
+        (false, IpNet::V6(_)) => BgpUpdateMessage {
+            withdrawn_prefixes: vec![],
+            attributes: Attributes::from_iter([AttributeValue::MpUnreachNlri(
+                Nlri::new_unreachable(prefix),
+            )]),
+            announced_prefixes: vec![],
+        },
+    }
+}
+
+// MRT BGP4MP_MESSAGE_AS4 record of an update, as received from the peer
+// The peer address is anonymized as in the other outputs, in its native family
+// https://datatracker.ietf.org/doc/html/rfc6396#section-4.4.3
+pub fn encode_update(update: &Update, output: &OutputConfig) -> Bytes {
+    let peer_ip = output_addr(update.peer_addr, output).to_canonical();
+    let local_ip = match peer_ip {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let message = MrtMessage::Bgp4Mp(Bgp4MpEnum::Message(Bgp4MpMessage {
+        msg_type: Bgp4MpType::MessageAs4,
+        peer_asn: Asn::new_32bit(update.peer_asn.to_u32()),
+        local_asn: Asn::new_32bit(0),
+        interface_index: 0,
+        peer_ip,
+        local_ip,
+        bgp_message: BgpMessage::Update(bgp_update(update)),
+    }));
+
+    let subtype = Bgp4MpType::MessageAs4 as u16;
+    let data = message.encode(subtype);
+    let header = CommonHeader {
+        timestamp: update.timestamp.timestamp() as u32,
+        microsecond_timestamp: Some(update.timestamp.timestamp_subsec_micros()),
+        entry_type: EntryType::BGP4MP_ET,
+        entry_subtype: subtype,
+        length: data.len() as u32,
+    };
+
+    let mut record = BytesMut::new();
+    record.extend(header.encode());
+    record.extend(data);
+    record.freeze()
+}
+
+// An MRT file being written, renamed to its final name once closed
+struct MrtFile {
+    writer: BufWriter<File>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    opened_at: Instant,
+    size: usize,
+}
+
+impl MrtFile {
+    fn create(output_dir: &str) -> Result<MrtFile, Box<dyn Error>> {
+        let name = format!("risotto-{}.mrt", Utc::now().format("%Y%m%dT%H%M%S%.3f"));
+        let path = PathBuf::from(output_dir).join(name);
+        let tmp_path = path.with_extension("mrt.inprogress");
+        let file = File::create(&tmp_path)?;
+
+        Ok(MrtFile {
+            writer: BufWriter::new(file),
+            tmp_path,
+            path,
+            opened_at: Instant::now(),
+            size: 0,
+        })
+    }
+
+    fn close(mut self) -> Result<PathBuf, Box<dyn Error>> {
+        self.writer.flush()?;
+        std::fs::rename(&self.tmp_path, &self.path)?;
+        Ok(self.path)
+    }
+}
+
+fn rotate(file: Option<MrtFile>) {
+    if let Some(file) = file {
+        match file.close() {
+            Ok(path) => log::info!("mrt - closed {}", path.display()),
+            Err(e) => log::error!("mrt - failed to close MRT file: {}", e),
+        }
+    }
+}
+
+// Writes the updates as MRT records to rotating files, for the MRT tooling
+pub struct MrtSink {
+    output_dir: String,
+    rotate_size: usize,
+    rotate_interval: Duration,
+    output: OutputConfig,
+    current: Option<MrtFile>,
+}
+
+impl MrtSink {
+    pub fn new(cfg: &SinksConfig, output: OutputConfig) -> Result<MrtSink, Box<dyn Error>> {
+        std::fs::create_dir_all(&cfg.mrt_output_dir)?;
+        Ok(MrtSink {
+            output_dir: cfg.mrt_output_dir.clone(),
+            rotate_size: (cfg.mrt_rotate_mb * 1024 * 1024) as usize,
+            rotate_interval: Duration::from_secs(cfg.mrt_rotate_interval),
+            output,
+            current: None,
+        })
+    }
+}
+
+impl Sink for MrtSink {
+    fn name(&self) -> &'static str {
+        "mrt"
+    }
+
+    fn send(&mut self, update: &Update) -> Result<(), Box<dyn Error>> {
+        if self.current.is_none() {
+            self.current = Some(MrtFile::create(&self.output_dir)?);
+        }
+
+        let file = self.current.as_mut().unwrap();
+        let record = encode_update(update, &self.output);
+        file.writer.write_all(&record)?;
+        file.size += record.len();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(file) = &mut self.current else {
+            return Ok(());
+        };
+        file.writer.flush()?;
+
+        // Close the current file if it is due for rotation
+        if file.size >= self.rotate_size || file.opened_at.elapsed() >= self.rotate_interval {
+            rotate(self.current.take());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MrtSink {
+    fn drop(&mut self) {
+        rotate(self.current.take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bgpkit_parser::bmp::messages::{BmpPeerType, RouteMonitoring};
+    use bgpkit_parser::models::{AsPath, ElemType, NetworkPrefix, Origin, Peer};
+    use bgpkit_parser::{parse_mrt_record, Elementor};
+
+    use crate::settings::{get_decode_config, get_output_config};
+    use crate::update::{decode_updates, UpdateHeader};
+
+    // Announcement of a prefix, as decoded from a Route Monitoring message
+    fn update(prefix: &str) -> Update {
+        let prefix: NetworkPrefix = prefix.parse().unwrap();
+        let mut attributes = vec![
+            AttributeValue::Origin(Origin::IGP),
+            AttributeValue::AsPath {
+                path: AsPath::from_sequence([65001, 65002]),
+                is_as4: true,
+            },
+        ];
+        let announced_prefixes = match prefix.prefix {
+            IpNet::V4(_) => {
+                attributes.push(AttributeValue::NextHop("192.0.2.1".parse().unwrap()));
+                vec![prefix]
+            }
+            IpNet::V6(_) => {
+                attributes.push(AttributeValue::MpReachNlri(Nlri::new_reachable(
+                    prefix,
+                    Some("2001:db8::1".parse().unwrap()),
+                )));
+                vec![]
+            }
+        };
+        let message = RouteMonitoring {
+            bgp_message: BgpMessage::Update(BgpUpdateMessage {
+                withdrawn_prefixes: vec![],
+                attributes: Attributes::from_iter(attributes),
+                announced_prefixes,
+            }),
+        };
+        let header = UpdateHeader {
+            router_addr: "10.0.0.1".parse().unwrap(),
+            router_port: 4000,
+            peer: Peer::new(
+                Ipv4Addr::new(192, 0, 2, 254),
+                "192.0.2.1".parse().unwrap(),
+                Asn::new_32bit(65001),
+            ),
+            peer_type: BmpPeerType::Global,
+            peer_distinguisher: 0,
+            timestamp: Some(1700000000123),
+            is_post_policy: false,
+            is_adj_rib_out: false,
+            raw: Bytes::new(),
+            received_at: Instant::now(),
+        };
+        let decode = get_decode_config(&config::Config::default()).unwrap();
+        decode_updates(message, header, None, &decode)
+            .unwrap()
+            .remove(0)
+    }
+
+    fn round_trip(update: &Update, output: &OutputConfig) -> Vec<bgpkit_parser::BgpElem> {
+        let record = encode_update(update, output);
+        let record = parse_mrt_record(&mut record.as_ref()).unwrap();
+        Elementor::new().record_to_elems(record)
+    }
+
+    #[test]
+    fn mrt_round_trip() {
+        let output = get_output_config(&config::Config::default()).unwrap();
+        for prefix in ["198.51.100.0/24", "2001:db8:1::/48"] {
+            let update = update(prefix);
+            let elems = round_trip(&update, &output);
+            assert_eq!(elems.len(), 1);
+            let elem = &elems[0];
+            assert_eq!(elem.elem_type, ElemType::ANNOUNCE);
+            assert_eq!(elem.prefix, update.prefix);
+            assert_eq!(elem.peer_ip, "192.0.2.1".parse::<IpAddr>().unwrap());
+            assert_eq!(elem.peer_asn, Asn::new_32bit(65001));
+            assert_eq!(elem.as_path, update.path);
+            assert_eq!(elem.timestamp, 1700000000.123);
+
+            // And back as a withdraw
+            let mut withdraw = update.clone();
+            withdraw.announced = false;
+            let elems = round_trip(&withdraw, &output);
+            assert_eq!(elems.len(), 1);
+            assert_eq!(elems[0].elem_type, ElemType::WITHDRAW);
+            assert_eq!(elems[0].prefix, update.prefix);
+        }
+    }
+
+    #[test]
+    fn mrt_anonymized() {
+        let mut output = get_output_config(&config::Config::default()).unwrap();
+        output.anonymize_key = Some(b"secret".to_vec());
+        let elems = round_trip(&update("198.51.100.0/24"), &output);
+        assert!(elems[0].peer_ip.is_ipv4());
+        assert_ne!(elems[0].peer_ip, "192.0.2.1".parse::<IpAddr>().unwrap());
+    }
+}
@@ -0,0 +1,159 @@
+use metrics::counter;
+use std::error::Error;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::settings::{NatsConfig, OutputConfig};
+use crate::sink::Sink;
+use crate::update::{check_update, format_update, Update};
+
+// A connection to a NATS server, speaking the client protocol
+// https://docs.nats.io/reference/reference-protocols/nats-protocol
+struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    fn connect(host: &str) -> Result<Connection, Box<dyn Error>> {
+        let stream = TcpStream::connect(host)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        // The server greets with its INFO
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if !line.starts_with("INFO") {
+            return Err(format!("unexpected greeting: {}", line.trim()).into());
+        }
+
+        // The PONG acknowledges the CONNECT, an authorization error is sent before
+        let mut writer = stream.try_clone()?;
+        writer.write_all(
+            b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"risotto\",\"lang\":\"rust\"}\r\nPING\r\n",
+        )?;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err("connection closed by the server".into());
+            }
+            match line.trim() {
+                "PONG" => break,
+                "PING" => writer.write_all(b"PONG\r\n")?,
+                l if l.starts_with("-ERR") => return Err(l.to_string().into()),
+                _ => (),
+            }
+        }
+
+        // From now on, the server messages are only polled between batches
+        stream.set_read_timeout(Some(Duration::from_millis(1)))?;
+        Ok(Connection { stream })
+    }
+
+    // Answer the server keepalive PINGs received in the meantime
+    fn poll(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err("connection closed by the server".into()),
+                Ok(n) => {
+                    let messages = String::from_utf8_lossy(&buf[..n]);
+                    for message in messages.lines() {
+                        if message.starts_with("PING") {
+                            self.stream.write_all(b"PONG\r\n")?;
+                        } else if message.starts_with("-ERR") {
+                            log::error!("nats - {}", message);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Ok(())
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn publish(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.poll()?;
+        self.stream.write_all(data)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+// Publishes the updates to a NATS subject, one message per update
+// With JetStream, the subject is bound to a stream to persist the updates
+pub struct NatsSink {
+    cfg: NatsConfig,
+    output: OutputConfig,
+    connection: Option<Connection>,
+    data: Vec<u8>,
+    n_messages: u64,
+}
+
+impl NatsSink {
+    pub fn new(cfg: NatsConfig, output: OutputConfig) -> NatsSink {
+        NatsSink {
+            cfg,
+            output,
+            connection: None,
+            data: Vec::new(),
+            n_messages: 0,
+        }
+    }
+
+    fn publish(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.connection.is_none() {
+            self.connection = Some(Connection::connect(&self.cfg.host)?);
+            log::debug!("nats - connected to {}", self.cfg.host);
+        }
+        let connection = self.connection.as_mut().unwrap();
+        if let Err(e) = connection.publish(&self.data) {
+            self.connection = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+impl Sink for NatsSink {
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+
+    fn send(&mut self, update: &Update) -> Result<(), Box<dyn Error>> {
+        let payload = format_update(update, &self.output);
+        if self.output.check_serialization {
+            check_update(update, &payload, &self.output);
+        }
+        write!(
+            self.data,
+            "PUB {} {}\r\n{}\r\n",
+            self.cfg.subject,
+            payload.len(),
+            payload
+        )?;
+        self.n_messages += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.data.is_empty() {
+            return Ok(());
+        }
+
+        // The server closes idle connections, so the batch is retried once on a new one
+        let result = self.publish().or_else(|e| {
+            log::warn!("nats - failed publishing messages: {}, reconnecting", e);
+            self.publish()
+        });
+
+        let status = if result.is_ok() { "ok" } else { "error" };
+        counter!("risotto_messages_produced_total", "broker" => "nats", "status" => status)
+            .increment(self.n_messages);
+        self.data.clear();
+        self.n_messages = 0;
+        result
+    }
+}
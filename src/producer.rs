@@ -1,13 +1,18 @@
+use core::net::IpAddr;
 use kafka::client::{Compression, DEFAULT_CONNECTION_IDLE_TIMEOUT_MILLIS};
 use kafka::producer::{AsBytes, Producer, Record, RequiredAcks, DEFAULT_ACK_TIMEOUT_MILLIS};
+use metrics::{counter, gauge, histogram};
 use std::error::Error;
 use std::io::BufRead;
 use std::io::Cursor;
 use std::ops::{Deref, DerefMut};
 use std::sync::mpsc::{Receiver, TryRecvError};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::settings::KafkaConfig;
+use crate::events::{format_event, PeerEvent};
+use crate::settings::{AdjRibOut, KafkaConfig, OutputConfig};
+use crate::throttle;
+use crate::update::{check_update, format_update, map_to_ipv6, stable_hash, Update};
 
 struct Trimmed(String);
 
@@ -30,26 +35,52 @@ impl DerefMut for Trimmed {
     }
 }
 
+// Hash of the peer of an update, so that its updates always land in the same partition,
+// including after a restart or an upgrade
+fn peer_hash(update: &Update) -> u64 {
+    let mut data = Vec::with_capacity(32);
+    for addr in [update.router_addr, update.peer_addr] {
+        if let IpAddr::V6(addr) = map_to_ipv6(addr) {
+            data.extend(addr.octets());
+        }
+    }
+    stable_hash(&data)
+}
+
+// Number of partitions of the topic, from the metadata loaded by the producer
+fn partition_count(producer: &Producer, topic: &str) -> Option<usize> {
+    let topics = producer.client().topics();
+    let n_partitions = topics.partitions(topic)?.len();
+    (n_partitions > 0).then_some(n_partitions)
+}
+
+// `partitions` gives the partition of each line, if any
+// Otherwise the producer picks the partition
+// `trigger` is what triggered the flush, recorded on each of its produce requests
 fn produce_impl(
     producer: &mut Producer,
     cfg: &KafkaConfig,
+    topic: &str,
     data: &mut dyn BufRead,
+    partitions: &[i32],
+    trigger: &'static str,
 ) -> Result<usize, Box<dyn Error>> {
     // ~ a buffer of prepared records to be send in a batch to Kafka
     // ~ in the loop following, we'll only modify the 'value' of the
     // cached records
     let mut rec_stash: Vec<Record<'_, (), Trimmed>> = (0..cfg.batch_max_size)
-        .map(|_| Record::from_value(&cfg.topic, Trimmed(String::new())))
+        .map(|_| Record::from_value(topic, Trimmed(String::new())))
         .collect();
 
     // ~ points to the next free slot in `rec_stash`.  if it reaches
     // `rec_stash.len()` we'll send `rec_stash` to kafka
     let mut next_rec = 0;
     let mut n_rec = 0;
+    let mut n_line = 0;
     loop {
         // ~ send out a batch if it's ready
         if next_rec == rec_stash.len() {
-            send_batch(producer, &rec_stash)?;
+            send_batch(producer, topic, &rec_stash, trigger, true)?;
             next_rec = 0;
         }
         let rec = &mut rec_stash[next_rec];
@@ -57,6 +88,8 @@ fn produce_impl(
         if data.read_line(&mut rec.value)? == 0 {
             break; // ~ EOF reached
         }
+        rec.partition = partitions.get(n_line).copied().unwrap_or(-1);
+        n_line += 1;
         if rec.value.trim().is_empty() {
             continue; // ~ skip empty lines
         }
@@ -66,15 +99,28 @@ fn produce_impl(
     }
     // ~ flush pending messages - if any
     if next_rec > 0 {
-        send_batch(producer, &rec_stash[..next_rec])?;
+        send_batch(producer, topic, &rec_stash[..next_rec], trigger, false)?;
     }
     Ok(n_rec)
 }
 
 fn send_batch(
     producer: &mut Producer,
+    topic: &str,
     batch: &[Record<'_, (), Trimmed>],
+    trigger: &'static str,
+    full: bool,
 ) -> Result<(), Box<dyn Error>> {
+    // Fill of the produce requests, to tune the batching
+    let n_bytes: usize = batch.iter().map(|rec| rec.value.as_bytes().len()).sum();
+    let labels = [
+        ("topic", topic.to_string()),
+        ("trigger", trigger.to_string()),
+        ("full", full.to_string()),
+    ];
+    histogram!("risotto_kafka_batch_messages", &labels).record(batch.len() as f64);
+    histogram!("risotto_kafka_batch_bytes", &labels).record(n_bytes as f64);
+
     let rs = producer.send_all(batch)?;
 
     for r in rs {
@@ -88,44 +134,345 @@ fn send_batch(
     Ok(())
 }
 
-pub async fn handle(cfg: &KafkaConfig, rx: Receiver<Vec<u8>>) {
-    // TODO: Allow multiple brokers via the config file
-    let mut client = kafka::client::KafkaClient::new(vec![cfg.host.to_owned()]);
+// Load the metadata of the topic once, to check that the brokers are reachable
+pub async fn preflight(cfg: &KafkaConfig, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let host = cfg.host.clone();
+    let topic = cfg.topic.clone();
+    let load = tokio::task::spawn_blocking(move || {
+        let mut client = kafka::client::KafkaClient::new(vec![host]);
+        client.load_metadata(&[topic])
+    });
 
-    // Wait until the metadata we succeed to reach the Kafka brokers
-    loop {
-        match client.load_metadata(&[cfg.topic.to_owned()]) {
-            Ok(_) => {
-                log::debug!("producer - metadata loaded");
-                break;
-            }
-            Err(_) => {
-                log::error!("producer - failed to load metadata: retrying in 5 seconds");
-                tokio::time::sleep(Duration::from_secs(5)).await;
-            }
+    match tokio::time::timeout(timeout, load).await {
+        Ok(Ok(Ok(()))) => {
+            log::info!("producer - kafka brokers reachable at {}", cfg.host);
+            Ok(())
         }
+        Ok(Ok(Err(e))) => Err(format!("failed to load metadata from {}: {}", cfg.host, e).into()),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(format!(
+            "no metadata from {} within {} seconds",
+            cfg.host,
+            timeout.as_secs()
+        )
+        .into()),
     }
+}
+
+// Producer to a cluster, once its metadata is loaded
+fn connect(host: &str, topics: &[String]) -> Result<Producer, Box<dyn Error>> {
+    // TODO: Allow multiple brokers via the config file
+    let mut client = kafka::client::KafkaClient::new(vec![host.to_owned()]);
+    client.load_metadata(topics)?;
+    log::debug!("producer - metadata loaded from {}", host);
 
     // TODO: Allow compression setting via the config file
     // TODO: Allow timeouts setting via the config file
-    let mut producer = Producer::from_client(client)
+    let producer = Producer::from_client(client)
         .with_ack_timeout(Duration::from_millis(DEFAULT_ACK_TIMEOUT_MILLIS))
         .with_connection_idle_timeout(Duration::from_millis(
             DEFAULT_CONNECTION_IDLE_TIMEOUT_MILLIS,
         ))
         .with_required_acks(RequiredAcks::One)
         .with_compression(Compression::NONE)
-        .create()
-        .unwrap();
+        .create()?;
+    Ok(producer)
+}
+
+const CLUSTERS: [&str; 2] = ["primary", "secondary"];
+
+// Producer to the active cluster, the primary one or the secondary one, if any
+// It fails over to the other cluster after `failover_after` consecutive failed flushes,
+// and back to the primary cluster once reachable again, probed every `failback_interval`
+struct ClusterProducer {
+    producer: Producer,
+    name: &'static str,
+    hosts: Vec<String>,
+    topics: Vec<String>,
+    partition_by_peer: bool,
+    active: usize,
+    failures: u64,
+    failover_after: u64,
+    failback_interval: Duration,
+    switched_at: Instant,
+}
 
+impl ClusterProducer {
+    // Wait until the metadata is loaded from a cluster, the primary one first
+    async fn new(
+        cfg: &KafkaConfig,
+        name: &'static str,
+        topics: &[String],
+        partition_by_peer: bool,
+    ) -> ClusterProducer {
+        let mut hosts = vec![cfg.host.clone()];
+        hosts.extend(cfg.secondary_host.clone());
+
+        loop {
+            for (active, host) in hosts.iter().enumerate() {
+                match connect(host, topics) {
+                    Ok(producer) => {
+                        let producer = ClusterProducer {
+                            producer,
+                            name,
+                            hosts: hosts.clone(),
+                            topics: topics.to_vec(),
+                            partition_by_peer,
+                            active,
+                            failures: 0,
+                            failover_after: cfg.failover_after,
+                            failback_interval: Duration::from_secs(cfg.failback_interval),
+                            switched_at: Instant::now(),
+                        };
+                        producer.connected();
+                        return producer;
+                    }
+                    Err(_) => throttle::log(
+                        log::Level::Error,
+                        format!("producer - failed to load metadata from {}", host),
+                    ),
+                }
+            }
+
+            // Retry until a cluster is reachable
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    }
+
+    fn connected(&self) {
+        gauge!("risotto_kafka_active_cluster", "producer" => self.name).set(self.active as f64);
+        if !self.partition_by_peer {
+            return;
+        }
+
+        // The updates of each peer are pinned to a partition, if the number of partitions is known
+        for topic in &self.topics {
+            match partition_count(&self.producer, topic) {
+                Some(n) => log::info!("producer - pinning peers to {} partitions of {}", n, topic),
+                None => log::warn!(
+                    "producer - unknown number of partitions for {}, not pinning peers",
+                    topic
+                ),
+            }
+        }
+    }
+
+    // Switch to a cluster, if reachable
+    fn switch(&mut self, active: usize) -> bool {
+        let host = &self.hosts[active];
+        match connect(host, &self.topics) {
+            Ok(producer) => {
+                log::warn!(
+                    "producer - {} - switched to the {} cluster at {}",
+                    self.name,
+                    CLUSTERS[active],
+                    host
+                );
+                self.producer = producer;
+                self.active = active;
+                self.failures = 0;
+                self.switched_at = Instant::now();
+                self.connected();
+                true
+            }
+            Err(e) => {
+                throttle::log(
+                    log::Level::Error,
+                    format!(
+                        "producer - {} - failed to switch to the {} cluster at {}: {}",
+                        self.name, CLUSTERS[active], host, e
+                    ),
+                );
+                false
+            }
+        }
+    }
+
+    // Produce to the active cluster
+    // A flush failing over to the other cluster is retried there, possibly producing duplicates
+    fn produce<T>(
+        &mut self,
+        mut produce: impl FnMut(&mut Producer) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        if self.active > 0 && self.switched_at.elapsed() >= self.failback_interval {
+            self.switched_at = Instant::now();
+            if self.switch(0) {
+                // Only the metadata was loaded, a single failed flush fails over again
+                self.failures = self.failover_after - 1;
+            }
+        }
+
+        let mut result = produce(&mut self.producer);
+        if result.is_err() {
+            self.failures += 1;
+            if self.hosts.len() > 1
+                && self.failures >= self.failover_after
+                && self.switch(1 - self.active)
+            {
+                result = produce(&mut self.producer);
+            }
+        }
+        if result.is_ok() {
+            self.failures = 0;
+        }
+        result
+    }
+}
+
+// Updates collected for a topic until the next flush
+struct Batch {
+    topic: String,
+    partition_by_peer: bool,
+    data: Vec<u8>,
+    peers: Vec<u64>,
+    received: Vec<Instant>,
+    n_messages: u64,
+}
+
+impl Batch {
+    fn new(cfg: &KafkaConfig, topic: &str) -> Batch {
+        Batch {
+            topic: topic.to_string(),
+            partition_by_peer: cfg.partition_by_peer,
+            data: Vec::new(),
+            peers: Vec::new(),
+            received: Vec::new(),
+            n_messages: 0,
+        }
+    }
+
+    fn push(&mut self, update: &Update, message: &str) {
+        self.data.extend(message.as_bytes());
+        self.data.extend(b"\n");
+        if self.partition_by_peer {
+            self.peers.push(peer_hash(update));
+        }
+        self.received.extend(update.received_at);
+        self.n_messages += 1;
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+        self.peers.clear();
+        self.received.clear();
+        self.n_messages = 0;
+    }
+
+    // Send the collected messages to Kafka in batches
+    // On failure, they are kept to be retried if failing over to the other cluster
+    fn flush(
+        &mut self,
+        producer: &mut Producer,
+        cfg: &KafkaConfig,
+        trigger: &'static str,
+    ) -> Result<(), Box<dyn Error>> {
+        // The number of partitions is looked up on each flush, as it may differ between clusters
+        let partitions: Vec<i32> = match partition_count(producer, &self.topic) {
+            Some(n) => self
+                .peers
+                .iter()
+                .map(|hash| (hash % n as u64) as i32)
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let mut batch = Cursor::new(&self.data);
+        let start = Instant::now();
+        let n = produce_impl(producer, cfg, &self.topic, &mut batch, &partitions, trigger)?;
+        log::info!("producer - produced {} messages to {}", n, self.topic);
+        counter!("risotto_messages_produced_total", "broker" => "kafka", "status" => "ok")
+            .increment(n as u64);
+
+        // From the reception of the BMP message to the acknowledgment of the brokers
+        // Along with the produce duration, to tell the batching wait from the brokers latency
+        histogram!("risotto_kafka_produce_duration_seconds").record(start.elapsed().as_secs_f64());
+        let latency = histogram!("risotto_end_to_end_latency_seconds");
+        for received_at in &self.received {
+            latency.record(received_at.elapsed().as_secs_f64());
+        }
+
+        self.clear();
+        Ok(())
+    }
+
+    // Drop the collected messages that failed to be produced
+    fn discard(&mut self, e: Box<dyn Error>) {
+        throttle::log(
+            log::Level::Error,
+            format!(
+                "producer - failed producing messages to {}: {}",
+                self.topic, e
+            ),
+        );
+        counter!("risotto_messages_produced_total", "broker" => "kafka", "status" => "error")
+            .increment(self.n_messages);
+        self.clear();
+    }
+}
+
+pub async fn handle(cfg: &KafkaConfig, output: &OutputConfig, rx: Receiver<Vec<Update>>) {
+    // The Adj-RIB-Out updates can be routed to their own topic
+    let adj_rib_out_topic = match cfg.adj_rib_out {
+        AdjRibOut::Topic => cfg.adj_rib_out_topic.clone(),
+        _ => None,
+    };
+    let mut topics = vec![cfg.topic.clone()];
+    topics.extend(adj_rib_out_topic.clone());
+    let mut producer = ClusterProducer::new(cfg, "updates", &topics, cfg.partition_by_peer).await;
+
+    let mut main = Batch::new(cfg, &cfg.topic);
+    let mut adj_rib_out = adj_rib_out_topic.map(|topic| Batch::new(cfg, &topic));
+
+    // With the idle flush enabled, the channel is polled every `batch_min_flush_ms`
+    // and the buffered messages are flushed as soon as no new message comes in
+    let batch_interval = Duration::from_secs(cfg.batch_interval);
+    let min_flush = Duration::from_millis(cfg.batch_min_flush_ms);
+    let poll_interval = if min_flush.is_zero() {
+        batch_interval
+    } else {
+        min_flush.min(batch_interval)
+    };
+
+    let mut last_flush = Instant::now();
     loop {
-        // Wait the batch wait time to collect messages
-        tokio::time::sleep(Duration::from_secs(cfg.batch_interval)).await;
-        let mut data = Vec::new();
+        // Wait the poll interval to collect messages
+        tokio::time::sleep(poll_interval).await;
+        let mut idle = true;
         loop {
             // Collect all of the messages from BMP handler
             match rx.try_recv() {
-                Ok(d) => data.extend(d),
+                Ok(updates) => {
+                    for update in updates {
+                        idle = false;
+                        if update.is_adj_rib_out && cfg.adj_rib_out == AdjRibOut::Drop {
+                            counter!("risotto_adj_rib_out_dropped_total").increment(1);
+                            continue;
+                        }
+
+                        let message = format_update(&update, output);
+                        if output.check_serialization {
+                            check_update(&update, &message, output);
+                        }
+                        histogram!("risotto_update_size_bytes").record(message.len() as f64);
+
+                        // The brokers would reject the whole batch with an oversized message
+                        if message.len() > cfg.message_max_bytes {
+                            log::warn!(
+                                "producer - dropping {} bytes update for {} from {}",
+                                message.len(),
+                                update.prefix.prefix,
+                                update.peer_addr
+                            );
+                            counter!("risotto_oversized_updates_total").increment(1);
+                            continue;
+                        }
+
+                        match adj_rib_out.as_mut() {
+                            Some(batch) if update.is_adj_rib_out => batch.push(&update, &message),
+                            _ => main.push(&update, &message),
+                        }
+                    }
+                }
                 Err(TryRecvError::Empty) => break,
                 Err(TryRecvError::Disconnected) => {
                     log::error!("producer - BMP handler disconnected");
@@ -134,21 +481,84 @@ pub async fn handle(cfg: &KafkaConfig, rx: Receiver<Vec<u8>>) {
             }
         }
 
+        // Flush once the batch waiting time has elapsed,
+        // or earlier if the feed went quiet with messages buffered
+        let empty = main.data.is_empty() && adj_rib_out.as_ref().is_none_or(|b| b.data.is_empty());
+        let idle_flush = !min_flush.is_zero() && idle && !empty;
+        let trigger = match last_flush.elapsed() < batch_interval {
+            true if idle_flush => "idle",
+            true => continue,
+            false => "interval",
+        };
+        last_flush = Instant::now();
+
         // If no data was collected within the batch waiting time,
         // continue to the next iteration
-        if data.is_empty() {
+        if empty {
             log::debug!("producer - produced 0 messages");
             continue;
         }
 
-        // Send the collected messages to Kafka in batches
-        let mut data = Cursor::new(data);
-        match produce_impl(&mut producer, cfg, &mut data) {
+        for batch in std::iter::once(&mut main).chain(adj_rib_out.as_mut()) {
+            if batch.data.is_empty() {
+                continue;
+            }
+            if let Err(e) = producer.produce(|producer| batch.flush(producer, cfg, trigger)) {
+                batch.discard(e);
+            }
+        }
+    }
+}
+
+// Peer events are produced to their own topic, at the batch interval
+pub async fn handle_events(
+    cfg: &KafkaConfig,
+    output: &OutputConfig,
+    topic: &str,
+    rx: Receiver<PeerEvent>,
+) {
+    let mut producer = ClusterProducer::new(cfg, "events", &[topic.to_string()], false).await;
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(cfg.batch_interval)).await;
+        let mut data = Vec::new();
+        let mut n_events = 0;
+        loop {
+            match rx.try_recv() {
+                Ok(event) => {
+                    data.extend(format_event(&event, output).as_bytes());
+                    data.extend(b"\n");
+                    n_events += 1;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    log::error!("producer - BMP handler disconnected");
+                    return;
+                }
+            }
+        }
+
+        if data.is_empty() {
+            continue;
+        }
+
+        let result = producer.produce(|producer| {
+            let mut data = Cursor::new(&data);
+            produce_impl(producer, cfg, topic, &mut data, &[], "interval")
+        });
+        match result {
             Ok(n) => {
-                log::info!("producer - produced {} messages", n)
+                log::info!("producer - produced {} peer events", n);
+                counter!("risotto_messages_produced_total", "broker" => "kafka", "status" => "ok")
+                    .increment(n as u64);
             }
             Err(e) => {
-                log::error!("producer - failed producing messages: {}", e);
+                throttle::log(
+                    log::Level::Error,
+                    format!("producer - failed producing peer events: {}", e),
+                );
+                counter!("risotto_messages_produced_total", "broker" => "kafka", "status" => "error")
+                    .increment(n_events);
             }
         };
     }
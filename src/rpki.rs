@@ -0,0 +1,152 @@
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use prefix_trie::PrefixMap;
+use serde::Deserialize;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::settings::RpkiConfig;
+
+pub type AsyncValidator = Arc<RwLock<Option<Validator>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RpkiStatus {
+    Valid,
+    Invalid,
+    NotFound,
+}
+
+impl RpkiStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RpkiStatus::Valid => "valid",
+            RpkiStatus::Invalid => "invalid",
+            RpkiStatus::NotFound => "not-found",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum VrpAsn {
+    Number(u32),
+    String(String),
+}
+
+#[derive(Deserialize)]
+struct Vrp {
+    asn: VrpAsn,
+    prefix: IpNet,
+    #[serde(rename = "maxLength")]
+    max_length: u8,
+}
+
+// VRP set as exported in JSON by routinator
+// {"roas": [{"asn": "AS13335", "prefix": "1.1.1.0/24", "maxLength": 24, "ta": "apnic"}]}
+#[derive(Deserialize)]
+struct VrpFile {
+    roas: Vec<Vrp>,
+}
+
+// Authorized (max length, origin ASN) pairs of a VRP prefix
+type Authorizations = Vec<(u8, u32)>;
+
+#[derive(Default)]
+pub struct Validator {
+    ipv4: PrefixMap<Ipv4Net, Authorizations>,
+    ipv6: PrefixMap<Ipv6Net, Authorizations>,
+    vrps: usize,
+}
+
+impl Validator {
+    pub fn from_file(path: &str) -> Result<Validator, Box<dyn Error>> {
+        let file = std::fs::File::open(path)?;
+        let reader = std::io::BufReader::new(file);
+        let vrp_file: VrpFile = serde_json::from_reader(reader)?;
+
+        let mut validator = Validator::default();
+        for vrp in vrp_file.roas {
+            let asn = match vrp.asn {
+                VrpAsn::Number(asn) => asn,
+                VrpAsn::String(asn) => asn.trim_start_matches("AS").parse()?,
+            };
+            let authorization = (vrp.max_length, asn);
+            match vrp.prefix.trunc() {
+                IpNet::V4(prefix) => validator
+                    .ipv4
+                    .entry(prefix)
+                    .or_default()
+                    .push(authorization),
+                IpNet::V6(prefix) => validator
+                    .ipv6
+                    .entry(prefix)
+                    .or_default()
+                    .push(authorization),
+            }
+            validator.vrps += 1;
+        }
+        Ok(validator)
+    }
+
+    pub fn len(&self) -> usize {
+        self.vrps
+    }
+
+    // Route origin validation
+    // https://datatracker.ietf.org/doc/html/rfc6811#section-2
+    pub fn validate(&self, prefix: &IpNet, origin: Option<u32>) -> RpkiStatus {
+        let prefix_len = prefix.prefix_len();
+        let prefix = prefix.trunc();
+        let covering: Vec<&Authorizations> = match &prefix {
+            IpNet::V4(prefix) => self.ipv4.cover(prefix).map(|(_, a)| a).collect(),
+            IpNet::V6(prefix) => self.ipv6.cover(prefix).map(|(_, a)| a).collect(),
+        };
+
+        if covering.is_empty() {
+            return RpkiStatus::NotFound;
+        }
+
+        // An AS_SET or empty path origin never matches a VRP
+        let Some(origin) = origin else {
+            return RpkiStatus::Invalid;
+        };
+
+        for authorizations in covering {
+            for (max_length, asn) in authorizations {
+                if *asn != 0 && *asn == origin && prefix_len <= *max_length {
+                    return RpkiStatus::Valid;
+                }
+            }
+        }
+        RpkiStatus::Invalid
+    }
+}
+
+pub fn new_validator(cfg: &RpkiConfig) -> AsyncValidator {
+    let validator = if cfg.enable { load(cfg) } else { None };
+    Arc::new(RwLock::new(validator))
+}
+
+fn load(cfg: &RpkiConfig) -> Option<Validator> {
+    match Validator::from_file(&cfg.path) {
+        Ok(validator) => {
+            log::info!("rpki - loaded {} VRPs from {}", validator.len(), cfg.path);
+            Some(validator)
+        }
+        Err(e) => {
+            log::error!("rpki - failed to load VRPs from {}: {}", cfg.path, e);
+            None
+        }
+    }
+}
+
+pub async fn reload_handler(validator: AsyncValidator, cfg: RpkiConfig) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(cfg.reload_interval)).await;
+
+        // Keep the current VRP set if the new one cannot be loaded
+        if let Some(new_validator) = load(&cfg) {
+            *validator.write().unwrap() = Some(new_validator);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use chrono::{DateTime, Utc};
+use core::net::{IpAddr, SocketAddr};
+use metrics::{counter, gauge};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::events::PeerEvent;
+use crate::settings::BMPConfig;
+use crate::state::PeerKey;
+use crate::update::map_to_ipv6;
+
+pub type AsyncSessions = Arc<Mutex<Sessions>>;
+
+pub fn new_sessions(events_tx: Option<Sender<PeerEvent>>) -> AsyncSessions {
+    Arc::new(Mutex::new(Sessions::new(events_tx)))
+}
+
+// A BMP session with a router
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub socket: SocketAddr,
+    pub bmp_version: Option<u8>,
+    pub sys_name: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    pub messages: u64,
+    // Established peers (by address and distinguisher), along with the time of their Peer Up
+    pub established_peers: HashMap<PeerKey, DateTime<Utc>>,
+}
+
+// Recent failures of the BMP sessions from a source
+#[derive(Default)]
+struct Failures {
+    at: VecDeque<Instant>,
+    refused_until: Option<Instant>,
+}
+
+pub struct Sessions {
+    sessions: HashMap<SocketAddr, Session>,
+    failures: HashMap<IpAddr, Failures>,
+    events_tx: Option<Sender<PeerEvent>>,
+}
+
+impl Sessions {
+    fn new(events_tx: Option<Sender<PeerEvent>>) -> Sessions {
+        Sessions {
+            sessions: HashMap::new(),
+            failures: HashMap::new(),
+            events_tx,
+        }
+    }
+
+    // Account for a BMP session closed on a malformed stream
+    // Repeated failures within the window refuse the source for the backoff duration
+    pub fn record_failure(&mut self, source: IpAddr, cfg: &BMPConfig) {
+        if cfg.backoff_failures == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let window = Duration::from_secs(cfg.backoff_window);
+        let failures = self.failures.entry(source).or_default();
+        failures.at.push_back(now);
+        while let Some(at) = failures.at.front() {
+            if now.duration_since(*at) <= window {
+                break;
+            }
+            failures.at.pop_front();
+        }
+
+        if failures.at.len() >= cfg.backoff_failures {
+            log::warn!(
+                "bmp - {} - refusing connections for {} seconds after {} failures",
+                source,
+                cfg.backoff_duration,
+                failures.at.len()
+            );
+            failures.at.clear();
+            failures.refused_until = Some(now + Duration::from_secs(cfg.backoff_duration));
+        }
+    }
+
+    // Check whether a new connection from a source is to be refused
+    pub fn is_refused(&mut self, source: &IpAddr) -> bool {
+        let Some(failures) = self.failures.get_mut(source) else {
+            return false;
+        };
+        match failures.refused_until {
+            Some(refused_until) if Instant::now() < refused_until => {
+                counter!(
+                    "risotto_bmp_connection_backoff_total",
+                    "source" => source.to_string(),
+                )
+                .increment(1);
+                true
+            }
+            Some(_) => {
+                failures.refused_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Register a new BMP session
+    pub fn open(&mut self, socket: SocketAddr) {
+        self.sessions.insert(
+            socket,
+            Session {
+                socket,
+                bmp_version: None,
+                sys_name: None,
+                connected_at: Utc::now(),
+                messages: 0,
+                established_peers: HashMap::new(),
+            },
+        );
+    }
+
+    // Remove a BMP session on connection teardown
+    // Its peers are no longer established, even without a Peer Down notification
+    pub fn close(&mut self, socket: &SocketAddr) {
+        if let Some(session) = self.sessions.remove(socket) {
+            for peer in session.established_peers.keys() {
+                record_established(socket, peer, false);
+                record_uptime(socket, peer, 0);
+            }
+            self.record_established_peers(socket);
+        }
+    }
+
+    // Account for a Peer Up (with its time) or Peer Down notification received on a BMP session
+    // A re-established peer starts over its uptime
+    pub fn set_established(
+        &mut self,
+        socket: &SocketAddr,
+        peer: PeerKey,
+        established_at: Option<DateTime<Utc>>,
+    ) {
+        let Some(session) = self.sessions.get_mut(socket) else {
+            return;
+        };
+        match established_at {
+            Some(established_at) => {
+                session.established_peers.insert(peer, established_at);
+            }
+            None => {
+                session.established_peers.remove(&peer);
+                record_uptime(socket, &peer, 0);
+            }
+        }
+        record_established(socket, &peer, established_at.is_some());
+        self.record_established_peers(socket);
+    }
+
+    // The uptimes change continuously, so they are only set when scraped
+    pub fn record_uptimes(&self) {
+        let now = Utc::now();
+        for session in self.sessions.values() {
+            for (peer, established_at) in &session.established_peers {
+                let uptime = (now - *established_at).num_seconds().max(0);
+                record_uptime(&session.socket, peer, uptime);
+            }
+        }
+    }
+
+    // Emit a peer event, if enabled
+    pub fn send_event(&self, event: PeerEvent) {
+        if let Some(events_tx) = &self.events_tx {
+            if events_tx.send(event).is_err() {
+                log::error!("bmp - failed to send peer event: producer disconnected");
+            }
+        }
+    }
+
+    // A router may have several BMP sessions
+    fn record_established_peers(&self, socket: &SocketAddr) {
+        let n_peers: usize = self
+            .sessions
+            .values()
+            .filter(|session| session.socket.ip() == socket.ip())
+            .map(|session| session.established_peers.len())
+            .sum();
+        gauge!(
+            "risotto_router_established_peers",
+            "router" => map_to_ipv6(socket.ip()).to_string(),
+        )
+        .set(n_peers as f64);
+    }
+
+    // Account for a message received on a BMP session
+    pub fn received(&mut self, socket: &SocketAddr, bmp_version: u8) {
+        if let Some(session) = self.sessions.get_mut(socket) {
+            session.bmp_version = Some(bmp_version);
+            session.messages += 1;
+        }
+    }
+
+    pub fn set_sys_name(&mut self, socket: &SocketAddr, sys_name: String) {
+        if let Some(session) = self.sessions.get_mut(socket) {
+            session.sys_name = Some(sys_name);
+        }
+    }
+
+    // Number of distinct routers connected, a router may have several BMP sessions
+    pub fn n_routers(&self) -> usize {
+        self.sessions
+            .values()
+            .map(|session| session.socket.ip())
+            .collect::<HashSet<IpAddr>>()
+            .len()
+    }
+
+    pub fn get_all(&self) -> Vec<Session> {
+        self.sessions.values().cloned().collect()
+    }
+}
+
+fn record_uptime(socket: &SocketAddr, peer: &PeerKey, uptime: i64) {
+    gauge!(
+        "risotto_peer_uptime_seconds",
+        "router" => map_to_ipv6(socket.ip()).to_string(),
+        "peer" => map_to_ipv6(peer.peer_addr).to_string(),
+        "peer_rd" => peer.rd(),
+    )
+    .set(uptime as f64);
+}
+
+fn record_established(socket: &SocketAddr, peer: &PeerKey, established: bool) {
+    gauge!(
+        "risotto_peer_established",
+        "router" => map_to_ipv6(socket.ip()).to_string(),
+        "peer" => map_to_ipv6(peer.peer_addr).to_string(),
+        "peer_rd" => peer.rd(),
+    )
+    .set(if established { 1.0 } else { 0.0 });
+}
@@ -1,77 +1,693 @@
 use config::Config;
 use core::net::IpAddr;
+use ipnet::IpNet;
+use serde::{Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 
-#[derive(Clone)]
+use crate::update::UPDATE_FIELDS;
+
+// Effective configuration, with the defaults applied, reported by the API
+#[derive(Clone, Serialize)]
+pub struct AppConfig {
+    pub api: APIConfig,
+    pub bmp: BMPConfig,
+    pub kafka: KafkaConfig,
+    pub nats: NatsConfig,
+    pub decode: DecodeConfig,
+    pub output: OutputConfig,
+    pub parquet: ParquetConfig,
+    pub sinks: SinksConfig,
+    pub rpki: RpkiConfig,
+    pub state: StateConfig,
+}
+
+pub fn get_app_config(settings: &Config) -> Result<AppConfig, Box<dyn Error>> {
+    Ok(AppConfig {
+        api: get_api_config(settings)?,
+        bmp: get_bmp_config(settings)?,
+        kafka: get_kafka_config(settings)?,
+        nats: get_nats_config(settings)?,
+        decode: get_decode_config(settings)?,
+        output: get_output_config(settings)?,
+        parquet: get_parquet_config(settings)?,
+        sinks: get_sinks_config(settings)?,
+        rpki: get_rpki_config(settings)?,
+        state: get_state_config(settings)?,
+    })
+}
+
+// Secrets are only reported as set or not
+fn redacted<T, S: Serializer>(secret: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_str("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Clone, Serialize)]
 pub struct APIConfig {
-    pub host: String,
+    pub hosts: Vec<String>,
+    pub admin_enable: bool,
 }
 
 pub fn get_api_config(settings: &Config) -> Result<APIConfig, Box<dyn Error>> {
     let api_addr = settings.get_string("api.address")?;
     let api_port = settings.get_int("api.port")?;
-    let host = host(api_addr, api_port, false);
-    Ok(APIConfig { host })
+    let hosts = hosts(api_addr, api_port, false);
+    let admin_enable = settings.get_bool("api.admin_enable").unwrap_or(false);
+    Ok(APIConfig {
+        hosts,
+        admin_enable,
+    })
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct BMPConfig {
-    pub host: String,
+    pub hosts: Vec<String>,
+    pub backlog: i32,
+    pub tcp_nodelay: bool,
+    pub keepalive_idle: u64,
+    pub keepalive_interval: u64,
+    pub keepalive_count: u32,
+    pub backoff_failures: usize,
+    pub backoff_window: u64,
+    pub backoff_duration: u64,
+    pub allowed_sources: Vec<IpNet>,
 }
 
 pub fn get_bmp_config(settings: &Config) -> Result<BMPConfig, Box<dyn Error>> {
     let bmp_addr = settings.get_string("bmp.address")?;
     let bmp_port = settings.get_int("bmp.port")?;
-    let host = host(bmp_addr, bmp_port, true);
-    Ok(BMPConfig { host })
+    let hosts = hosts(bmp_addr, bmp_port, true);
+    // Pending connections, for the routers reconnecting at once after a collector-wide flap
+    let backlog = settings.get_int("bmp.backlog").unwrap_or(1024) as i32;
+    let tcp_nodelay = settings.get_bool("bmp.tcp_nodelay").unwrap_or(true);
+    let keepalive_idle = settings.get_int("bmp.keepalive.idle").unwrap_or(60) as u64;
+    let keepalive_interval = settings.get_int("bmp.keepalive.interval").unwrap_or(10) as u64;
+    let keepalive_count = settings.get_int("bmp.keepalive.count").unwrap_or(6) as u32;
+    let backoff_failures = settings.get_int("bmp.backoff.failures").unwrap_or(3) as usize;
+    let backoff_window = settings.get_int("bmp.backoff.window").unwrap_or(10) as u64;
+    let backoff_duration = settings.get_int("bmp.backoff.duration").unwrap_or(5) as u64;
+    // All the sources are allowed if no allowlist is given
+    let allowed_sources = settings
+        .get_array("bmp.allowed_sources")
+        .unwrap_or_default()
+        .into_iter()
+        .map(|source| Ok(source.into_string()?.parse::<IpNet>()?))
+        .collect::<Result<Vec<IpNet>, Box<dyn Error>>>()?;
+    Ok(BMPConfig {
+        hosts,
+        backlog,
+        tcp_nodelay,
+        keepalive_idle,
+        keepalive_interval,
+        keepalive_count,
+        backoff_failures,
+        backoff_window,
+        backoff_duration,
+        allowed_sources,
+    })
 }
 
-#[derive(Clone)]
+// Routing of the Adj-RIB-Out updates by the Kafka producer
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdjRibOut {
+    Keep,
+    Drop,
+    Topic,
+}
+
+#[derive(Clone, Serialize)]
 pub struct KafkaConfig {
+    pub enable: bool,
     pub host: String,
     pub topic: String,
     pub batch_max_size: u64,
     pub batch_interval: u64,
+    pub batch_min_flush_ms: u64,
+    pub message_max_bytes: usize,
+    pub events_topic: Option<String>,
+    pub heartbeat_interval: u64,
+    pub partition_by_peer: bool,
+    pub adj_rib_out: AdjRibOut,
+    pub adj_rib_out_topic: Option<String>,
+    pub secondary_host: Option<String>,
+    pub failover_after: u64,
+    pub failback_interval: u64,
 }
 
 pub fn get_kafka_config(settings: &Config) -> Result<KafkaConfig, Box<dyn Error>> {
     // TODO: better error handling
     // Right now the thread will panic if the settings are not found,
     // but not the entire program
+    let enable = settings.get_bool("kafka.enable").unwrap_or(true);
     let kafka_addr = settings.get_string("kafka.address")?;
     let kafka_port = settings.get_int("kafka.port")?;
+    // The secondary cluster to fail over to, if any, on the same port as the primary by default
+    let secondary_host = match settings.get_string("kafka.secondary.address") {
+        Ok(secondary_addr) => {
+            let secondary_port = settings
+                .get_int("kafka.secondary.port")
+                .unwrap_or(kafka_port);
+            Some(host(secondary_addr, secondary_port, true))
+        }
+        Err(_) => None,
+    };
     let host = host(kafka_addr, kafka_port, true);
 
+    // Consecutive failed flushes before failing over
+    let failover_after = settings.get_int("kafka.failover_after").unwrap_or(3).max(1) as u64;
+    let failback_interval = settings.get_int("kafka.failback_interval").unwrap_or(60) as u64;
+
     let topic = settings.get_string("kafka.topic")?;
     let batch_max_size = settings.get_int("kafka.batch_max_size").unwrap_or(100) as u64;
     let batch_interval = settings.get_int("kafka.batch_interval").unwrap_or(1) as u64;
+    let batch_min_flush_ms = settings.get_int("kafka.batch_min_flush_ms").unwrap_or(0) as u64;
+    // Default `message.max.bytes` of the Kafka brokers
+    let message_max_bytes = settings
+        .get_int("kafka.message_max_bytes")
+        .unwrap_or(1048588) as usize;
+    // Peer events are only produced if given a topic
+    let events_topic = settings.get_string("kafka.events_topic").ok();
+    // Heartbeats are produced along the peer events, disabled by default
+    let heartbeat_interval = settings.get_int("kafka.heartbeat_interval").unwrap_or(0) as u64;
+    if heartbeat_interval > 0 && events_topic.is_none() {
+        return Err("the heartbeats require kafka.events_topic".into());
+    }
+    let partition_by_peer = settings
+        .get_bool("kafka.partition_by_peer")
+        .unwrap_or(false);
+    // The Adj-RIB-Out updates are kept in the main topic by default
+    let adj_rib_out = settings
+        .get_string("kafka.adj_rib_out")
+        .unwrap_or("keep".to_string());
+    let adj_rib_out = match adj_rib_out.as_str() {
+        "keep" => AdjRibOut::Keep,
+        "drop" => AdjRibOut::Drop,
+        "topic" => AdjRibOut::Topic,
+        _ => return Err(format!("unknown Adj-RIB-Out routing: {}", adj_rib_out).into()),
+    };
+    let adj_rib_out_topic = settings.get_string("kafka.adj_rib_out_topic").ok();
+    if adj_rib_out == AdjRibOut::Topic && adj_rib_out_topic.is_none() {
+        return Err("the Adj-RIB-Out routing to a topic requires kafka.adj_rib_out_topic".into());
+    }
 
     Ok(KafkaConfig {
+        enable,
         host,
         topic,
         batch_max_size,
         batch_interval,
+        batch_min_flush_ms,
+        message_max_bytes,
+        events_topic,
+        heartbeat_interval,
+        partition_by_peer,
+        adj_rib_out,
+        adj_rib_out_topic,
+        secondary_host,
+        failover_after,
+        failback_interval,
+    })
+}
+
+#[derive(Clone, Serialize)]
+pub struct NatsConfig {
+    pub enable: bool,
+    pub host: String,
+    pub subject: String,
+}
+
+pub fn get_nats_config(settings: &Config) -> Result<NatsConfig, Box<dyn Error>> {
+    let enable = settings.get_bool("nats.enable").unwrap_or(false);
+    let nats_addr = settings
+        .get_string("nats.address")
+        .unwrap_or("127.0.0.1".to_string());
+    let nats_port = settings.get_int("nats.port").unwrap_or(4222);
+    let host = host(nats_addr, nats_port, true);
+    let subject = settings
+        .get_string("nats.subject")
+        .unwrap_or("risotto-updates".to_string());
+
+    Ok(NatsConfig {
+        enable,
+        host,
+        subject,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AsnFormat {
+    Asplain,
+    Asdot,
+}
+
+#[derive(Clone, Serialize)]
+pub struct LoopDetectionConfig {
+    pub max_prepends: usize,
+    pub asns: Vec<u32>,
+}
+
+pub fn get_loop_detection_config(settings: &Config) -> Result<LoopDetectionConfig, Box<dyn Error>> {
+    let max_prepends = settings
+        .get_int("decode.loop_detection.max_prepends")
+        .unwrap_or(10) as usize;
+    let asns = settings
+        .get_array("decode.loop_detection.asns")
+        .unwrap_or_default()
+        .into_iter()
+        .map(|asn| asn.into_int().map(|asn| asn as u32))
+        .collect::<Result<Vec<u32>, _>>()?;
+
+    Ok(LoopDetectionConfig { max_prepends, asns })
+}
+
+// Role of a peer relative to the monitored router
+// https://datatracker.ietf.org/doc/html/rfc9234#section-3.1
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PeerRole {
+    Provider,
+    Customer,
+    Peer,
+    Rs,
+    RsClient,
+}
+
+// Peer roles, configured as a list as the addresses cannot be used as keys
+// decode:
+//   peer_roles:
+//     - peer: 192.0.2.1
+//       role: customer
+pub fn get_peer_roles(settings: &Config) -> Result<HashMap<IpAddr, PeerRole>, Box<dyn Error>> {
+    let mut peer_roles = HashMap::new();
+    for entry in settings.get_array("decode.peer_roles").unwrap_or_default() {
+        let entry = entry.into_table()?;
+        let peer = match entry.get("peer") {
+            Some(peer) => peer.clone().into_string()?.parse::<IpAddr>()?,
+            None => return Err("missing peer in peer role".into()),
+        };
+        let role = match entry.get("role") {
+            Some(role) => role.clone().into_string()?,
+            None => return Err(format!("missing role for peer {}", peer).into()),
+        };
+        let role = match role.as_str() {
+            "provider" => PeerRole::Provider,
+            "customer" => PeerRole::Customer,
+            "peer" => PeerRole::Peer,
+            "rs" => PeerRole::Rs,
+            "rs-client" => PeerRole::RsClient,
+            _ => return Err(format!("unknown peer role: {}", role).into()),
+        };
+        peer_roles.insert(peer, role);
+    }
+    Ok(peer_roles)
+}
+
+// Handling of the Route Monitoring messages with no per-peer header
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingPeerHeader {
+    Drop,
+    LocalRib,
+}
+
+// Settings used to decode the updates
+#[derive(Clone, Serialize)]
+pub struct DecodeConfig {
+    pub loop_detection: LoopDetectionConfig,
+    pub peer_roles: HashMap<IpAddr, PeerRole>,
+    pub max_as_path_length: usize,
+    pub include_raw_pdu: bool,
+    pub drop_host_routes: bool,
+    pub drop_default_routes: bool,
+    pub drop_bogons: bool,
+    pub bogons: Vec<IpNet>,
+    pub sort_communities: bool,
+    pub sampling_rate: u64,
+    pub missing_peer_header: MissingPeerHeader,
+}
+
+// Martian and bogon prefixes, not expected in the global routing table
+// https://datatracker.ietf.org/doc/html/rfc6890
+const DEFAULT_BOGONS: [&str; 24] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/8",
+    "100::/64",
+    "2001:2::/48",
+    "2001:10::/28",
+    "2001:db8::/32",
+    "3ffe::/16",
+    "fc00::/7",
+    "fe80::/10",
+    "fec0::/10",
+    "ff00::/8",
+];
+
+pub fn get_decode_config(settings: &Config) -> Result<DecodeConfig, Box<dyn Error>> {
+    let loop_detection = get_loop_detection_config(settings)?;
+    let peer_roles = get_peer_roles(settings)?;
+    let max_as_path_length = settings.get_int("decode.max_as_path_length").unwrap_or(256) as usize;
+    // The raw BMP message is attached to the updates when decoded
+    let include_raw_pdu = settings.get_bool("decode.include_raw_pdu").unwrap_or(false);
+    let sort_communities = settings
+        .get_bool("decode.sort_communities")
+        .unwrap_or(false);
+    let drop_host_routes = settings
+        .get_bool("decode.prefixes.drop_host_routes")
+        .unwrap_or(false);
+    let drop_default_routes = settings
+        .get_bool("decode.prefixes.drop_default_routes")
+        .unwrap_or(false);
+    let drop_bogons = settings
+        .get_bool("decode.prefixes.drop_bogons")
+        .unwrap_or(false);
+    // All the updates are emitted by default
+    let sampling_rate = settings.get_int("decode.sampling_rate").unwrap_or(1).max(1) as u64;
+    // The Route Monitoring messages with no per-peer header are dropped by default
+    let missing_peer_header = settings
+        .get_string("decode.missing_peer_header")
+        .unwrap_or("drop".to_string());
+    let missing_peer_header = match missing_peer_header.as_str() {
+        "drop" => MissingPeerHeader::Drop,
+        "local_rib" => MissingPeerHeader::LocalRib,
+        _ => {
+            return Err(format!(
+                "unknown handling of the missing per-peer header: {}",
+                missing_peer_header
+            )
+            .into())
+        }
+    };
+    // The built-in bogons are used if no list is given
+    let bogons = match settings.get_array("decode.prefixes.bogons") {
+        Ok(bogons) => bogons
+            .into_iter()
+            .map(|bogon| Ok(bogon.into_string()?.parse::<IpNet>()?))
+            .collect::<Result<Vec<IpNet>, Box<dyn Error>>>()?,
+        Err(_) => DEFAULT_BOGONS
+            .iter()
+            .map(|bogon| bogon.parse().unwrap())
+            .collect(),
+    };
+
+    Ok(DecodeConfig {
+        loop_detection,
+        peer_roles,
+        max_as_path_length,
+        include_raw_pdu,
+        drop_host_routes,
+        drop_default_routes,
+        drop_bogons,
+        bogons,
+        sort_communities,
+        sampling_rate,
+        missing_peer_header,
+    })
+}
+
+#[derive(Clone, Serialize)]
+pub struct OutputConfig {
+    pub asn_format: AsnFormat,
+    pub fields: Option<HashSet<String>>,
+    #[serde(serialize_with = "redacted")]
+    pub anonymize_key: Option<Vec<u8>>,
+    pub check_serialization: bool,
+    pub ipv4_mapped: bool,
+}
+
+pub fn get_output_config(settings: &Config) -> Result<OutputConfig, Box<dyn Error>> {
+    let asn_format = settings
+        .get_string("output.asn_format")
+        .unwrap_or("asplain".to_string());
+    let asn_format = match asn_format.as_str() {
+        "asplain" => AsnFormat::Asplain,
+        "asdot" => AsnFormat::Asdot,
+        _ => return Err(format!("unknown ASN format: {}", asn_format).into()),
+    };
+
+    // All the fields are included by default
+    let fields = match settings.get_array("output.fields") {
+        Ok(fields) => {
+            let fields = fields
+                .into_iter()
+                .map(|field| field.into_string())
+                .collect::<Result<HashSet<String>, _>>()?;
+            for field in &fields {
+                if !UPDATE_FIELDS.contains(&field.as_str()) {
+                    return Err(format!("unknown output field: {}", field).into());
+                }
+            }
+            Some(fields)
+        }
+        Err(_) => None,
+    };
+
+    // The raw BMP messages would leak the anonymized addresses
+    let anonymize_key = settings
+        .get_string("output.anonymize_key")
+        .ok()
+        .map(String::into_bytes);
+    if anonymize_key.is_some() && settings.get_bool("decode.include_raw_pdu").unwrap_or(false) {
+        return Err("the raw BMP messages cannot be included with anonymization".into());
+    }
+
+    // Debugging aid, the serialized updates are parsed back against the schema
+    let check_serialization = settings
+        .get_bool("output.check_serialization")
+        .unwrap_or(false);
+
+    // IPv4 addresses are emitted as IPv4-mapped IPv6 addresses by default
+    let ipv4_mapped = settings.get_bool("output.ipv4_mapped").unwrap_or(true);
+
+    Ok(OutputConfig {
+        asn_format,
+        fields,
+        anonymize_key,
+        check_serialization,
+        ipv4_mapped,
+    })
+}
+
+#[derive(Clone, Serialize)]
+pub struct ParquetConfig {
+    pub enable: bool,
+    pub output_dir: String,
+    pub rotate_mb: u64,
+    pub rotate_interval: u64,
+    pub batch_interval: u64,
+}
+
+pub fn get_parquet_config(settings: &Config) -> Result<ParquetConfig, Box<dyn Error>> {
+    let enable = settings.get_bool("parquet.enable").unwrap_or(false);
+    let output_dir = settings
+        .get_string("parquet.output_dir")
+        .unwrap_or("parquet".to_string());
+    let rotate_mb = settings.get_int("parquet.rotate_mb").unwrap_or(128) as u64;
+    let rotate_interval = settings.get_int("parquet.rotate_interval").unwrap_or(3600) as u64;
+    let batch_interval = settings.get_int("parquet.batch_interval").unwrap_or(10) as u64;
+
+    Ok(ParquetConfig {
+        enable,
+        output_dir,
+        rotate_mb,
+        rotate_interval,
+        batch_interval,
+    })
+}
+
+#[derive(Clone, Serialize)]
+pub struct SinksConfig {
+    pub buffer_size: usize,
+    pub file_enable: bool,
+    pub file_path: String,
+    pub stdout_enable: bool,
+    pub counter_enable: bool,
+    pub stream_enable: bool,
+    pub origins_enable: bool,
+    pub origins_top: usize,
+    pub mrt_enable: bool,
+    pub mrt_output_dir: String,
+    pub mrt_rotate_mb: u64,
+    pub mrt_rotate_interval: u64,
+}
+
+pub fn get_sinks_config(settings: &Config) -> Result<SinksConfig, Box<dyn Error>> {
+    let buffer_size = settings.get_int("sinks.buffer_size").unwrap_or(10000) as usize;
+    let file_enable = settings.get_bool("sinks.file.enable").unwrap_or(false);
+    let file_path = settings
+        .get_string("sinks.file.path")
+        .unwrap_or("updates.ndjson".to_string());
+    let stdout_enable = settings.get_bool("sinks.stdout.enable").unwrap_or(false);
+    let counter_enable = settings.get_bool("sinks.counter.enable").unwrap_or(false);
+    let stream_enable = settings.get_bool("sinks.stream.enable").unwrap_or(false);
+    let origins_enable = settings.get_bool("sinks.origins.enable").unwrap_or(false);
+    let origins_top = settings.get_int("sinks.origins.top").unwrap_or(100) as usize;
+    let mrt_enable = settings.get_bool("sinks.mrt.enable").unwrap_or(false);
+    let mrt_output_dir = settings
+        .get_string("sinks.mrt.output_dir")
+        .unwrap_or("mrt".to_string());
+    let mrt_rotate_mb = settings.get_int("sinks.mrt.rotate_mb").unwrap_or(128) as u64;
+    // The MRT archives of the route collectors are usually rotated every 15 minutes
+    let mrt_rotate_interval = settings.get_int("sinks.mrt.rotate_interval").unwrap_or(900) as u64;
+
+    Ok(SinksConfig {
+        buffer_size,
+        file_enable,
+        file_path,
+        stdout_enable,
+        counter_enable,
+        stream_enable,
+        origins_enable,
+        origins_top,
+        mrt_enable,
+        mrt_output_dir,
+        mrt_rotate_mb,
+        mrt_rotate_interval,
+    })
+}
+
+#[derive(Clone, Serialize)]
+pub struct RpkiConfig {
+    pub enable: bool,
+    pub path: String,
+    pub reload_interval: u64,
+}
+
+pub fn get_rpki_config(settings: &Config) -> Result<RpkiConfig, Box<dyn Error>> {
+    let enable = settings.get_bool("rpki.enable").unwrap_or(false);
+    let path = settings
+        .get_string("rpki.path")
+        .unwrap_or("vrps.json".to_string());
+    let reload_interval = settings.get_int("rpki.reload_interval").unwrap_or(600) as u64;
+
+    Ok(RpkiConfig {
+        enable,
+        path,
+        reload_interval,
     })
 }
 
-#[derive(Clone)]
+// Peers across which the duplicate announcements and the withdraws are deduplicated
+#[derive(Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupScope {
+    #[default]
+    Peer,
+    Router,
+    Global,
+}
+
+#[derive(Clone, Serialize)]
 pub struct StateConfig {
     pub enable: bool,
+    pub graceful_restart: bool,
+    pub max_prefixes_per_peer: usize,
+    pub max_peers_per_router: usize,
     pub path: String,
     pub interval: u64,
+    pub fsync_interval: u64,
+    pub max_dump_failures: u64,
+    pub withdraws_batch_size: usize,
+    pub withdraws_batch_interval: u64,
+    pub max_peer_up_withdraws: usize,
+    pub dampening_max_flaps: usize,
+    pub dampening_window: u64,
+    pub dampening_cooldown: u64,
+    pub withdraw_grace_secs: u64,
+    pub audit_interval: u64,
+    pub retain_origin: bool,
+    pub dedup_scope: DedupScope,
 }
 
 pub fn get_state_config(settings: &Config) -> Result<StateConfig, Box<dyn Error>> {
     let enable = settings.get_bool("state.enable")?;
+    let graceful_restart = settings.get_bool("state.graceful_restart").unwrap_or(false);
+    let max_prefixes_per_peer =
+        settings.get_int("state.max_prefixes_per_peer").unwrap_or(0) as usize;
+    // The number of peers per router is not capped by default
+    let max_peers_per_router = settings.get_int("state.max_peers_per_router").unwrap_or(0) as usize;
     let path = settings.get_string("state.path")?;
     let interval = settings.get_int("state.save_interval")? as u64;
+    // The dumps are left to the OS to be written to disk by default
+    let fsync_interval = settings.get_int("state.fsync_interval").unwrap_or(0) as u64;
+    // Failing dumps are retried forever by default
+    let max_dump_failures = settings.get_int("state.max_dump_failures").unwrap_or(0) as u64;
+    let withdraws_batch_size = settings
+        .get_int("state.withdraws_batch_size")
+        .unwrap_or(10000) as usize;
+    let withdraws_batch_interval = settings
+        .get_int("state.withdraws_batch_interval")
+        .unwrap_or(10) as u64;
+    // Peers processed concurrently once their startup withdraws are due
+    let max_peer_up_withdraws = settings
+        .get_int("state.max_peer_up_withdraws")
+        .unwrap_or(16)
+        .max(1) as usize;
+    let dampening_max_flaps = settings.get_int("state.dampening.max_flaps").unwrap_or(0) as usize;
+    let dampening_window = settings.get_int("state.dampening.window").unwrap_or(300) as u64;
+    let dampening_cooldown = settings.get_int("state.dampening.cooldown").unwrap_or(900) as u64;
+    let withdraw_grace_secs = settings.get_int("state.withdraw_grace_secs").unwrap_or(0) as u64;
+    // The consistency audit of the gauges is disabled by default
+    let audit_interval = settings.get_int("state.audit_interval").unwrap_or(0) as u64;
+    // The origin of the prefixes is not retained for the synthetic withdraws by default
+    let retain_origin = settings.get_bool("state.retain_origin").unwrap_or(false);
+    // Each peer is deduplicated on its own by default
+    let dedup_scope = settings
+        .get_string("state.dedup_scope")
+        .unwrap_or("peer".to_string());
+    let dedup_scope = match dedup_scope.as_str() {
+        "peer" => DedupScope::Peer,
+        "router" => DedupScope::Router,
+        "global" => DedupScope::Global,
+        _ => return Err(format!("unknown deduplication scope: {}", dedup_scope).into()),
+    };
     Ok(StateConfig {
         enable,
+        graceful_restart,
+        max_prefixes_per_peer,
+        max_peers_per_router,
         path,
         interval,
+        fsync_interval,
+        max_dump_failures,
+        withdraws_batch_size,
+        withdraws_batch_interval,
+        max_peer_up_withdraws,
+        dampening_max_flaps,
+        dampening_window,
+        dampening_cooldown,
+        withdraw_grace_secs,
+        audit_interval,
+        retain_origin,
+        dedup_scope,
     })
 }
 
+// Listeners can be bound to a comma-separated list of addresses
+pub fn hosts(addresses: String, port: i64, accept_fqdn: bool) -> Vec<String> {
+    addresses
+        .split(',')
+        .map(|address| host(address.trim().to_string(), port, accept_fqdn))
+        .collect()
+}
+
 pub fn host(address: String, port: i64, accept_fqdn: bool) -> String {
     let host = match address.parse::<IpAddr>() {
         Ok(ip) => {
@@ -0,0 +1,356 @@
+use bgpkit_parser::models::NetworkPrefix;
+use chrono::{DateTime, Utc};
+use core::net::IpAddr;
+use metrics::{counter, gauge};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Stdout, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio_graceful::ShutdownGuard;
+
+use crate::settings::OutputConfig;
+use crate::throttle;
+use crate::update::{construct_as_path, format_update_json, map_to_ipv6, Update};
+
+// A sink along with the receiving end of its channel
+pub type SinkChannel = (Box<dyn Sink>, Receiver<Vec<Update>>);
+
+// A consumer of the updates emitted by the collector
+pub trait Sink: Send {
+    fn name(&self) -> &'static str;
+    fn send(&mut self, update: &Update) -> Result<(), Box<dyn Error>>;
+
+    // Called once a batch of updates has been sent
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+// Appends the updates as JSON lines to a local file
+pub struct FileSink {
+    writer: BufWriter<File>,
+    output: OutputConfig,
+}
+
+impl FileSink {
+    pub fn new(path: &str, output: OutputConfig) -> Result<FileSink, Box<dyn Error>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSink {
+            writer: BufWriter::new(file),
+            output,
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn send(&mut self, update: &Update) -> Result<(), Box<dyn Error>> {
+        self.writer
+            .write_all(format_update_json(update, &self.output).as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Writes the updates to the standard output, to be piped to another process
+// Each JSON update is prefixed by its length, as a big-endian u32, to be read as a stream
+// The logs go to the standard error, so they do not mix with the updates
+pub struct StdoutSink {
+    writer: BufWriter<Stdout>,
+    output: OutputConfig,
+}
+
+impl StdoutSink {
+    pub fn new(output: OutputConfig) -> StdoutSink {
+        StdoutSink {
+            writer: BufWriter::new(std::io::stdout()),
+            output,
+        }
+    }
+}
+
+impl Sink for StdoutSink {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    fn send(&mut self, update: &Update) -> Result<(), Box<dyn Error>> {
+        let message = format_update_json(update, &self.output);
+        let length = u32::try_from(message.len())?;
+        self.writer.write_all(&length.to_be_bytes())?;
+        self.writer.write_all(message.as_bytes())?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Only counts the updates, exposed as Prometheus metrics
+pub struct CounterSink;
+
+impl Sink for CounterSink {
+    fn name(&self) -> &'static str {
+        "counter"
+    }
+
+    fn send(&mut self, update: &Update) -> Result<(), Box<dyn Error>> {
+        counter!(
+            "risotto_updates_total",
+            "router" => map_to_ipv6(update.router_addr).to_string(),
+            "peer" => map_to_ipv6(update.peer_addr).to_string(),
+            "announced" => update.announced.to_string(),
+            "synthetic" => update.synthetic.to_string(),
+        )
+        .increment(1);
+        Ok(())
+    }
+}
+
+// Broadcasts the updates to the clients of the API live stream
+pub struct StreamSink {
+    tx: broadcast::Sender<Update>,
+}
+
+impl StreamSink {
+    pub fn new(tx: broadcast::Sender<Update>) -> StreamSink {
+        StreamSink { tx }
+    }
+}
+
+impl Sink for StreamSink {
+    fn name(&self) -> &'static str {
+        "stream"
+    }
+
+    fn send(&mut self, update: &Update) -> Result<(), Box<dyn Error>> {
+        // Having no client connected is not an error
+        let _ = self.tx.send(update.clone());
+        Ok(())
+    }
+}
+
+// A prefix as announced by a peer of a router, as identified in the state
+type PeerPrefix = (IpAddr, IpAddr, NetworkPrefix, bool, bool);
+
+// Counts the distinct prefixes per origin ASN, exposed as Prometheus metrics
+// Only the top origin ASNs are exposed to bound the number of series
+pub struct OriginSink {
+    top: usize,
+    origins: HashMap<PeerPrefix, u32>,
+    prefixes: HashMap<u32, HashMap<NetworkPrefix, usize>>,
+    exposed: HashSet<u32>,
+    recorded_at: Option<Instant>,
+}
+
+impl OriginSink {
+    // The top origin ASNs are computed at most once per interval
+    const RECORD_INTERVAL: Duration = Duration::from_secs(10);
+
+    pub fn new(top: usize) -> OriginSink {
+        OriginSink {
+            top,
+            origins: HashMap::new(),
+            prefixes: HashMap::new(),
+            exposed: HashSet::new(),
+            recorded_at: None,
+        }
+    }
+
+    fn add(&mut self, origin: u32, prefix: NetworkPrefix) {
+        *self
+            .prefixes
+            .entry(origin)
+            .or_default()
+            .entry(prefix)
+            .or_default() += 1;
+    }
+
+    fn remove(&mut self, origin: u32, prefix: &NetworkPrefix) {
+        let Some(prefixes) = self.prefixes.get_mut(&origin) else {
+            return;
+        };
+        if let Some(count) = prefixes.get_mut(prefix) {
+            *count -= 1;
+            if *count == 0 {
+                prefixes.remove(prefix);
+            }
+        }
+        if prefixes.is_empty() {
+            self.prefixes.remove(&origin);
+        }
+    }
+
+    fn record(&mut self) {
+        let mut origins: Vec<(u32, usize)> = self
+            .prefixes
+            .iter()
+            .map(|(origin, prefixes)| (*origin, prefixes.len()))
+            .collect();
+        origins.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        origins.truncate(self.top);
+
+        // The origin ASNs leaving the top are zeroed
+        let top: HashSet<u32> = origins.iter().map(|(origin, _)| *origin).collect();
+        for origin in self.exposed.difference(&top) {
+            gauge!("risotto_prefixes_by_origin", "asn" => origin.to_string()).set(0.0);
+        }
+        for (origin, n_prefixes) in origins {
+            gauge!("risotto_prefixes_by_origin", "asn" => origin.to_string())
+                .set(n_prefixes as f64);
+        }
+        self.exposed = top;
+    }
+}
+
+impl Sink for OriginSink {
+    fn name(&self) -> &'static str {
+        "origins"
+    }
+
+    fn send(&mut self, update: &Update) -> Result<(), Box<dyn Error>> {
+        let key = (
+            update.router_addr,
+            update.peer_addr,
+            update.prefix,
+            update.is_post_policy,
+            update.is_adj_rib_out,
+        );
+
+        // An announcement replaces the previous one, possibly from another origin
+        if let Some(origin) = self.origins.remove(&key) {
+            self.remove(origin, &update.prefix);
+        }
+        if !update.announced {
+            return Ok(());
+        }
+
+        // The origin ASN is the last ASN of the AS path
+        if let Some(origin) = construct_as_path(update.path.clone()).last() {
+            self.origins.insert(key, *origin);
+            self.add(*origin, update.prefix);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        if self
+            .recorded_at
+            .is_none_or(|recorded_at| recorded_at.elapsed() >= Self::RECORD_INTERVAL)
+        {
+            self.record();
+            self.recorded_at = Some(Instant::now());
+        }
+        Ok(())
+    }
+}
+
+pub async fn handle(sink: Box<dyn Sink>, rx: Receiver<Vec<Update>>, guard: ShutdownGuard) {
+    let name = sink.name();
+    let sink = Arc::new(Mutex::new(Some(sink)));
+
+    // The receiver is blocking, so the sink runs on a dedicated thread
+    let worker = tokio::task::spawn_blocking({
+        let sink = sink.clone();
+        move || {
+            while let Ok(updates) = rx.recv() {
+                let mut sink = sink.lock().unwrap();
+                let Some(sink) = sink.as_mut() else {
+                    return;
+                };
+                for update in &updates {
+                    if let Err(e) = sink.send(update) {
+                        log::error!("sink - {} - failed sending update: {}", name, e);
+                    }
+                }
+                if let Err(e) = sink.flush() {
+                    log::error!("sink - {} - failed flushing updates: {}", name, e);
+                }
+            }
+            // The channel is also closed on shutdown, once the sink is dropped
+            if sink.lock().unwrap().is_some() {
+                log::error!("sink - {} - BMP handler disconnected", name);
+            }
+        }
+    });
+
+    tokio::select! {
+        _ = worker => (),
+        _ = guard.cancelled() => {
+            // Drop the sink between two batches, so that it closes its current file
+            // The task then waits for the collector to end, rather than ending it
+            drop(sink.lock().unwrap().take());
+            drop(guard);
+            std::future::pending().await
+        }
+    }
+}
+
+// Sequence of the emitted updates, for the consumers to detect gaps or reordering
+// It starts over with the process, so it comes with its epoch, the start of the sequence
+static SEQUENCE: AtomicU64 = AtomicU64::new(1);
+static SEQUENCE_EPOCH: LazyLock<DateTime<Utc>> = LazyLock::new(Utc::now);
+
+// Send updates to the sinks
+// A failure is accounted and logged rather than fatal, the caller decides to go on or not
+pub fn send(tx: &Sender<Vec<Update>>, mut updates: Vec<Update>) -> bool {
+    let seq = SEQUENCE.fetch_add(updates.len() as u64, Ordering::Relaxed);
+    for (n, update) in updates.iter_mut().enumerate() {
+        update.seq = seq + n as u64;
+        update.seq_epoch = *SEQUENCE_EPOCH;
+    }
+
+    let Err(e) = tx.send(updates) else {
+        return true;
+    };
+
+    counter!("risotto_tx_send_errors_total").increment(1);
+    let updates = e.0;
+    match updates.first() {
+        Some(update) => log::error!(
+            "sink - {} - {} failed to send {} updates: sinks disconnected",
+            update.router_addr,
+            update.peer_addr,
+            updates.len()
+        ),
+        None => log::error!("sink - failed to send updates: sinks disconnected"),
+    }
+    false
+}
+
+// Forward every batch of updates to each of the sinks
+// A sink lagging behind has its updates dropped so the others are not stalled
+// The drops are counted, the warning is only logged once per period
+pub fn fanout(rx: Receiver<Vec<Update>>, txs: Vec<(&'static str, SyncSender<Vec<Update>>)>) {
+    while let Ok(updates) = rx.recv() {
+        for (name, tx) in &txs {
+            match tx.try_send(updates.clone()) {
+                Ok(_) => (),
+                Err(TrySendError::Full(updates)) => {
+                    throttle::log(
+                        log::Level::Warn,
+                        format!("sink - {} - buffer full, dropping updates", name),
+                    );
+                    counter!("risotto_sink_dropped_updates_total", "sink" => *name)
+                        .increment(updates.len() as u64);
+                }
+                Err(TrySendError::Disconnected(_)) => (),
+            }
+        }
+    }
+}
@@ -1,197 +1,1496 @@
-use bgpkit_parser::models::{NetworkPrefix, Origin, Peer as BGPkitPeer};
-use chrono::Utc;
+use bgpkit_parser::bmp::messages::BmpPeerType;
+use bgpkit_parser::models::{AsPath, NetworkPrefix, Origin, Peer as BGPkitPeer};
+use chrono::{DateTime, MappedLocalTime, TimeZone, Utc};
 use core::net::IpAddr;
+use hyperloglogplus::{HyperLogLog, HyperLogLogPF};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use metrics::{counter, describe_gauge, gauge, histogram};
+use prefix_trie::PrefixMap;
 use rand::Rng;
-use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::hash::{Hash, Hasher, RandomState};
+use std::str::FromStr;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
 
-use crate::settings::StateConfig;
-use crate::update::{format_update, Update};
+use crate::settings::{DedupScope, StateConfig};
+use crate::sink;
+use crate::throttle;
+use crate::update::{construct_as_path, format_peer_type, format_rd, map_to_ipv6, Update};
 
 pub type AsyncState = Arc<Mutex<State>>;
 
-type RouterPeerUpdate = (IpAddr, IpAddr, TimedPrefix);
+type RouterPeerUpdate = (IpAddr, IpAddr, BmpPeerType, TimedPrefix);
+
+// Router of the prefix, unless deduplicated across the routers
+type HoldersKey = (Option<IpAddr>, TimedPrefix);
+
+type AgeKey = (i64, IpNet, u32, bool, bool);
+
+type RecordedUpdates = HashMap<(IpAddr, PeerKey), (BmpPeerType, usize, usize)>;
 
 pub fn new_state(state_config: &StateConfig) -> AsyncState {
     Arc::new(Mutex::new(State::new(state_config)))
 }
 
-pub fn dump(state: AsyncState) {
-    let state = state.lock().unwrap();
-    let file = std::fs::File::create(state.config.path.clone()).unwrap();
+pub fn describe_metrics() {
+    describe_gauge!(
+        "risotto_bgp_peers",
+        "Number of BGP peers per (router, peer type)"
+    );
+    describe_gauge!(
+        "risotto_bgp_updates",
+        "Number of BGP updates per (router, peer)"
+    );
+    describe_gauge!(
+        "risotto_peer_churn_rate",
+        "Moving average of the updates emitted per minute per (router, peer)"
+    );
+    describe_gauge!(
+        "risotto_state_distinct_prefixes",
+        "Number of distinct prefixes tracked in the state per (router, peer)"
+    );
+    describe_gauge!(
+        "risotto_peer_distinct_as_paths",
+        "Estimated number of distinct AS paths announced per (router, peer)"
+    );
+    describe_gauge!(
+        "risotto_peer_dampened",
+        "Whether the updates of a (router, peer) are suppressed after flapping"
+    );
+    describe_gauge!(
+        "risotto_peer_up_withdraws_pending",
+        "Number of peers waiting for their startup withdraws"
+    );
+}
+
+// Version of the state dump format
+// To be bumped, along with a migration in `read_dump`, when the layout of the store changes
+const DUMP_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Dump<'a> {
+    version: u32,
+    store: &'a StoreSnapshot,
+}
+
+// Persisted fields of the store, copied under the lock to be dumped without holding it
+// Serialized as the store itself, whose indexes and estimators are rebuilt or start over on load
+#[derive(Serialize)]
+struct StoreSnapshot {
+    #[serde(serialize_with = "ordered_map")]
+    routers: HashMap<IpAddr, RouterSnapshot>,
+}
+
+#[derive(Serialize)]
+struct RouterSnapshot {
+    #[serde(serialize_with = "ordered_map")]
+    peers: HashMap<PeerKey, PeerSnapshot>,
+}
+
+#[derive(Serialize)]
+struct PeerSnapshot {
+    details: BGPkitPeer,
+    peer_type: BmpPeerType,
+    peer_distinguisher: u64,
+    restart_time: Option<u16>,
+    #[serde(serialize_with = "ordered_prefixes")]
+    updates: HashSet<TimedPrefix>,
+    #[serde(serialize_with = "ordered_prefixes")]
+    withdrawn: HashSet<TimedPrefix>,
+}
+
+#[derive(Deserialize)]
+struct DumpVersion {
+    // Legacy dumps are the bare store, without any version
+    version: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct VersionedDump {
+    store: MemoryStore,
+}
+
+fn read_dump(path: &str) -> Result<MemoryStore, Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    let version: DumpVersion = serde_json::from_slice(&data)?;
+
+    match version.version {
+        None => {
+            // The fields added since are filled with their defaults
+            log::info!("state - migrating legacy state dump {}", path);
+            Ok(serde_json::from_slice(&data)?)
+        }
+        Some(DUMP_VERSION) => {
+            let dump: VersionedDump = serde_json::from_slice(&data)?;
+            Ok(dump.store)
+        }
+        Some(version) => Err(format!("unsupported state dump version: {}", version).into()),
+    }
+}
+
+// The dump is written to a temporary file then renamed, so a crash never leaves a partial dump
+// With fsync, the dump is also forced to disk, to survive a power loss
+pub fn dump(state: AsyncState, fsync: bool) -> Result<(), Box<dyn Error>> {
+    // Take a snapshot of the store so the lock is not held while writing
+    let state_lock = state.lock().unwrap();
+    let store = state_lock.store.snapshot();
+    let path = state_lock.config.path.clone();
+    drop(state_lock);
+
+    let tmp_path = format!("{}.tmp", path);
+    let file = std::fs::File::create(&tmp_path)?;
     let mut writer = std::io::BufWriter::new(file);
-    serde_json::to_writer(&mut writer, &state.store).unwrap();
+    let dump = Dump {
+        version: DUMP_VERSION,
+        store: &store,
+    };
+    serde_json::to_writer(&mut writer, &dump)?;
+    let file = writer.into_inner()?;
+    if fsync {
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, &path)?;
+
+    // The rename itself is only durable once the directory is synced
+    if fsync {
+        let dir = match std::path::Path::new(&path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        std::fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
 }
 
 pub fn load(state: AsyncState) {
     let mut state = state.lock().unwrap();
 
-    let file = match std::fs::File::open(state.config.path.clone()) {
-        Ok(file) => file,
-        Err(_) => return,
+    let path = state.config.path.clone();
+    if !std::path::Path::new(&path).exists() {
+        return;
+    }
+
+    match read_dump(&path) {
+        Ok(mut store) => {
+            store.dedup_scope = state.config.dedup_scope;
+            store.index_prefixes();
+            store.record_metrics();
+            state.store = store;
+        }
+        Err(e) => {
+            // Keep the dump aside rather than overwriting it with the next one
+            let corrupted_path = format!("{}.corrupted", path);
+            log::error!(
+                "state - failed to load state dump {}: {}, moving it to {}",
+                path,
+                e,
+                corrupted_path
+            );
+            if let Err(e) = std::fs::rename(&path, &corrupted_path) {
+                log::error!("state - failed to move state dump {}: {}", path, e);
+            }
+        }
+    }
+}
+
+// Print a summary of a state dump, without running the collector
+pub fn inspect(path: &str, list_prefixes: bool) -> Result<(), Box<dyn Error>> {
+    let store = read_dump(path)?;
+
+    let format_timestamp = |timestamp: i64| match Utc.timestamp_millis_opt(timestamp) {
+        MappedLocalTime::Single(dt) => dt.to_rfc3339(),
+        _ => timestamp.to_string(),
     };
 
-    let reader = std::io::BufReader::new(file);
-    let store: MemoryStore = serde_json::from_reader(reader).unwrap();
-    state.store = store;
+    let mut oldest: Option<i64> = None;
+    let mut newest: Option<i64> = None;
+
+    println!("routers: {}", store.routers.len());
+
+    let mut routers: Vec<_> = store.routers.iter().collect();
+    routers.sort_by_key(|(router_addr, _)| **router_addr);
+    for (router_addr, router) in routers {
+        println!("router {} - {} peers", router_addr, router.peers.len());
+
+        let mut peers: Vec<_> = router.peers.iter().collect();
+        peers.sort_by_key(|(key, _)| **key);
+        for (key, peer) in peers {
+            let stale = peer.updates.iter().filter(|prefix| prefix.stale).count();
+            println!(
+                "  peer {} ({}) - {} prefixes, {} stale",
+                key,
+                format_peer_type(peer.peer_type),
+                peer.updates.len(),
+                stale
+            );
+
+            for prefix in &peer.updates {
+                oldest = Some(oldest.map_or(prefix.timestamp, |t| t.min(prefix.timestamp)));
+                newest = Some(newest.map_or(prefix.timestamp, |t| t.max(prefix.timestamp)));
+            }
+
+            if list_prefixes {
+                let mut prefixes: Vec<_> = peer.updates.iter().collect();
+                prefixes.sort_by_key(|prefix| prefix.timestamp);
+                for prefix in prefixes {
+                    println!(
+                        "    {} - post_policy={} adj_rib_out={} stale={} - {}",
+                        prefix.prefix,
+                        prefix.is_post_policy,
+                        prefix.is_adj_rib_out,
+                        prefix.stale,
+                        format_timestamp(prefix.timestamp)
+                    );
+                }
+            }
+        }
+    }
+
+    if let (Some(oldest), Some(newest)) = (oldest, newest) {
+        println!("oldest update: {}", format_timestamp(oldest));
+        println!("newest update: {}", format_timestamp(newest));
+    }
+    Ok(())
+}
+
+// Startup withdraws of a peer, due 5 minutes after its Peer Up
+pub struct PeerUpWithdraws {
+    router_addr: IpAddr,
+    peer: BGPkitPeer,
+    peer_distinguisher: u64,
+    startup: DateTime<Utc>,
+    tx: Sender<Vec<Update>>,
+}
+
+pub struct State {
+    store: MemoryStore,
+    config: StateConfig,
+    flaps: HashMap<(IpAddr, PeerKey), PeerFlaps>,
+    // Scheduled by due time, rather than a sleeping task per peer
+    peer_up_withdraws: BTreeMap<Instant, Vec<PeerUpWithdraws>>,
+    // Synthetic withdraws of the peers gone down, not sent yet
+    peer_down_withdraws: HashMap<(IpAddr, PeerKey), HashMap<TimedPrefix, Update>>,
+}
+
+impl State {
+    pub fn new(state_config: &StateConfig) -> State {
+        State {
+            store: MemoryStore::new(state_config.dedup_scope),
+            config: state_config.clone(),
+            flaps: HashMap::new(),
+            peer_up_withdraws: BTreeMap::new(),
+            peer_down_withdraws: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &StateConfig {
+        &self.config
+    }
+
+    // Account for a Peer Up or Peer Down of a peer
+    // Too many transitions within the window dampen the peer for the cooldown period
+    pub fn record_flap(
+        &mut self,
+        router_addr: &IpAddr,
+        peer: &BGPkitPeer,
+        peer_distinguisher: u64,
+    ) {
+        if self.config.dampening_max_flaps == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.dampening_window);
+        let key = PeerKey::new(peer, peer_distinguisher);
+        let flaps = self.flaps.entry((*router_addr, key)).or_default();
+
+        flaps.transitions.push_back(now);
+        while let Some(transition) = flaps.transitions.front() {
+            if now.duration_since(*transition) <= window {
+                break;
+            }
+            flaps.transitions.pop_front();
+        }
+
+        if flaps.dampened_until.is_none()
+            && flaps.transitions.len() >= self.config.dampening_max_flaps
+        {
+            log::warn!(
+                router:% = router_addr, peer:% = peer.peer_address;
+                "state - {} - {} dampened for {} seconds after {} transitions",
+                router_addr,
+                peer.peer_address,
+                self.config.dampening_cooldown,
+                flaps.transitions.len()
+            );
+            flaps.dampened_until = Some(now + Duration::from_secs(self.config.dampening_cooldown));
+            record_dampened(router_addr, &key, true);
+        }
+    }
+
+    // Hold back the announcement of a dampened peer until its cooldown ends
+    // Returns whether the update is held, the withdraws are still emitted as the consumers
+    // may have the prefix from before the cooldown
+    pub fn hold(
+        &mut self,
+        router_addr: &IpAddr,
+        peer: &BGPkitPeer,
+        update: &Update,
+        tx: &Sender<Vec<Update>>,
+    ) -> bool {
+        let key = (*router_addr, PeerKey::new(peer, update.peer_distinguisher));
+        let Some(flaps) = self.flaps.get_mut(&key) else {
+            return false;
+        };
+        if flaps.dampened_until.is_none() {
+            return false;
+        }
+
+        let prefix = TimedPrefix::of(update);
+        if !update.announced {
+            flaps.held.remove(&prefix);
+            return false;
+        }
+        flaps.held.insert(prefix, update.clone());
+        flaps.tx = Some(tx.clone());
+        true
+    }
+
+    // End the cooldown of the dampened peers due, and emit the announcements held meanwhile
+    // Only the prefixes still in the state are emitted, the others being withdrawn or evicted since
+    // Emitted under the state lock, so they are not reordered with the next updates of the peer
+    fn release_dampened(&mut self) {
+        let now = Instant::now();
+        for ((router_addr, key), flaps) in self.flaps.iter_mut() {
+            if flaps.dampened_until.is_none_or(|until| now < until) {
+                continue;
+            }
+
+            log::info!(
+                router:% = router_addr, peer:% = key.peer_addr;
+                "state - {} - {} no longer dampened",
+                router_addr,
+                key.peer_addr
+            );
+            flaps.dampened_until = None;
+            flaps.transitions.clear();
+            record_dampened(router_addr, key, false);
+
+            let held = std::mem::take(&mut flaps.held);
+            let Some(tx) = flaps.tx.take() else {
+                continue;
+            };
+            let mut updates: Vec<Update> = held
+                .into_iter()
+                .filter(|(prefix, _)| self.store.contains(router_addr, key, prefix))
+                .map(|(_, update)| update)
+                .collect();
+            if updates.is_empty() {
+                continue;
+            }
+
+            updates.sort_by_key(|update| update.timestamp);
+            log::info!(
+                router:% = router_addr, peer:% = key.peer_addr;
+                "state - {} - {} emitting {} announcements held during the cooldown",
+                router_addr,
+                key.peer_addr,
+                updates.len()
+            );
+            sink::send(&tx, updates);
+        }
+    }
+
+    // Check whether a peer fits within the cap of peers of its router
+    // The known peers always do, only the new ones are refused once the cap is reached
+    pub fn admits_peer(
+        &self,
+        router_addr: &IpAddr,
+        peer: &BGPkitPeer,
+        peer_distinguisher: u64,
+    ) -> bool {
+        let max_peers = self.config.max_peers_per_router;
+        if !self.config.enable || max_peers == 0 {
+            return true;
+        }
+        let Some(router) = self.store.routers.get(router_addr) else {
+            return true;
+        };
+        let key = PeerKey::new(peer, peer_distinguisher);
+        if router.peers.len() < max_peers || router.peers.contains_key(&key) {
+            return true;
+        }
+
+        throttle::log_kv(
+            log::Level::Warn,
+            &[
+                ("router", &router_addr.to_string()),
+                ("peer", &peer.peer_address.to_string()),
+            ],
+            format!(
+                "state - {} - {} refused, the router is at its cap of {} peers",
+                router_addr, peer.peer_address, max_peers
+            ),
+        );
+        counter!(
+            "risotto_peer_limit_exceeded",
+            "router" => map_to_ipv6(*router_addr).to_string(),
+        )
+        .increment(1);
+        false
+    }
+
+    // Schedule the withdraw of the prefixes not re-announced after a Peer Up
+    // The due time is jittered, so the peers of a reconnecting router are spread out
+    pub fn schedule_peer_up_withdraws(
+        &mut self,
+        router_addr: IpAddr,
+        peer: BGPkitPeer,
+        peer_distinguisher: u64,
+        tx: Sender<Vec<Update>>,
+    ) {
+        let random = {
+            let mut rng = rand::thread_rng();
+            rng.gen_range(-60.0..60.0) as i64
+        };
+        let sleep_time = 300 + random; // 5 minutes +/- 1 minute
+
+        let due = Instant::now() + Duration::from_secs(sleep_time as u64);
+        self.peer_up_withdraws
+            .entry(due)
+            .or_default()
+            .push(PeerUpWithdraws {
+                router_addr,
+                peer,
+                peer_distinguisher,
+                startup: Utc::now(),
+                tx,
+            });
+        self.record_peer_up_withdraws();
+    }
+
+    // Next peer whose startup withdraws are due, if any
+    fn next_peer_up_withdraws(&mut self) -> Option<PeerUpWithdraws> {
+        let mut entry = self.peer_up_withdraws.first_entry()?;
+        if *entry.key() > Instant::now() {
+            return None;
+        }
+        let withdraws = entry.get_mut().pop();
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+        self.record_peer_up_withdraws();
+        withdraws
+    }
+
+    fn record_peer_up_withdraws(&self) {
+        let pending: usize = self.peer_up_withdraws.values().map(Vec::len).sum();
+        gauge!("risotto_peer_up_withdraws_pending").set(pending as f64);
+    }
+
+    // Queue the synthetic withdraws of a peer gone down, to be sent in paced batches
+    // Returns whether there were none queued yet, so a task is needed to send them
+    pub fn queue_peer_down_withdraws(
+        &mut self,
+        router_addr: &IpAddr,
+        peer: &BGPkitPeer,
+        peer_distinguisher: u64,
+        synthetic_updates: Vec<Update>,
+    ) -> bool {
+        let key = (*router_addr, PeerKey::new(peer, peer_distinguisher));
+        let needs_task = !self.peer_down_withdraws.contains_key(&key);
+        self.peer_down_withdraws.entry(key).or_default().extend(
+            synthetic_updates
+                .into_iter()
+                .map(|update| (TimedPrefix::of(&update), update)),
+        );
+        needs_task
+    }
+
+    // Drop the queued withdraw of a prefix the peer announces again
+    // Otherwise the withdraw could be sent after the announcement
+    pub fn cancel_peer_down_withdraw(&mut self, router_addr: &IpAddr, update: &Update) {
+        let key = (
+            *router_addr,
+            PeerKey {
+                peer_addr: update.peer_addr,
+                peer_distinguisher: update.peer_distinguisher,
+            },
+        );
+        if let Some(withdraws) = self.peer_down_withdraws.get_mut(&key) {
+            withdraws.remove(&TimedPrefix::of(update));
+        }
+    }
+
+    // Next batch of the queued withdraws of a peer, all of them if `n` is 0
+    // The peer is forgotten once there are none left
+    fn next_peer_down_withdraws(&mut self, key: &(IpAddr, PeerKey), n: usize) -> Vec<Update> {
+        let Some(withdraws) = self.peer_down_withdraws.get_mut(key) else {
+            return Vec::new();
+        };
+        if withdraws.is_empty() {
+            self.peer_down_withdraws.remove(key);
+            return Vec::new();
+        }
+
+        let n = if n == 0 { withdraws.len() } else { n };
+        let prefixes: Vec<TimedPrefix> = withdraws.keys().take(n).cloned().collect();
+        prefixes
+            .iter()
+            .filter_map(|prefix| withdraws.remove(prefix))
+            .collect()
+    }
+
+    // Number of prefixes in the state, across all the routers and peers
+    pub fn n_prefixes(&self) -> usize {
+        self.store
+            .routers
+            .values()
+            .flat_map(|router| router.peers.values())
+            .map(|peer| peer.updates.len())
+            .sum()
+    }
+
+    // Get all the updates from the state
+    pub fn get_all(&self) -> Result<Vec<RouterPeerUpdate>, Box<dyn Error>> {
+        Ok(self.store.get_all())
+    }
+
+    // Get a specific router peer along with its updates
+    pub fn get_peer(
+        &self,
+        router_addr: &IpAddr,
+        peer: &BGPkitPeer,
+        peer_distinguisher: u64,
+    ) -> Result<Option<Peer>, Box<dyn Error>> {
+        Ok(self
+            .store
+            .get_peer(router_addr, &PeerKey::new(peer, peer_distinguisher)))
+    }
+
+    // Register a peer and its graceful restart time, if advertised
+    // Returns false if the peer is refused, its router being at its cap of peers
+    pub fn add_peer(
+        &mut self,
+        router_addr: &IpAddr,
+        peer: &BGPkitPeer,
+        peer_type: BmpPeerType,
+        peer_distinguisher: u64,
+        restart_time: Option<u16>,
+    ) -> Result<bool, Box<dyn Error>> {
+        if !self.config.enable {
+            return Ok(true);
+        }
+        if !self.admits_peer(router_addr, peer, peer_distinguisher) {
+            return Ok(false);
+        }
+
+        let router = self.store._get_router(router_addr);
+        router.add_peer(peer, peer_type, peer_distinguisher);
+        let key = PeerKey::new(peer, peer_distinguisher);
+        let peer = router.peers.get_mut(&key).unwrap();
+        let previous_type = std::mem::replace(&mut peer.peer_type, peer_type);
+        peer.restart_time = restart_time;
+        peer.restarting = false;
+
+        router.record_peers(router_addr);
+        if previous_type != peer_type {
+            // The updates gauge is labeled by peer type
+            self.store
+                .set_updates_gauges(router_addr, key, previous_type, 0, 0);
+        }
+        self.store.record_updates(router_addr, &key);
+        Ok(true)
+    }
+
+    // Mark all updates for a specific router and peer as stale
+    // Returns the restart time and the generation of the flap if the peer is graceful restart capable
+    pub fn mark_stale(
+        &mut self,
+        router_addr: &IpAddr,
+        peer: &BGPkitPeer,
+        peer_distinguisher: u64,
+    ) -> Result<Option<(u16, u64)>, Box<dyn Error>> {
+        if !self.config.enable || !self.config.graceful_restart {
+            return Ok(None);
+        }
+
+        let key = PeerKey::new(peer, peer_distinguisher);
+        Ok(self.store.mark_stale(router_addr, &key))
+    }
+
+    // Evict the oldest prefixes of a peer above the configured cap
+    // Returns the synthetic withdraws of the evicted prefixes
+    pub fn evict(
+        &mut self,
+        router_addr: &IpAddr,
+        router_port: u16,
+        peer: &BGPkitPeer,
+        peer_distinguisher: u64,
+    ) -> Result<Vec<Update>, Box<dyn Error>> {
+        if !self.config.enable || self.config.max_prefixes_per_peer == 0 {
+            return Ok(Vec::new());
+        }
+
+        let key = PeerKey::new(peer, peer_distinguisher);
+        let evicted = self
+            .store
+            .evict(router_addr, &key, self.config.max_prefixes_per_peer);
+        if evicted.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        counter!(
+            "risotto_state_evictions_total",
+            "router" => map_to_ipv6(*router_addr).to_string(),
+            "peer" => map_to_ipv6(peer.peer_address).to_string(),
+        )
+        .increment(evicted.len() as u64);
+
+        let state_peer = self.store.get_peer(router_addr, &key).unwrap();
+        let synthetic_updates = evicted
+            .into_iter()
+            .filter(|prefix| !self.is_shared(router_addr, peer, peer_distinguisher, prefix))
+            .map(|prefix| {
+                synthesize_withdraw_update(*router_addr, router_port, &state_peer, prefix)
+            })
+            .collect();
+        Ok(synthetic_updates)
+    }
+
+    // Remove all updates for a specific router and peer
+    pub fn remove_updates(
+        &mut self,
+        router_addr: &IpAddr,
+        peer: &BGPkitPeer,
+        peer_distinguisher: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.config.enable {
+            return Ok(());
+        }
+
+        let key = PeerKey::new(peer, peer_distinguisher);
+        self.store.remove_peer(router_addr, &key);
+        Ok(())
+    }
+
+    // Compare the number of updates of each peer to its gauges, and fix the gauges out of date
+    // Returns the number of discrepancies
+    pub fn audit(&mut self) -> usize {
+        let mut recorded = self.store.recorded.clone();
+        let mut drifts = Vec::new();
+        let mut fixes = Vec::new();
+        for (router_addr, router) in &self.store.routers {
+            for (key, peer) in &router.peers {
+                let n_recorded = recorded
+                    .remove(&(*router_addr, *key))
+                    .map_or((0, 0), |(_, n_updates, n_prefixes)| (n_updates, n_prefixes));
+                let n_actual = (peer.updates.len(), peer.index.len());
+                if n_recorded != n_actual {
+                    drifts.push((*router_addr, key.peer_addr, n_recorded, n_actual));
+                    fixes.push((*router_addr, *key, peer.peer_type, n_actual));
+                }
+            }
+        }
+
+        // Gauges left over by the peers no longer in the state
+        for ((router_addr, key), (peer_type, n_updates, n_prefixes)) in recorded {
+            if n_updates > 0 || n_prefixes > 0 {
+                drifts.push((router_addr, key.peer_addr, (n_updates, n_prefixes), (0, 0)));
+                fixes.push((router_addr, key, peer_type, (0, 0)));
+            }
+        }
+
+        for (router_addr, key, peer_type, (n_updates, n_prefixes)) in fixes {
+            self.store
+                .set_updates_gauges(&router_addr, key, peer_type, n_updates, n_prefixes);
+        }
+
+        for (router_addr, peer_addr, n_recorded, n_actual) in &drifts {
+            log::warn!(
+                router:% = router_addr, peer:% = peer_addr;
+                "state - audit - {} - {} - gauges at {} updates and {} prefixes instead of {} and {}",
+                router_addr,
+                peer_addr,
+                n_recorded.0,
+                n_recorded.1,
+                n_actual.0,
+                n_actual.1
+            );
+            counter!(
+                "risotto_state_gauge_drift_total",
+                "router" => map_to_ipv6(*router_addr).to_string(),
+                "peer" => map_to_ipv6(*peer_addr).to_string(),
+            )
+            .increment(1);
+        }
+        drifts.len()
+    }
+
+    // Purge the withdrawn prefixes past the grace period
+    pub fn purge_withdrawn(&mut self) {
+        let grace = Duration::from_secs(self.config.withdraw_grace_secs);
+        let before = Utc::now().timestamp_millis() - grace.as_millis() as i64;
+        for router in self.store.routers.values_mut() {
+            for peer in router.peers.values_mut() {
+                peer.purge_withdrawn(before);
+            }
+        }
+    }
+
+    // Get the prefixes of a router peer covering an address or a prefix, with the longest match
+    // There is one per RIB the prefix is monitored in
+    pub fn longest_match(
+        &self,
+        router_addr: &IpAddr,
+        peer_addr: &IpAddr,
+        prefix: &IpNet,
+    ) -> Vec<TimedPrefix> {
+        self.store
+            .routers
+            .iter()
+            .filter(|(addr, _)| map_to_ipv6(**addr) == map_to_ipv6(*router_addr))
+            .flat_map(|(_, router)| router.peers.values())
+            .filter(|peer| map_to_ipv6(peer.details.peer_address) == map_to_ipv6(*peer_addr))
+            .flat_map(|peer| peer.longest_match(prefix))
+            .collect()
+    }
+
+    // Remove the peers of a router, or only one of them, from the state
+    // Returns the synthetic withdraws of their prefixes
+    pub fn remove_peers(
+        &mut self,
+        router_addr: &IpAddr,
+        peer_addr: Option<&IpAddr>,
+    ) -> Vec<Update> {
+        let matching: Vec<(IpAddr, Peer)> = self
+            .store
+            .routers
+            .iter()
+            .filter(|(addr, _)| map_to_ipv6(**addr) == map_to_ipv6(*router_addr))
+            .flat_map(|(addr, router)| {
+                router
+                    .peers
+                    .values()
+                    .filter(|peer| {
+                        peer_addr.is_none_or(|peer_addr| {
+                            map_to_ipv6(*peer_addr) == map_to_ipv6(peer.details.peer_address)
+                        })
+                    })
+                    .map(|peer| (*addr, peer.clone()))
+            })
+            .collect();
+
+        let mut synthetic_updates = Vec::new();
+        // The peers are removed one at a time, so a prefix shared by several of them is only
+        // withdrawn along the last one
+        for (addr, peer) in matching {
+            let key = peer.key();
+            synthetic_updates.extend(
+                peer.updates
+                    .iter()
+                    .filter(|prefix| !self.store.is_shared(&addr, &key, prefix))
+                    .map(|prefix| synthesize_withdraw_update(addr, 0, &peer, prefix.clone())),
+            );
+            self.store.remove_peer(&addr, &key);
+        }
+        synthetic_updates
+    }
+
+    // Whether another peer within the deduplication scope holds the prefix
+    // Its withdraw is then not emitted, as the prefix is still reachable in the scope
+    pub fn is_shared(
+        &self,
+        router_addr: &IpAddr,
+        peer: &BGPkitPeer,
+        peer_distinguisher: u64,
+        prefix: &TimedPrefix,
+    ) -> bool {
+        let key = PeerKey::new(peer, peer_distinguisher);
+        self.store.is_shared(router_addr, &key, prefix)
+    }
+
+    // Update the state with a new update
+    pub fn update(
+        &mut self,
+        router_addr: &IpAddr,
+        peer: &BGPkitPeer,
+        update: &Update,
+    ) -> Result<bool, Box<dyn Error>> {
+        if !self.config.enable {
+            // If the state is disabled, all updates are emited
+            return Ok(true);
+        }
+        let emit = self.store.update(
+            router_addr,
+            peer,
+            update,
+            self.config.withdraw_grace_secs,
+            self.config.retain_origin,
+        );
+        Ok(emit)
+    }
+}
+
+// Peer Up and Peer Down history of a peer, for dampening
+#[derive(Default)]
+struct PeerFlaps {
+    transitions: VecDeque<Instant>,
+    dampened_until: Option<Instant>,
+    // Latest announcement of each prefix during the cooldown
+    held: HashMap<TimedPrefix, Update>,
+    tx: Option<Sender<Vec<Update>>>,
+}
+
+fn record_dampened(router_addr: &IpAddr, key: &PeerKey, dampened: bool) {
+    gauge!(
+        "risotto_peer_dampened",
+        "router" => map_to_ipv6(*router_addr).to_string(),
+        "peer" => map_to_ipv6(key.peer_addr).to_string(),
+        "peer_rd" => key.rd(),
+    )
+    .set(if dampened { 1.0 } else { 0.0 });
+}
+
+#[derive(Serialize, Deserialize, Eq, Clone)]
+pub struct TimedPrefix {
+    pub prefix: NetworkPrefix,
+    pub is_post_policy: bool,
+    pub is_adj_rib_out: bool,
+    pub timestamp: i64,
+    #[serde(default)]
+    pub stale: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<PrefixOrigin>,
+}
+
+// Origin of an announced prefix, retained to be carried along its synthetic withdraw
+// So the consumers can match it with its announcement, without retaining all its attributes
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct PrefixOrigin {
+    pub origin: Origin,
+    pub origin_asn: Option<u32>,
+}
+
+impl TimedPrefix {
+    // Prefix of an update, to look it up in the state
+    // Only the prefix and its RIB are compared
+    fn of(update: &Update) -> TimedPrefix {
+        TimedPrefix {
+            prefix: update.prefix,
+            is_post_policy: update.is_post_policy,
+            is_adj_rib_out: update.is_adj_rib_out,
+            timestamp: 0,
+            stale: false,
+            origin: None,
+        }
+    }
+
+    // Ordering of the prefixes by last announcement
+    fn age_key(&self) -> AgeKey {
+        (
+            self.timestamp,
+            self.prefix.prefix,
+            self.prefix.path_id,
+            self.is_post_policy,
+            self.is_adj_rib_out,
+        )
+    }
+
+    // Ordering of the prefixes, for a stable output
+    fn sort_key(&self) -> (IpNet, bool, bool) {
+        (self.prefix.prefix, self.is_post_policy, self.is_adj_rib_out)
+    }
+}
+
+// Peers are keyed by their address and their distinguisher (the Route Distinguisher of RD
+// instance peers), so that the peers of distinct VRFs sharing an address do not collide
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
+pub struct PeerKey {
+    pub peer_addr: IpAddr,
+    pub peer_distinguisher: u64,
+}
+
+impl PeerKey {
+    pub fn new(peer: &BGPkitPeer, peer_distinguisher: u64) -> PeerKey {
+        PeerKey {
+            peer_addr: peer.peer_address,
+            peer_distinguisher,
+        }
+    }
+
+    // Route Distinguisher of the peer for the metric labels, empty for a zero distinguisher
+    pub fn rd(&self) -> String {
+        format_rd(self.peer_distinguisher).unwrap_or_default()
+    }
+}
+
+// Rendered as the bare address without distinguisher, so legacy dumps keyed by address still load
+impl fmt::Display for PeerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.peer_distinguisher {
+            0 => write!(f, "{}", self.peer_addr),
+            rd => write!(f, "{}@{}", self.peer_addr, rd),
+        }
+    }
+}
+
+impl FromStr for PeerKey {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (peer_addr, peer_distinguisher) = match s.split_once('@') {
+            Some((peer_addr, rd)) => (peer_addr.parse()?, rd.parse()?),
+            None => (s.parse()?, 0),
+        };
+        Ok(PeerKey {
+            peer_addr,
+            peer_distinguisher,
+        })
+    }
+}
+
+impl Serialize for PeerKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        key.parse().map_err(de::Error::custom)
+    }
+}
+
+// Maps are serialized in key order, for stable dumps
+fn ordered_map<S: Serializer, K: Ord + Serialize, V: Serialize>(
+    map: &HashMap<K, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let ordered: BTreeMap<&K, &V> = map.iter().collect();
+    ordered.serialize(serializer)
+}
+
+fn ordered_prefixes<S: Serializer>(
+    prefixes: &HashSet<TimedPrefix>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut ordered: Vec<&TimedPrefix> = prefixes.iter().collect();
+    ordered.sort_by_key(|prefix| prefix.sort_key());
+    ordered.serialize(serializer)
+}
+
+impl PartialEq for TimedPrefix {
+    fn eq(&self, other: &Self) -> bool {
+        self.prefix == other.prefix
+            && self.is_post_policy == other.is_post_policy
+            && self.is_adj_rib_out == other.is_adj_rib_out
+    }
+}
+
+impl Hash for TimedPrefix {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.prefix.hash(state);
+        self.is_post_policy.hash(state);
+        self.is_adj_rib_out.hash(state);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct MemoryStore {
+    #[serde(serialize_with = "ordered_map")]
+    routers: HashMap<IpAddr, Router>,
+    #[serde(skip)]
+    dedup_scope: DedupScope,
+    // Number of peers holding each prefix within the deduplication scope
+    #[serde(skip)]
+    holders: HashMap<HoldersKey, usize>,
+    // Last numbers of updates and prefixes recorded in the gauges of each (router, peer), as the gauges cannot be read
+    // Checked against the state by the audit, to catch a code path leaving a gauge out of date
+    #[serde(skip)]
+    recorded: RecordedUpdates,
+}
+
+impl MemoryStore {
+    fn new(dedup_scope: DedupScope) -> MemoryStore {
+        MemoryStore {
+            routers: HashMap::new(),
+            dedup_scope,
+            holders: HashMap::new(),
+            recorded: HashMap::new(),
+        }
+    }
+
+    // Copy of the persisted fields only, to be dumped
+    fn snapshot(&self) -> StoreSnapshot {
+        let routers = self.routers.iter().map(|(router_addr, router)| {
+            let peers = router.peers.iter().map(|(key, peer)| {
+                let peer = PeerSnapshot {
+                    details: peer.details,
+                    peer_type: peer.peer_type,
+                    peer_distinguisher: peer.peer_distinguisher,
+                    restart_time: peer.restart_time,
+                    updates: peer.updates.clone(),
+                    withdrawn: peer.withdrawn.clone(),
+                };
+                (*key, peer)
+            });
+            let router = RouterSnapshot {
+                peers: peers.collect(),
+            };
+            (*router_addr, router)
+        });
+        StoreSnapshot {
+            routers: routers.collect(),
+        }
+    }
+
+    fn _get_router(&mut self, router_addr: &IpAddr) -> &mut Router {
+        let router = self.routers.entry(*router_addr).or_insert(Router::new());
+        router
+    }
+
+    fn get_all(&self) -> Vec<RouterPeerUpdate> {
+        let mut res: Vec<(IpAddr, PeerKey, BmpPeerType, TimedPrefix)> = Vec::new();
+        for (router_addr, router) in &self.routers {
+            for (key, peer) in &router.peers {
+                for update in &peer.updates {
+                    res.push((*router_addr, *key, peer.peer_type, update.clone()));
+                }
+            }
+        }
+
+        // Sorted, so the API output does not depend on the iteration order
+        res.sort_by_key(|(router_addr, key, _, update)| (*router_addr, *key, update.sort_key()));
+        res.into_iter()
+            .map(|(router_addr, key, peer_type, update)| {
+                (router_addr, key.peer_addr, peer_type, update)
+            })
+            .collect()
+    }
+
+    fn get_peer(&self, router_addr: &IpAddr, key: &PeerKey) -> Option<Peer> {
+        let router_binding = Router::new();
+        let router = self.routers.get(router_addr).unwrap_or(&router_binding);
+        router.peers.get(key).cloned()
+    }
+
+    fn evict(
+        &mut self,
+        router_addr: &IpAddr,
+        key: &PeerKey,
+        max_prefixes: usize,
+    ) -> Vec<TimedPrefix> {
+        let router = self._get_router(router_addr);
+        let Some(state_peer) = router.peers.get_mut(key) else {
+            return Vec::new();
+        };
+        if state_peer.updates.len() <= max_prefixes {
+            return Vec::new();
+        }
+
+        // Least recently announced first
+        let n = state_peer.updates.len() - max_prefixes;
+        let prefixes: Vec<TimedPrefix> = (0..n).map_while(|_| state_peer.pop_oldest()).collect();
+
+        self.record_updates(router_addr, key);
+        for prefix in &prefixes {
+            self.release(router_addr, prefix);
+        }
+        prefixes
+    }
+
+    fn mark_stale(&mut self, router_addr: &IpAddr, key: &PeerKey) -> Option<(u16, u64)> {
+        let router = self._get_router(router_addr);
+        let peer = router.peers.get_mut(key)?;
+        let restart_time = peer.restart_time?;
+
+        // A later flap restarts the restart time, superseding the handler of the previous one
+        peer.stale_generation += 1;
+        peer.restarting = true;
+        peer.updates = peer
+            .updates
+            .drain()
+            .map(|mut prefix| {
+                prefix.stale = true;
+                prefix
+            })
+            .collect();
+        Some((restart_time, peer.stale_generation))
+    }
+
+    fn contains(&self, router_addr: &IpAddr, key: &PeerKey, prefix: &TimedPrefix) -> bool {
+        self.routers
+            .get(router_addr)
+            .and_then(|router| router.peers.get(key))
+            .is_some_and(|peer| peer.updates.contains(prefix))
+    }
+
+    fn remove_peer(&mut self, router_addr: &IpAddr, key: &PeerKey) {
+        let router = self._get_router(router_addr);
+        let removed = router.peers.remove(key);
+        router.record_peers(router_addr);
+        if let Some(peer) = removed {
+            self.set_updates_gauges(router_addr, *key, peer.peer_type, 0, 0);
+            for prefix in &peer.updates {
+                self.release(router_addr, prefix);
+            }
+        }
+    }
+
+    // Key of a prefix within the deduplication scope, if deduplicated
+    fn holders_key(&self, router_addr: &IpAddr, prefix: &TimedPrefix) -> Option<HoldersKey> {
+        match self.dedup_scope {
+            DedupScope::Peer => None,
+            DedupScope::Router => Some((Some(*router_addr), prefix.clone())),
+            DedupScope::Global => Some((None, prefix.clone())),
+        }
+    }
+
+    // Account for a peer now holding the prefix
+    fn hold(&mut self, router_addr: &IpAddr, prefix: &TimedPrefix) {
+        if let Some(key) = self.holders_key(router_addr, prefix) {
+            *self.holders.entry(key).or_default() += 1;
+        }
+    }
+
+    // Account for a peer no longer holding the prefix
+    fn release(&mut self, router_addr: &IpAddr, prefix: &TimedPrefix) {
+        let Some(key) = self.holders_key(router_addr, prefix) else {
+            return;
+        };
+        if let Some(holders) = self.holders.get_mut(&key) {
+            *holders -= 1;
+            if *holders == 0 {
+                self.holders.remove(&key);
+            }
+        }
+    }
+
+    // Whether a peer other than the given one holds the prefix, within the deduplication scope
+    fn is_shared(&self, router_addr: &IpAddr, key: &PeerKey, prefix: &TimedPrefix) -> bool {
+        let Some(holders_key) = self.holders_key(router_addr, prefix) else {
+            return false;
+        };
+        let holders = self.holders.get(&holders_key).copied().unwrap_or(0);
+        let own = self.contains(router_addr, key, prefix) as usize;
+        holders > own
+    }
+
+    // Returns whether the update is to be emitted
+    // Beyond the peer scope, the prefixes held by the other peers of the scope are not emitted
+    fn update(
+        &mut self,
+        router_addr: &IpAddr,
+        peer: &BGPkitPeer,
+        update: &Update,
+        withdraw_grace_secs: u64,
+        retain_origin: bool,
+    ) -> bool {
+        let prefix = TimedPrefix::of(update);
+        let key = PeerKey::new(peer, update.peer_distinguisher);
+        let shared = self.is_shared(router_addr, &key, &prefix);
+
+        let router = self._get_router(router_addr);
+        let n_peers = router.peers.len();
+        let emit = router.update(peer, update, withdraw_grace_secs, retain_origin);
+
+        if router.peers.len() != n_peers {
+            router.record_peers(router_addr);
+        }
+        if emit {
+            router.record_churn(router_addr, &key);
+            if update.announced {
+                router.record_as_paths(router_addr, &key, update);
+            }
+            self.record_updates(router_addr, &key);
+
+            // The update either adds the prefix to the peer, or removes it
+            match update.announced {
+                true => self.hold(router_addr, &prefix),
+                false => self.release(router_addr, &prefix),
+            }
+        }
+        emit && !shared
+    }
+
+    // The prefix indexes are not dumped, so they are rebuilt once the state is loaded
+    fn index_prefixes(&mut self) {
+        self.holders = HashMap::new();
+        let held: Vec<(IpAddr, TimedPrefix)> = self
+            .routers
+            .iter()
+            .flat_map(|(router_addr, router)| {
+                router.peers.values().flat_map(move |peer| {
+                    peer.updates.iter().map(move |p| (*router_addr, p.clone()))
+                })
+            })
+            .collect();
+        for (router_addr, prefix) in &held {
+            self.hold(router_addr, prefix);
+        }
+
+        for router in self.routers.values_mut() {
+            for peer in router.peers.values_mut() {
+                peer.index = PrefixIndex::default();
+                peer.ages = peer.updates.iter().map(TimedPrefix::age_key).collect();
+                for prefix in &peer.updates {
+                    peer.index.insert(prefix);
+                }
+            }
+        }
+    }
+
+    // Set the gauges from the whole state, only needed once it is loaded
+    fn record_metrics(&mut self) {
+        let mut keys = Vec::new();
+        for (router_addr, router) in &self.routers {
+            router.record_peers(router_addr);
+            keys.extend(router.peers.keys().map(|key| (*router_addr, *key)));
+        }
+        for (router_addr, key) in &keys {
+            self.record_updates(router_addr, key);
+        }
+    }
+
+    fn record_updates(&mut self, router_addr: &IpAddr, key: &PeerKey) {
+        let Some(peer) = self
+            .routers
+            .get(router_addr)
+            .and_then(|router| router.peers.get(key))
+        else {
+            return;
+        };
+        let (peer_type, n_updates, n_prefixes) =
+            (peer.peer_type, peer.updates.len(), peer.index.len());
+        self.set_updates_gauges(router_addr, *key, peer_type, n_updates, n_prefixes);
+    }
+
+    fn set_updates_gauges(
+        &mut self,
+        router_addr: &IpAddr,
+        key: PeerKey,
+        peer_type: BmpPeerType,
+        n_updates: usize,
+        n_prefixes: usize,
+    ) {
+        set_updates_gauges(router_addr, key, peer_type, n_updates, n_prefixes);
+        match (n_updates, n_prefixes) {
+            (0, 0) => self.recorded.remove(&(*router_addr, key)),
+            _ => self
+                .recorded
+                .insert((*router_addr, key), (peer_type, n_updates, n_prefixes)),
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Peer {
+    details: BGPkitPeer,
+    #[serde(default = "default_peer_type")]
+    peer_type: BmpPeerType,
+    #[serde(default)]
+    peer_distinguisher: u64,
+    #[serde(default)]
+    restart_time: Option<u16>,
+    #[serde(serialize_with = "ordered_prefixes")]
+    pub updates: HashSet<TimedPrefix>,
+    // Withdrawn prefixes kept for the grace period, to correlate quick re-announcements
+    #[serde(default, serialize_with = "ordered_prefixes")]
+    withdrawn: HashSet<TimedPrefix>,
+    #[serde(skip)]
+    index: PrefixIndex,
+    // Prefixes by last announcement, to evict the least recently announced ones
+    #[serde(skip)]
+    ages: BTreeSet<AgeKey>,
+    #[serde(skip)]
+    churn: Churn,
+    #[serde(skip)]
+    as_paths: AsPathSketch,
+    // Flaps of a graceful restart capable peer, and whether it is down within its restart time
+    #[serde(skip)]
+    stale_generation: u64,
+    #[serde(skip)]
+    restarting: bool,
 }
 
-pub struct State {
-    store: MemoryStore,
-    config: StateConfig,
+fn default_peer_type() -> BmpPeerType {
+    BmpPeerType::Global
 }
 
-impl State {
-    pub fn new(state_config: &StateConfig) -> State {
-        State {
-            store: MemoryStore::new(),
-            config: state_config.clone(),
+impl Peer {
+    fn new(peer: &BGPkitPeer, peer_type: BmpPeerType, peer_distinguisher: u64) -> Peer {
+        Peer {
+            details: *peer,
+            peer_type,
+            peer_distinguisher,
+            restart_time: None,
+            updates: HashSet::new(),
+            withdrawn: HashSet::new(),
+            index: PrefixIndex::default(),
+            ages: BTreeSet::new(),
+            churn: Churn::default(),
+            as_paths: AsPathSketch::default(),
+            stale_generation: 0,
+            restarting: false,
         }
     }
 
-    // Get all the updates from the state
-    pub fn get_all(&self) -> Result<Vec<RouterPeerUpdate>, Box<dyn Error>> {
-        Ok(self.store.get_all())
-    }
-
-    // Get the updates for a specific router and peer
-    pub fn get_updates_by_peer(
-        &self,
-        router_addr: &IpAddr,
-        peer: &BGPkitPeer,
-    ) -> Result<Vec<TimedPrefix>, Box<dyn Error>> {
-        Ok(self.store.get_updates_by_peer(router_addr, peer))
+    fn key(&self) -> PeerKey {
+        PeerKey::new(&self.details, self.peer_distinguisher)
     }
 
-    // Remove all updates for a specific router and peer
-    pub fn remove_updates(
-        &mut self,
-        router_addr: &IpAddr,
-        peer: &BGPkitPeer,
-    ) -> Result<(), Box<dyn Error>> {
-        if !self.config.enable {
-            return Ok(());
+    // Add or refresh a prefix, keeping the index consistent
+    fn insert(&mut self, prefix: TimedPrefix) {
+        self.index.insert(&prefix);
+        // The previous key is removed first, as it is the same on a refresh within a millisecond
+        if let Some(previous) = self.updates.get(&prefix) {
+            self.ages.remove(&previous.age_key());
         }
-
-        self.store.remove_peer(router_addr, peer);
-        Ok(())
+        self.ages.insert(prefix.age_key());
+        self.updates.replace(prefix);
     }
 
-    // Update the state with a new update
-    pub fn update(
-        &mut self,
-        router_addr: &IpAddr,
-        peer: &BGPkitPeer,
-        update: &Update,
-    ) -> Result<bool, Box<dyn Error>> {
-        if !self.config.enable {
-            // If the state is disabled, all updates are emited
-            return Ok(true);
+    fn remove(&mut self, prefix: &TimedPrefix) {
+        self.index.remove(prefix);
+        if let Some(previous) = self.updates.take(prefix) {
+            self.ages.remove(&previous.age_key());
         }
-        let emit = self.store.update(router_addr, peer, update);
-        Ok(emit)
     }
-}
 
-#[derive(Serialize, Deserialize, Eq, Clone)]
-pub struct TimedPrefix {
-    pub prefix: NetworkPrefix,
-    pub is_post_policy: bool,
-    pub is_adj_rib_out: bool,
-    pub timestamp: i64,
-}
+    // Remove the least recently announced prefix
+    fn pop_oldest(&mut self) -> Option<TimedPrefix> {
+        let (_, prefix, path_id, is_post_policy, is_adj_rib_out) = self.ages.pop_first()?;
+        let prefix = TimedPrefix {
+            prefix: NetworkPrefix { prefix, path_id },
+            is_post_policy,
+            is_adj_rib_out,
+            timestamp: 0,
+            stale: false,
+            origin: None,
+        };
+        self.index.remove(&prefix);
+        self.updates.take(&prefix)
+    }
 
-impl PartialEq for TimedPrefix {
-    fn eq(&self, other: &Self) -> bool {
-        self.prefix == other.prefix
-            && self.is_post_policy == other.is_post_policy
-            && self.is_adj_rib_out == other.is_adj_rib_out
+    // Purge the withdrawn prefixes past the grace period
+    fn purge_withdrawn(&mut self, before: i64) {
+        self.withdrawn.retain(|prefix| prefix.timestamp >= before);
     }
-}
 
-impl Hash for TimedPrefix {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.prefix.hash(state);
-        self.is_post_policy.hash(state);
-        self.is_adj_rib_out.hash(state);
+    fn longest_match(&self, prefix: &IpNet) -> Vec<TimedPrefix> {
+        self.index
+            .longest_match(prefix)
+            .iter()
+            .filter_map(|key| self.updates.get(key).cloned())
+            .collect()
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct MemoryStore {
-    routers: HashMap<IpAddr, Router>,
+// Longest prefix match index of the prefixes of a peer, along its updates
+// The prefixes are only keys, the updates hold their timestamp and staleness
+#[derive(Clone, Default)]
+struct PrefixIndex {
+    ipv4: PrefixMap<Ipv4Net, Vec<TimedPrefix>>,
+    ipv6: PrefixMap<Ipv6Net, Vec<TimedPrefix>>,
 }
 
-impl MemoryStore {
-    fn new() -> MemoryStore {
-        MemoryStore {
-            routers: HashMap::new(),
+impl PrefixIndex {
+    fn entry(&mut self, prefix: &IpNet) -> &mut Vec<TimedPrefix> {
+        match prefix.trunc() {
+            IpNet::V4(prefix) => self.ipv4.entry(prefix).or_default(),
+            IpNet::V6(prefix) => self.ipv6.entry(prefix).or_default(),
         }
     }
 
-    fn _get_router(&mut self, router_addr: &IpAddr) -> &mut Router {
-        let router = self.routers.entry(*router_addr).or_insert(Router::new());
-        router
+    fn insert(&mut self, prefix: &TimedPrefix) {
+        let keys = self.entry(&prefix.prefix.prefix);
+        if !keys.contains(prefix) {
+            keys.push(prefix.clone());
+        }
     }
 
-    fn get_all(&self) -> Vec<RouterPeerUpdate> {
-        let mut res = Vec::new();
-        for (router_addr, router) in &self.routers {
-            for (peer_addr, peer) in &router.peers {
-                for update in &peer.updates {
-                    res.push((*router_addr, *peer_addr, update.clone()));
-                }
-            }
+    fn remove(&mut self, prefix: &TimedPrefix) {
+        let keys = self.entry(&prefix.prefix.prefix);
+        keys.retain(|key| key != prefix);
+        if keys.is_empty() {
+            match prefix.prefix.prefix.trunc() {
+                IpNet::V4(prefix) => self.ipv4.remove(&prefix),
+                IpNet::V6(prefix) => self.ipv6.remove(&prefix),
+            };
         }
-        res
     }
 
-    fn get_peer(&self, router_addr: &IpAddr, peer_addr: &IpAddr) -> Option<Peer> {
-        let router_binding = Router::new();
-        let router = self.routers.get(router_addr).unwrap_or(&router_binding);
-        router.peers.get(peer_addr).cloned()
+    // Number of distinct prefixes, whatever their path IDs and RIBs
+    fn len(&self) -> usize {
+        self.ipv4.len() + self.ipv6.len()
     }
 
-    fn get_updates_by_peer(&self, router_addr: &IpAddr, peer: &BGPkitPeer) -> Vec<TimedPrefix> {
-        let router_binding = Router::new();
-        let router = self.routers.get(router_addr).unwrap_or(&router_binding);
-        let peer_binding = Peer {
-            details: *peer,
-            updates: HashSet::new(),
+    fn longest_match(&self, prefix: &IpNet) -> &[TimedPrefix] {
+        let keys = match prefix.trunc() {
+            IpNet::V4(prefix) => self.ipv4.get_lpm(&prefix).map(|(_, keys)| keys),
+            IpNet::V6(prefix) => self.ipv6.get_lpm(&prefix).map(|(_, keys)| keys),
         };
-        let updates = router
-            .peers
-            .get(&peer.peer_address)
-            .unwrap_or(&peer_binding);
-
-        updates.updates.iter().cloned().collect()
+        keys.map(Vec::as_slice).unwrap_or_default()
     }
+}
 
-    fn remove_peer(&mut self, router_addr: &IpAddr, peer: &BGPkitPeer) {
-        let router = self._get_router(router_addr);
-        router.remove_peer(peer);
+// Exponentially weighted moving average of the rate of emitted updates
+#[derive(Clone, Default)]
+struct Churn {
+    rate: f64,
+    last: Option<Instant>,
+}
+
+impl Churn {
+    // Time constant of the average, in seconds
+    const TAU: f64 = 60.0;
+
+    // Account for an emitted update and return the rate, in updates per minute
+    fn update(&mut self) -> f64 {
+        let now = Instant::now();
+        if let Some(last) = self.last {
+            let elapsed = now.duration_since(last).as_secs_f64();
+            self.rate *= (-elapsed / Self::TAU).exp();
+        }
+        self.rate += 1.0 / Self::TAU;
+        self.last = Some(now);
+        self.rate * 60.0
     }
+}
 
-    fn update(&mut self, router_addr: &IpAddr, peer: &BGPkitPeer, update: &Update) -> bool {
-        let router = self._get_router(router_addr);
-        router.update(peer, update)
+// Approximate distinct count of the AS paths announced, bounded to a few kilobytes per peer
+#[derive(Clone)]
+struct AsPathSketch(HyperLogLogPF<Vec<u32>, RandomState>);
+
+impl AsPathSketch {
+    // 2^10 registers, for a standard error of about 3%
+    const PRECISION: u8 = 10;
+
+    // Account for an announced AS path and return the estimated number of distinct paths
+    fn update(&mut self, path: &[u32]) -> f64 {
+        self.0.insert(path);
+        self.0.count()
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Peer {
-    details: BGPkitPeer,
-    updates: HashSet<TimedPrefix>,
+impl Default for AsPathSketch {
+    fn default() -> Self {
+        AsPathSketch(HyperLogLogPF::new(Self::PRECISION, RandomState::new()).unwrap())
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct Router {
-    peers: HashMap<IpAddr, Peer>,
+    #[serde(serialize_with = "ordered_map")]
+    peers: HashMap<PeerKey, Peer>,
 }
 
 impl Router {
@@ -201,20 +1500,72 @@ impl Router {
         }
     }
 
-    fn add_peer(&mut self, peer: &BGPkitPeer) {
-        self.peers.entry(peer.peer_address).or_insert_with(|| Peer {
-            details: *peer,
-            updates: HashSet::new(),
-        });
+    fn add_peer(&mut self, peer: &BGPkitPeer, peer_type: BmpPeerType, peer_distinguisher: u64) {
+        self.peers
+            .entry(PeerKey::new(peer, peer_distinguisher))
+            .or_insert_with(|| Peer::new(peer, peer_type, peer_distinguisher));
+    }
+
+    // The gauges are maintained as the state changes, so a scrape only renders them
+    fn record_peers(&self, router_addr: &IpAddr) {
+        for peer_type in [
+            BmpPeerType::Global,
+            BmpPeerType::RD,
+            BmpPeerType::Local,
+            BmpPeerType::LocalRib,
+        ] {
+            let n_peers = self
+                .peers
+                .values()
+                .filter(|peer| peer.peer_type == peer_type)
+                .count();
+            gauge!(
+                "risotto_bgp_peers",
+                "router" => map_to_ipv6(*router_addr).to_string(),
+                "peer_type" => format_peer_type(peer_type),
+            )
+            .set(n_peers as f64);
+        }
+    }
+
+    fn record_churn(&mut self, router_addr: &IpAddr, key: &PeerKey) {
+        if let Some(peer) = self.peers.get_mut(key) {
+            let rate = peer.churn.update();
+            gauge!(
+                "risotto_peer_churn_rate",
+                "router" => map_to_ipv6(*router_addr).to_string(),
+                "peer" => map_to_ipv6(key.peer_addr).to_string(),
+                "peer_rd" => key.rd(),
+            )
+            .set(rate);
+        }
     }
 
-    fn remove_peer(&mut self, peer: &BGPkitPeer) {
-        self.peers.remove(&peer.peer_address);
+    fn record_as_paths(&mut self, router_addr: &IpAddr, key: &PeerKey, update: &Update) {
+        if let Some(peer) = self.peers.get_mut(key) {
+            let distinct = peer
+                .as_paths
+                .update(&construct_as_path(update.path.clone()));
+            gauge!(
+                "risotto_peer_distinct_as_paths",
+                "router" => map_to_ipv6(*router_addr).to_string(),
+                "peer" => map_to_ipv6(key.peer_addr).to_string(),
+                "peer_rd" => key.rd(),
+            )
+            .set(distinct);
+        }
     }
 
-    fn update(&mut self, peer: &BGPkitPeer, update: &Update) -> bool {
-        self.add_peer(peer);
-        let peer = self.peers.get_mut(&peer.peer_address).unwrap();
+    fn update(
+        &mut self,
+        peer: &BGPkitPeer,
+        update: &Update,
+        withdraw_grace_secs: u64,
+        retain_origin: bool,
+    ) -> bool {
+        self.add_peer(peer, update.peer_type, update.peer_distinguisher);
+        let key = PeerKey::new(peer, update.peer_distinguisher);
+        let peer = self.peers.get_mut(&key).unwrap();
 
         let now: i64 = chrono::Utc::now().timestamp_millis();
         let timed_prefix = TimedPrefix {
@@ -222,96 +1573,285 @@ impl Router {
             is_post_policy: update.is_post_policy,
             is_adj_rib_out: update.is_adj_rib_out,
             timestamp: now,
+            stale: false,
+            origin: match retain_origin && update.announced {
+                true => Some(PrefixOrigin {
+                    origin: update.origin,
+                    origin_asn: update
+                        .path
+                        .as_ref()
+                        .and_then(|path| path.get_origin_opt())
+                        .map(|asn| asn.to_u32()),
+                }),
+                false => None,
+            },
         };
 
+        // Suppress withdraws for prefixes we never saw announced
+        // This happens at startup, before the state is populated
+        let present = peer.updates.contains(&timed_prefix);
+        if !update.announced && !present {
+            counter!(
+                "risotto_spurious_withdraws_total",
+                "router" => map_to_ipv6(update.router_addr).to_string(),
+                "peer" => map_to_ipv6(update.peer_addr).to_string(),
+            )
+            .increment(1);
+            return false;
+        }
+
         // Will emit the update only if (1) announced + not present or (2) withdrawn + present
         // Which is a XOR operation
-        let emit = update.announced ^ peer.updates.contains(&timed_prefix);
+        let emit = update.announced ^ present;
 
         if update.announced {
+            // A prefix re-announced within the grace period of its withdraw
+            if let Some(withdrawn) = peer.withdrawn.take(&timed_prefix) {
+                histogram!(
+                    "risotto_reannounce_delay_seconds",
+                    "router" => map_to_ipv6(update.router_addr).to_string(),
+                    "peer" => map_to_ipv6(update.peer_addr).to_string(),
+                )
+                .record((now - withdrawn.timestamp) as f64 / 1000.0);
+            }
+
             // Announced prefix: add the update or overwrite it if present
-            peer.updates.replace(timed_prefix);
+            peer.insert(timed_prefix);
         } else {
             // Withdrawn prefix: remove the update if present
-            peer.updates.remove(&timed_prefix);
+            peer.remove(&timed_prefix);
+
+            // Keep it for the grace period, timestamped at withdrawal
+            if withdraw_grace_secs > 0 {
+                peer.withdrawn.replace(timed_prefix);
+            }
         }
         emit
     }
 }
 
-pub fn synthesize_withdraw_update(prefix: TimedPrefix) -> Update {
+fn set_updates_gauges(
+    router_addr: &IpAddr,
+    key: PeerKey,
+    peer_type: BmpPeerType,
+    n_updates: usize,
+    n_prefixes: usize,
+) {
+    gauge!(
+        "risotto_bgp_updates",
+        "router" => map_to_ipv6(*router_addr).to_string(),
+        "peer" => map_to_ipv6(key.peer_addr).to_string(),
+        "peer_rd" => key.rd(),
+        "peer_type" => format_peer_type(peer_type),
+    )
+    .set(n_updates as f64);
+
+    // Distinct prefixes, to tell a flapping peer from a growing table along with the churn
+    // The updates of a prefix with several path IDs or in several RIBs count once
+    gauge!(
+        "risotto_state_distinct_prefixes",
+        "router" => map_to_ipv6(*router_addr).to_string(),
+        "peer" => map_to_ipv6(key.peer_addr).to_string(),
+        "peer_rd" => key.rd(),
+    )
+    .set(n_prefixes as f64);
+}
+
+// The withdraw is fully reconstructed from the state rather than the current peer header
+// The direction and policy come from the stored prefix, since a peer may be monitored
+// both pre/post-policy and in Adj-RIB-In/Adj-RIB-Out
+pub fn synthesize_withdraw_update(
+    router_addr: IpAddr,
+    router_port: u16,
+    peer: &Peer,
+    prefix: TimedPrefix,
+) -> Update {
     Update {
+        router_addr,
+        router_port,
+        peer_addr: peer.details.peer_address,
+        peer_bgp_id: peer.details.peer_bgp_id,
+        peer_asn: peer.details.peer_asn,
+        peer_type: peer.peer_type,
+        peer_distinguisher: peer.peer_distinguisher,
         prefix: prefix.prefix,
         announced: false,
-        origin: Origin::INCOMPLETE,
-        path: None,
+        // Only the origin retained with the prefix, if any, to match the withdraw to its announcement
+        origin: prefix
+            .origin
+            .map_or(Origin::INCOMPLETE, |origin| origin.origin),
+        path: prefix
+            .origin
+            .and_then(|origin| origin.origin_asn)
+            .map(|asn| AsPath::from_sequence([asn])),
         communities: vec![],
+        originator_id: None,
+        cluster_list: vec![],
+        only_to_customer: None,
+        med: None,
+        local_pref: None,
         is_post_policy: prefix.is_post_policy,
         is_adj_rib_out: prefix.is_adj_rib_out,
         timestamp: Utc::now(),
+        header_time_valid: false,
         synthetic: true,
+        rpki_status: None,
+        as_path_has_loop: false,
+        as_path_truncated: false,
+        as_path_prepend_count: 0,
+        otc_leak_suspect: false,
+        raw_pdu: None,
+        prefix_sid: None,
+        seq: 0,
+        seq_epoch: DateTime::UNIX_EPOCH,
+        received_at: None,
+    }
+}
+
+// Send the synthetic withdraws in batches, with a pause in between
+// So the teardown of a full table peer does not flood the sinks at the expense of the other peers
+pub async fn send_synthetic_updates(
+    cfg: &StateConfig,
+    synthetic_updates: Vec<Update>,
+    tx: Sender<Vec<Update>>,
+) {
+    if cfg.withdraws_batch_size == 0 {
+        sink::send(&tx, synthetic_updates);
+        return;
+    }
+
+    let mut synthetic_updates = synthetic_updates.into_iter().peekable();
+    while synthetic_updates.peek().is_some() {
+        let batch: Vec<Update> = synthetic_updates
+            .by_ref()
+            .take(cfg.withdraws_batch_size)
+            .collect();
+        if !sink::send(&tx, batch) {
+            // The remaining batches would fail as well
+            return;
+        }
+
+        if synthetic_updates.peek().is_some() {
+            tokio::time::sleep(Duration::from_millis(cfg.withdraws_batch_interval)).await;
+        }
     }
 }
 
-pub async fn peer_up_withdraws_handler(
+// Send the queued withdraws of a peer gone down, in batches with a pause in between
+// Each batch is sent under the state lock, so it is not reordered with the announcements
+// of the peer coming back, which cancel the withdraws of their prefixes
+pub async fn peer_down_withdraws_handler(
     state: AsyncState,
     router_addr: IpAddr,
-    bgp_peer: BGPkitPeer,
-    tx: Sender<Vec<u8>>,
+    peer: BGPkitPeer,
+    peer_distinguisher: u64,
+    tx: Sender<Vec<Update>>,
 ) {
-    let startup = chrono::Utc::now();
-    let random = {
-        let mut rng = rand::thread_rng();
-        rng.gen_range(-60.0..60.0) as i64
-    };
-    let sleep_time = 300 + random; // 5 minutes +/- 1 minute
+    let key = (router_addr, PeerKey::new(&peer, peer_distinguisher));
+    let cfg = state.lock().unwrap().config().clone();
+    loop {
+        {
+            let mut state_lock = state.lock().unwrap();
+            let batch = state_lock.next_peer_down_withdraws(&key, cfg.withdraws_batch_size);
+            if batch.is_empty() {
+                return;
+            }
+            if !sink::send(&tx, batch) {
+                // The remaining batches would fail as well
+                state_lock.peer_down_withdraws.remove(&key);
+                return;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(cfg.withdraws_batch_interval)).await;
+    }
+}
+
+// Run the due startup withdraws, with at most `max_peer_up_withdraws` peers at once
+// So a router reconnecting with thousands of peers does not contend on the state lock all at once
+pub async fn peer_up_withdraws_handler(state: AsyncState, cfg: StateConfig) {
+    let permits = Arc::new(Semaphore::new(cfg.max_peer_up_withdraws));
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        loop {
+            // The due withdraws stay scheduled while waiting for a free slot
+            let permit = permits.clone().acquire_owned().await.unwrap();
+            let next = state.lock().unwrap().next_peer_up_withdraws();
+            let Some(withdraws) = next else {
+                break;
+            };
 
-    tokio::time::sleep(Duration::from_secs(sleep_time as u64)).await;
+            let state = state.clone();
+            tokio::spawn(async move {
+                peer_up_withdraws(state, withdraws).await;
+                drop(permit);
+            });
+        }
+    }
+}
+
+async fn peer_up_withdraws(state: AsyncState, withdraws: PeerUpWithdraws) {
+    let PeerUpWithdraws {
+        router_addr,
+        peer: bgp_peer,
+        peer_distinguisher,
+        startup,
+        tx,
+    } = withdraws;
 
     log::info!(
+        router:% = router_addr, peer:% = bgp_peer.peer_address;
         "state - startup withdraws handler - {} - {} removing updates older than {}",
         router_addr,
         bgp_peer.peer_address,
         startup
     );
 
-    let state_lock: std::sync::MutexGuard<'_, State> = state.lock().unwrap();
-    let peer = match state_lock
-        .store
-        .get_peer(&router_addr, &bgp_peer.peer_address)
-    {
-        Some(peer) => peer,
-        None => return,
+    let peer = {
+        let state_lock: std::sync::MutexGuard<'_, State> = state.lock().unwrap();
+        match state_lock
+            .store
+            .get_peer(&router_addr, &PeerKey::new(&bgp_peer, peer_distinguisher))
+        {
+            Some(peer) => peer,
+            None => return,
+        }
     };
 
-    drop(state_lock);
-
     let mut synthetic_updates = Vec::new();
-    for update in peer.updates {
+    for update in &peer.updates {
         if update.timestamp < startup.timestamp_millis() {
             // This update has been re-announced after startup
             // Emit a synthetic withdraw update
-            synthetic_updates.push((
+            synthetic_updates.push(synthesize_withdraw_update(
                 router_addr,
-                peer.details,
-                synthesize_withdraw_update(update.clone()),
+                0,
+                &peer,
+                update.clone(),
             ));
         }
     }
 
-    let mut state_lock: std::sync::MutexGuard<'_, State> = state.lock().unwrap();
-    let mut buffer: Vec<u8> = vec![];
-    for (router_addr, peer, update) in &mut synthetic_updates {
-        let update_str = format_update(*router_addr, 0, peer, update);
-        log::trace!("{:?}", update_str);
-        buffer.extend(update_str.as_bytes());
-        buffer.extend(b"\n");
+    let cfg = {
+        let mut state_lock: std::sync::MutexGuard<'_, State> = state.lock().unwrap();
+        let cfg = state_lock.config.clone();
 
-        // Remove the update from the state
-        state_lock.store.update(router_addr, peer, update);
-    }
+        // Remove the updates from the state
+        // Only the withdraws of the prefixes no longer held within the scope are emitted
+        synthetic_updates.retain(|update| {
+            log::trace!("{:?}", update);
+            state_lock.store.update(
+                &router_addr,
+                &peer.details,
+                update,
+                cfg.withdraw_grace_secs,
+                cfg.retain_origin,
+            )
+        });
+        cfg
+    };
 
     log::info!(
+        router:% = router_addr, peer:% = bgp_peer.peer_address;
         "state - startup withdraws handler - {} - {} emitting {} synthetic withdraw updates",
         router_addr,
         bgp_peer.peer_address,
@@ -319,16 +1859,453 @@ pub async fn peer_up_withdraws_handler(
     );
 
     // Sent to the event pipeline
-    tx.send(buffer).unwrap();
+    send_synthetic_updates(&cfg, synthetic_updates, tx).await;
+}
+
+// Withdraw the prefixes of a flap of the peer not refreshed within the restart time
+// The peer is removed if it is still down and no longer has any prefix
+#[allow(clippy::too_many_arguments)]
+pub async fn stale_withdraws_handler(
+    state: AsyncState,
+    router_addr: IpAddr,
+    router_port: u16,
+    bgp_peer: BGPkitPeer,
+    peer_distinguisher: u64,
+    restart_time: u16,
+    generation: u64,
+    tx: Sender<Vec<Update>>,
+) {
+    tokio::time::sleep(Duration::from_secs(restart_time as u64)).await;
+
+    let key = PeerKey::new(&bgp_peer, peer_distinguisher);
+    let (cfg, synthetic_updates) = {
+        let mut state_lock = state.lock().unwrap();
+        let peer = match state_lock.store.get_peer(&router_addr, &key) {
+            // Superseded by a later flap, with a restart time of its own
+            Some(peer) if peer.stale_generation != generation => return,
+            Some(peer) => peer,
+            None => return,
+        };
+
+        // Withdraw the prefixes that were not refreshed within the restart time
+        let mut synthetic_updates = Vec::new();
+        for prefix in &peer.updates {
+            if prefix.stale {
+                synthetic_updates.push(synthesize_withdraw_update(
+                    router_addr,
+                    router_port,
+                    &peer,
+                    prefix.clone(),
+                ));
+            }
+        }
+
+        // Remove the updates from the state
+        // Only the withdraws of the prefixes no longer held within the scope are emitted
+        let cfg = state_lock.config.clone();
+        synthetic_updates.retain(|update| {
+            log::trace!("{:?}", update);
+            state_lock.store.update(
+                &router_addr,
+                &peer.details,
+                update,
+                cfg.withdraw_grace_secs,
+                cfg.retain_origin,
+            )
+        });
+
+        let store = &mut state_lock.store;
+        let empty = store
+            .routers
+            .get(&router_addr)
+            .and_then(|router| router.peers.get(&key))
+            .is_some_and(|peer| peer.restarting && peer.updates.is_empty());
+        if empty {
+            store.remove_peer(&router_addr, &key);
+        }
+
+        (cfg, synthetic_updates)
+    };
+
+    log::info!(
+        router:% = router_addr, peer:% = bgp_peer.peer_address;
+        "state - stale withdraws handler - {} - {} emitting {} synthetic withdraw updates",
+        router_addr,
+        bgp_peer.peer_address,
+        synthetic_updates.len()
+    );
+
+    // Sent to the event pipeline
+    send_synthetic_updates(&cfg, synthetic_updates, tx).await;
 }
 
+// A failing dump (e.g. on a full disk) is retried on the next interval
+// Only after `max_dump_failures` consecutive failures the handler gives up, if set
 pub async fn dump_handler(state: AsyncState, cfg: StateConfig) {
+    let fsync_interval = Duration::from_secs(cfg.fsync_interval);
+    let mut last_fsync = Instant::now();
+    let mut failures = 0;
     loop {
         // TODO do not spawn this task if state is disabled
         tokio::time::sleep(Duration::from_secs(cfg.interval)).await;
         if cfg.enable {
-            log::debug!("state - dump handler - dumping state to {}", cfg.path);
-            dump(state.clone());
+            // Only some of the dumps are forced to disk
+            let fsync = cfg.fsync_interval > 0 && last_fsync.elapsed() >= fsync_interval;
+            log::debug!(
+                "state - dump handler - dumping state to {} (fsync: {})",
+                cfg.path,
+                fsync
+            );
+            match dump(state.clone(), fsync) {
+                Ok(()) => {
+                    failures = 0;
+                    if fsync {
+                        last_fsync = Instant::now();
+                    }
+                }
+                Err(e) => {
+                    failures += 1;
+                    counter!("risotto_state_dump_failures_total").increment(1);
+                    log::error!(
+                        "state - dump handler - failed to dump state to {} ({} in a row): {}",
+                        cfg.path,
+                        failures,
+                        e
+                    );
+                    if cfg.max_dump_failures > 0 && failures >= cfg.max_dump_failures {
+                        log::error!(
+                            "state - dump handler - giving up after {} failures",
+                            failures
+                        );
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Periodic self-check of the gauges against the state, infrequent as it walks all the peers
+pub async fn audit_handler(state: AsyncState, cfg: StateConfig) {
+    if !cfg.enable || cfg.audit_interval == 0 {
+        return std::future::pending().await;
+    }
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(cfg.audit_interval)).await;
+        let drifts = state.lock().unwrap().audit();
+        log::debug!("state - audit - {} gauges out of date", drifts);
+    }
+}
+
+// Periodic end of the cooldown of the dampened peers
+pub async fn dampening_handler(state: AsyncState, cfg: StateConfig) {
+    if cfg.dampening_max_flaps == 0 {
+        return std::future::pending().await;
+    }
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        state.lock().unwrap().release_dampened();
+    }
+}
+
+pub async fn purge_handler(state: AsyncState, cfg: StateConfig) {
+    if !cfg.enable || cfg.withdraw_grace_secs == 0 {
+        return std::future::pending().await;
+    }
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(cfg.withdraw_grace_secs)).await;
+        state.lock().unwrap().purge_withdrawn();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::get_state_config;
+    use bgpkit_parser::models::Asn;
+    use config::Config;
+    use core::net::Ipv4Addr;
+
+    fn peer(peer_addr: &str) -> BGPkitPeer {
+        BGPkitPeer::new(
+            Ipv4Addr::new(192, 0, 2, 254),
+            peer_addr.parse().unwrap(),
+            Asn::new_32bit(65001),
+        )
+    }
+
+    fn prefix(prefix: &str) -> TimedPrefix {
+        TimedPrefix {
+            prefix: prefix.parse().unwrap(),
+            is_post_policy: false,
+            is_adj_rib_out: false,
+            timestamp: 0,
+            stale: false,
+            origin: None,
+        }
+    }
+
+    fn update(
+        router_addr: IpAddr,
+        peer: &BGPkitPeer,
+        prefix: TimedPrefix,
+        announced: bool,
+    ) -> Update {
+        let state_peer = Peer::new(peer, BmpPeerType::Global, 0);
+        let mut update = synthesize_withdraw_update(router_addr, 0, &state_peer, prefix);
+        update.announced = announced;
+        update.synthetic = false;
+        update
+    }
+
+    // Update the store, returns whether the update is emitted
+    fn apply(
+        store: &mut MemoryStore,
+        router_addr: IpAddr,
+        peer: &BGPkitPeer,
+        prefix_str: &str,
+        announced: bool,
+    ) -> bool {
+        let update = update(router_addr, peer, prefix(prefix_str), announced);
+        store.update(&router_addr, peer, &update, 0, false)
+    }
+
+    #[test]
+    fn distinct_prefixes() {
+        let mut peer = Peer::new(&peer("192.0.2.1"), BmpPeerType::Global, 0);
+        let mut with_path_id = prefix("198.51.100.0/24");
+        with_path_id.prefix.path_id = 1;
+        let mut post_policy = prefix("198.51.100.0/24");
+        post_policy.is_post_policy = true;
+
+        // The updates of a prefix with several path IDs or in several RIBs count once
+        for p in [prefix("198.51.100.0/24"), with_path_id, post_policy] {
+            peer.insert(p);
+        }
+        peer.insert(prefix("2001:db8::/32"));
+        assert_eq!((peer.updates.len(), peer.index.len()), (4, 2));
+
+        peer.remove(&prefix("198.51.100.0/24"));
+        assert_eq!((peer.updates.len(), peer.index.len()), (3, 2));
+        peer.remove(&prefix("2001:db8::/32"));
+        assert_eq!((peer.updates.len(), peer.index.len()), (2, 1));
+    }
+
+    #[test]
+    fn snapshot_serialized_as_store() {
+        let mut store = MemoryStore::new(DedupScope::Peer);
+        let (r1, r2) = ("10.0.0.1".parse().unwrap(), "2001:db8::1".parse().unwrap());
+        let (a, b) = (peer("192.0.2.1"), peer("2001:db8::2"));
+        apply(&mut store, r1, &a, "198.51.100.0/24", true);
+        apply(&mut store, r1, &a, "203.0.113.0/24", true);
+        apply(&mut store, r1, &a, "203.0.113.0/24", false);
+        apply(&mut store, r2, &b, "2001:db8:1::/48", true);
+
+        let snapshot = serde_json::to_string(&store.snapshot()).unwrap();
+        assert_eq!(snapshot, serde_json::to_string(&store).unwrap());
+    }
+
+    #[test]
+    fn audit_recorded_updates() {
+        let settings = Config::builder()
+            .set_override("state.enable", true)
+            .unwrap()
+            .set_override("state.path", "/nonexistent/risotto.json")
+            .unwrap()
+            .set_override("state.save_interval", 10)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut state = State::new(&get_state_config(&settings).unwrap());
+        let (r1, a, b) = (
+            "10.0.0.1".parse().unwrap(),
+            peer("192.0.2.1"),
+            peer("192.0.2.2"),
+        );
+        apply(&mut state.store, r1, &a, "198.51.100.0/24", true);
+        apply(&mut state.store, r1, &b, "203.0.113.0/24", true);
+        state.store.remove_peer(&r1, &PeerKey::new(&b, 0));
+        assert_eq!(state.audit(), 0);
+
+        // A gauge left out of date is caught, then fixed
+        state.store.recorded.clear();
+        assert_eq!(state.audit(), 1);
+        assert_eq!(state.audit(), 0);
+    }
+
+    #[test]
+    fn refresh_same_timestamp() {
+        let mut peer = Peer::new(&peer("192.0.2.1"), BmpPeerType::Global, 0);
+        peer.insert(prefix("198.51.100.0/24"));
+        peer.insert(prefix("203.0.113.0/24"));
+
+        // Refreshed with an unchanged timestamp, the prefix is still evictable
+        peer.insert(prefix("198.51.100.0/24"));
+        assert_eq!(peer.ages.len(), peer.updates.len());
+        assert!(peer.pop_oldest().is_some());
+        assert!(peer.pop_oldest().is_some());
+        assert!(peer.updates.is_empty());
+    }
+
+    #[test]
+    fn dedup_router_scope() {
+        let mut store = MemoryStore::new(DedupScope::Router);
+        let (r1, r2) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let (a, b, c) = (peer("192.0.2.1"), peer("192.0.2.2"), peer("192.0.2.3"));
+
+        // Only the first peer of each router announcing the prefix emits it
+        assert!(apply(&mut store, r1, &a, "198.51.100.0/24", true));
+        assert!(!apply(&mut store, r1, &b, "198.51.100.0/24", true));
+        assert!(apply(&mut store, r2, &c, "198.51.100.0/24", true));
+        assert!(store.is_shared(&r1, &PeerKey::new(&a, 0), &prefix("198.51.100.0/24")));
+        assert!(!store.is_shared(&r2, &PeerKey::new(&c, 0), &prefix("198.51.100.0/24")));
+
+        // Only the last peer of the router withdrawing the prefix emits the withdraw
+        assert!(!apply(&mut store, r1, &a, "198.51.100.0/24", false));
+        assert!(apply(&mut store, r1, &b, "198.51.100.0/24", false));
+        assert!(apply(&mut store, r2, &c, "198.51.100.0/24", false));
+        assert!(store.holders.is_empty());
+    }
+
+    #[test]
+    fn dedup_global_scope() {
+        let mut store = MemoryStore::new(DedupScope::Global);
+        let (r1, r2) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let (a, b) = (peer("192.0.2.1"), peer("192.0.2.2"));
+
+        assert!(apply(&mut store, r1, &a, "198.51.100.0/24", true));
+        assert!(!apply(&mut store, r2, &b, "198.51.100.0/24", true));
+        assert!(apply(&mut store, r2, &b, "203.0.113.0/24", true));
+
+        // A peer going down only releases its own prefixes
+        store.remove_peer(&r1, &PeerKey::new(&a, 0));
+        assert!(!store.is_shared(&r2, &PeerKey::new(&b, 0), &prefix("198.51.100.0/24")));
+        assert!(apply(&mut store, r2, &b, "198.51.100.0/24", false));
+        assert!(apply(&mut store, r2, &b, "203.0.113.0/24", false));
+        assert!(store.holders.is_empty());
+    }
+
+    #[test]
+    fn dedup_peer_scope() {
+        let mut store = MemoryStore::new(DedupScope::Peer);
+        let r1 = "10.0.0.1".parse().unwrap();
+        let (a, b) = (peer("192.0.2.1"), peer("192.0.2.2"));
+
+        assert!(apply(&mut store, r1, &a, "198.51.100.0/24", true));
+        assert!(apply(&mut store, r1, &b, "198.51.100.0/24", true));
+        assert!(store.holders.is_empty());
+    }
+
+    #[test]
+    fn withdraw_before_announce() {
+        let mut store = MemoryStore::new(DedupScope::Peer);
+        let r1 = "10.0.0.1".parse().unwrap();
+        let a = peer("192.0.2.1");
+
+        // A withdraw for a prefix never announced is suppressed, and not kept as withdrawn
+        assert!(!apply(&mut store, r1, &a, "198.51.100.0/24", false));
+        assert!(!store.contains(&r1, &PeerKey::new(&a, 0), &prefix("198.51.100.0/24")));
+
+        // The announcement which follows is emitted, and so is its withdraw
+        assert!(apply(&mut store, r1, &a, "198.51.100.0/24", true));
+        assert!(apply(&mut store, r1, &a, "198.51.100.0/24", false));
+        assert!(!apply(&mut store, r1, &a, "198.51.100.0/24", false));
+    }
+
+    #[test]
+    fn synthetic_withdraws_mixed_directions() {
+        let mut store = MemoryStore::new(DedupScope::Peer);
+        let r1: IpAddr = "10.0.0.1".parse().unwrap();
+        let a = peer("192.0.2.1");
+
+        // The same prefix monitored pre/post-policy, in the Adj-RIB-In and the Adj-RIB-Out
+        let directions = [(false, false), (true, false), (false, true), (true, true)];
+        for (is_post_policy, is_adj_rib_out) in directions {
+            let mut timed_prefix = prefix("198.51.100.0/24");
+            timed_prefix.is_post_policy = is_post_policy;
+            timed_prefix.is_adj_rib_out = is_adj_rib_out;
+            let update = update(r1, &a, timed_prefix, true);
+            assert!(store.update(&r1, &a, &update, 0, false));
         }
+
+        // The withdraws are rebuilt from the stored peer, one for each direction
+        let state_peer = store.get_peer(&r1, &PeerKey::new(&a, 0)).unwrap();
+        let mut withdraws: Vec<(bool, bool)> = state_peer
+            .updates
+            .iter()
+            .map(|prefix| synthesize_withdraw_update(r1, 0, &state_peer, prefix.clone()))
+            .map(|update| {
+                assert!(!update.announced && update.synthetic);
+                assert_eq!(update.peer_addr, a.peer_address);
+                (update.is_post_policy, update.is_adj_rib_out)
+            })
+            .collect();
+        withdraws.sort();
+        assert_eq!(
+            withdraws,
+            [(false, false), (false, true), (true, false), (true, true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_withdraws_of_the_last_flap() {
+        let settings = Config::builder()
+            .set_override("state.enable", true)
+            .unwrap()
+            .set_override("state.path", "/nonexistent/risotto.json")
+            .unwrap()
+            .set_override("state.save_interval", 10)
+            .unwrap()
+            .set_override("state.graceful_restart", true)
+            .unwrap()
+            .build()
+            .unwrap();
+        let state = new_state(&get_state_config(&settings).unwrap());
+        let router_addr = "10.0.0.1".parse().unwrap();
+        let a = peer("192.0.2.1");
+        let key = PeerKey::new(&a, 0);
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let (first, second) = {
+            let mut state_lock = state.lock().unwrap();
+            let state_lock = &mut *state_lock;
+            state_lock
+                .add_peer(&router_addr, &a, BmpPeerType::Global, 0, Some(0))
+                .unwrap();
+            apply(
+                &mut state_lock.store,
+                router_addr,
+                &a,
+                "198.51.100.0/24",
+                true,
+            );
+
+            // The peer flaps again before the end of the restart time of its first flap
+            let first = state_lock.mark_stale(&router_addr, &a, 0).unwrap().unwrap();
+            state_lock
+                .add_peer(&router_addr, &a, BmpPeerType::Global, 0, Some(0))
+                .unwrap();
+            let second = state_lock.mark_stale(&router_addr, &a, 0).unwrap().unwrap();
+            (first.1, second.1)
+        };
+
+        // The restart time of the first flap is superseded by the one of the second flap
+        stale_withdraws_handler(state.clone(), router_addr, 0, a, 0, 0, first, tx.clone()).await;
+        assert_eq!(rx.try_iter().count(), 0);
+
+        // The peer is removed along with its last prefix, as it is still down
+        stale_withdraws_handler(state.clone(), router_addr, 0, a, 0, 0, second, tx).await;
+        let withdraws: Vec<Update> = rx.try_iter().flatten().collect();
+        assert_eq!(withdraws.len(), 1);
+        assert!(!withdraws[0].announced && withdraws[0].synthetic);
+        assert!(state
+            .lock()
+            .unwrap()
+            .store
+            .get_peer(&router_addr, &key)
+            .is_none());
     }
 }
@@ -0,0 +1,81 @@
+use log::{Level, Record};
+use std::collections::HashMap;
+use std::fmt::Arguments;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+// Repeated identical log lines are collapsed over this period
+const PERIOD: Duration = Duration::from_secs(60);
+
+// Above this many distinct lines, the lines not seen within the period are forgotten
+const MAX_LINES: usize = 1024;
+
+struct Line {
+    since: Instant,
+    suppressed: u64,
+}
+
+static LINES: LazyLock<Mutex<HashMap<String, Line>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Log a line at most once per period, for the hot paths failing repeatedly (e.g. a broker down)
+// The repetitions are counted, and reported along the next line logged after the period
+pub fn log(level: Level, message: String) {
+    log_kv(level, &[], message);
+}
+
+// Same, with the key-value pairs of the structured logs (e.g. the router)
+// The lines are told apart by their message only
+pub fn log_kv(level: Level, kvs: &[(&str, &str)], message: String) {
+    if !log::log_enabled!(level) {
+        return;
+    }
+
+    let mut lines = LINES.lock().unwrap();
+    if let Some(line) = lines.get_mut(&message) {
+        if line.since.elapsed() < PERIOD {
+            line.suppressed += 1;
+            return;
+        }
+
+        match line.suppressed {
+            0 => emit(level, kvs, format_args!("{}", message)),
+            n => emit(
+                level,
+                kvs,
+                format_args!(
+                    "{} ({} occurrences in the last {} seconds)",
+                    message,
+                    n + 1,
+                    line.since.elapsed().as_secs()
+                ),
+            ),
+        }
+        line.since = Instant::now();
+        line.suppressed = 0;
+        return;
+    }
+
+    if lines.len() >= MAX_LINES {
+        lines.retain(|_, line| line.since.elapsed() < PERIOD);
+    }
+    emit(level, kvs, format_args!("{}", message));
+    lines.insert(
+        message,
+        Line {
+            since: Instant::now(),
+            suppressed: 0,
+        },
+    );
+}
+
+fn emit(level: Level, kvs: &[(&str, &str)], args: Arguments) {
+    log::logger().log(
+        &Record::builder()
+            .level(level)
+            .target(module_path!())
+            .module_path(Some(module_path!()))
+            .key_values(&kvs)
+            .args(args)
+            .build(),
+    );
+}
@@ -1,30 +1,78 @@
 use chrono::{DateTime, MappedLocalTime, TimeZone, Utc};
 
-use bgpkit_parser::bmp::messages::RouteMonitoring;
+use bgpkit_parser::bmp::messages::{BmpPeerType, RouteMonitoring};
 use bgpkit_parser::models::*;
-use core::net::IpAddr;
+use bytes::Bytes;
+use core::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
 use log::error;
+use metrics::counter;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::time::Instant;
+
+use crate::attributes::{prefix_sid, PrefixSid};
+use crate::rpki::{RpkiStatus, Validator};
+use crate::settings::{AsnFormat, DecodeConfig, LoopDetectionConfig, OutputConfig, PeerRole};
 
 pub struct UpdateHeader {
-    pub timestamp: i64,
+    pub router_addr: IpAddr,
+    pub router_port: u16,
+    pub peer: Peer,
+    pub peer_type: BmpPeerType,
+    pub peer_distinguisher: u64,
+    pub timestamp: Option<i64>,
     pub is_post_policy: bool,
     pub is_adj_rib_out: bool,
+    pub raw: Bytes,
+    pub received_at: Instant,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Update {
+    pub router_addr: IpAddr,
+    pub router_port: u16,
+    pub peer_addr: IpAddr,
+    pub peer_bgp_id: Ipv4Addr,
+    pub peer_asn: Asn,
+    pub peer_type: BmpPeerType,
+    pub peer_distinguisher: u64,
     pub prefix: NetworkPrefix,
     pub announced: bool,
     pub origin: Origin,
     pub path: Option<AsPath>,
     pub communities: Vec<MetaCommunity>,
+    pub originator_id: Option<Ipv4Addr>,
+    pub cluster_list: Vec<u32>,
+    pub only_to_customer: Option<u32>,
+    pub med: Option<u32>,
+    pub local_pref: Option<u32>,
     pub is_post_policy: bool,
     pub is_adj_rib_out: bool,
     pub timestamp: DateTime<Utc>,
+    pub header_time_valid: bool,
     pub synthetic: bool,
+    pub rpki_status: Option<RpkiStatus>,
+    pub as_path_has_loop: bool,
+    pub as_path_truncated: bool,
+    pub as_path_prepend_count: u8,
+    pub otc_leak_suspect: bool,
+    pub raw_pdu: Option<Bytes>,
+    pub prefix_sid: Option<PrefixSid>,
+    // Assigned when sent to the sinks
+    pub seq: u64,
+    pub seq_epoch: DateTime<Utc>,
+    // Reception of the BMP message, none for the synthetic updates
+    pub received_at: Option<Instant>,
 }
 
-pub fn decode_updates(message: RouteMonitoring, header: UpdateHeader) -> Option<Vec<Update>> {
+pub fn decode_updates(
+    message: RouteMonitoring,
+    header: UpdateHeader,
+    validator: Option<&Validator>,
+    decode: &DecodeConfig,
+) -> Option<Vec<Update>> {
     let mut updates = Vec::new();
 
     match message.bgp_message {
@@ -51,36 +99,171 @@ pub fn decode_updates(message: RouteMonitoring, header: UpdateHeader) -> Option<
                 }
             }
 
+            // A prefix both announced and withdrawn in the same update is withdrawn
+            // Otherwise the resulting state would depend on the processing order
+            let withdrawn: HashSet<NetworkPrefix> = prefixes_to_update
+                .iter()
+                .filter(|(_, announced)| !announced)
+                .map(|(prefix, _)| *prefix)
+                .collect();
+            let n_prefixes = prefixes_to_update.len();
+            prefixes_to_update
+                .retain(|(prefix, announced)| !announced || !withdrawn.contains(prefix));
+            if prefixes_to_update.len() < n_prefixes {
+                counter!(
+                    "risotto_conflicting_nlri_total",
+                    "router" => map_to_ipv6(header.router_addr).to_string(),
+                    "peer" => map_to_ipv6(header.peer.peer_address).to_string(),
+                )
+                .increment((n_prefixes - prefixes_to_update.len()) as u64);
+            }
+
+            // Host routes and default routes are counted, and dropped if configured
+            prefixes_to_update.retain(|(prefix, _)| {
+                let (category, drop) = match prefix_category(&prefix.prefix) {
+                    Some(PrefixCategory::HostRoute) => ("host_route", decode.drop_host_routes),
+                    Some(PrefixCategory::DefaultRoute) => {
+                        ("default_route", decode.drop_default_routes)
+                    }
+                    None => return true,
+                };
+                counter!(
+                    "risotto_prefix_categories_total",
+                    "router" => map_to_ipv6(header.router_addr).to_string(),
+                    "peer" => map_to_ipv6(header.peer.peer_address).to_string(),
+                    "category" => category,
+                    "dropped" => drop.to_string(),
+                )
+                .increment(1);
+                !drop
+            });
+
+            // Martians and bogons are dropped if configured
+            if decode.drop_bogons {
+                prefixes_to_update.retain(|(prefix, _)| {
+                    let is_bogon = decode
+                        .bogons
+                        .iter()
+                        .any(|bogon| bogon.contains(&prefix.prefix));
+                    if is_bogon {
+                        counter!(
+                            "risotto_updates_dropped_bogon_total",
+                            "router" => map_to_ipv6(header.router_addr).to_string(),
+                            "peer" => map_to_ipv6(header.peer.peer_address).to_string(),
+                        )
+                        .increment(1);
+                    }
+                    !is_bogon
+                });
+            }
+
             // Get the other attributes
             let origin = attributes.origin();
-            let path = match attributes.as_path() {
-                Some(path) => Some(path.clone()),
-                None => None,
-            };
-            let communities: Vec<MetaCommunity> = attributes.iter_communities().collect();
-
-            let timestamp = match Utc.timestamp_millis_opt(header.timestamp) {
-                MappedLocalTime::Single(dt) => dt,
-                _ => {
-                    error!(
-                        "bmp - failed to parse timestamp: {}, using Utc::now()",
-                        header.timestamp
-                    );
-                    Utc::now()
+            let mut path = attributes.as_path().cloned();
+            let mut communities: Vec<MetaCommunity> = attributes.iter_communities().collect();
+            // The communities iterator of the BGP parser skips the IPv6 extended communities
+            for value in attributes.iter() {
+                if let AttributeValue::Ipv6AddressSpecificExtendedCommunities(ecs) = value {
+                    communities.extend(ecs.iter().copied().map(MetaCommunity::Ipv6Extended));
                 }
+            }
+            if decode.sort_communities {
+                communities.sort_by_cached_key(community_sort_key);
+            }
+            let originator_id = attributes.origin_id();
+            let cluster_list = attributes.clusters().unwrap_or_default().to_vec();
+            let only_to_customer = attributes.only_to_customer().map(|asn| asn.to_u32());
+            let med = attributes.multi_exit_discriminator();
+            let local_pref = attributes.local_preference();
+            // The BGP parser discards the Prefix-SID attribute, read from the raw message
+            let prefix_sid = prefix_sid(&header.raw);
+            let raw_pdu = decode.include_raw_pdu.then(|| header.raw.clone());
+
+            // Routers may send no timestamp in the per-peer header (e.g. for the Local RIB)
+            // The update is then timestamped at reception and flagged as such
+            let (timestamp, header_time_valid) = match header.timestamp {
+                Some(timestamp) => match Utc.timestamp_millis_opt(timestamp) {
+                    MappedLocalTime::Single(dt) => (dt, true),
+                    _ => {
+                        error!(
+                            "bmp - failed to parse timestamp: {}, using Utc::now()",
+                            timestamp
+                        );
+                        (Utc::now(), false)
+                    }
+                },
+                None => (Utc::now(), false),
+            };
+
+            let origin_asn = path
+                .as_ref()
+                .and_then(|path| path.get_origin_opt())
+                .map(|asn| asn.to_u32());
+            let as_path = construct_as_path(path.clone());
+            let as_path_has_loop = has_loop(&as_path, &decode.loop_detection);
+            let as_path_prepend_count = prepend_count(&as_path);
+            let otc_leak_suspect = is_otc_leak(
+                decode.peer_roles.get(&header.peer.peer_address).copied(),
+                only_to_customer,
+                header.peer.peer_asn.to_u32(),
+            );
+
+            // Pathological AS paths are truncated, once the origin and loops are known
+            let as_path_truncated = match path.as_mut() {
+                Some(path) => truncate_as_path(path, decode.max_as_path_length),
+                None => false,
             };
+            if as_path_truncated {
+                counter!(
+                    "risotto_as_path_truncated_total",
+                    "router" => map_to_ipv6(header.router_addr).to_string(),
+                    "peer" => map_to_ipv6(header.peer.peer_address).to_string(),
+                )
+                .increment(1);
+            }
 
             for (prefix, announced) in prefixes_to_update {
+                // Only announced prefixes are validated against the VRP set
+                let rpki_status = match validator {
+                    Some(validator) if announced => {
+                        Some(validator.validate(&prefix.prefix, origin_asn))
+                    }
+                    _ => None,
+                };
+
                 updates.push(Update {
+                    router_addr: header.router_addr,
+                    router_port: header.router_port,
+                    peer_addr: header.peer.peer_address,
+                    peer_bgp_id: header.peer.peer_bgp_id,
+                    peer_asn: header.peer.peer_asn,
+                    peer_type: header.peer_type,
+                    peer_distinguisher: header.peer_distinguisher,
                     prefix,
                     announced,
                     origin,
                     path: path.clone(),
                     communities: communities.clone(),
+                    originator_id,
+                    cluster_list: cluster_list.clone(),
+                    only_to_customer,
+                    med,
+                    local_pref,
                     is_post_policy: header.is_post_policy,
                     is_adj_rib_out: header.is_adj_rib_out,
                     timestamp,
+                    header_time_valid,
                     synthetic: false,
+                    rpki_status,
+                    as_path_has_loop,
+                    as_path_truncated,
+                    as_path_prepend_count,
+                    otc_leak_suspect,
+                    raw_pdu: raw_pdu.clone(),
+                    prefix_sid,
+                    seq: 0,
+                    seq_epoch: DateTime::UNIX_EPOCH,
+                    received_at: Some(header.received_at),
                 });
             }
 
@@ -90,6 +273,22 @@ pub fn decode_updates(message: RouteMonitoring, header: UpdateHeader) -> Option<
     }
 }
 
+pub enum PrefixCategory {
+    HostRoute,
+    DefaultRoute,
+}
+
+// Host routes (/32 or /128) and default routes (/0) are often handled apart downstream
+pub fn prefix_category(prefix: &IpNet) -> Option<PrefixCategory> {
+    if prefix.prefix_len() == prefix.max_prefix_len() {
+        Some(PrefixCategory::HostRoute)
+    } else if prefix.prefix_len() == 0 {
+        Some(PrefixCategory::DefaultRoute)
+    } else {
+        None
+    }
+}
+
 pub fn construct_as_path(path: Option<AsPath>) -> Vec<u32> {
     match path {
         Some(mut path) => {
@@ -108,68 +307,1015 @@ pub fn construct_as_path(path: Option<AsPath>) -> Vec<u32> {
     }
 }
 
+// Keep the first ASNs of an AS path, the closest to the peer
+// Returns whether the AS path was truncated, a zero length disables the truncation
+pub fn truncate_as_path(path: &mut AsPath, max_length: usize) -> bool {
+    if max_length == 0 {
+        return false;
+    }
+
+    let mut length = 0;
+    for i in 0..path.segments.len() {
+        let asns = match &mut path.segments[i] {
+            AsPathSegment::AsSequence(asns)
+            | AsPathSegment::AsSet(asns)
+            | AsPathSegment::ConfedSequence(asns)
+            | AsPathSegment::ConfedSet(asns) => asns,
+        };
+        if length + asns.len() > max_length {
+            asns.truncate(max_length - length);
+            let keep = if asns.is_empty() { i } else { i + 1 };
+            path.segments.truncate(keep);
+            return true;
+        }
+        length += asns.len();
+    }
+    false
+}
+
+// Largest number of times a single ASN is prepended, i.e. repeated in a row after its first occurrence
+// Prepending is commonly used for traffic engineering, or to stuff the AS path
+pub fn prepend_count(path: &[u32]) -> u8 {
+    let mut max_prepends = 0;
+    let mut prepends = 0;
+    for pair in path.windows(2) {
+        if pair[0] == pair[1] {
+            prepends += 1;
+            max_prepends = max_prepends.max(prepends);
+        } else {
+            prepends = 0;
+        }
+    }
+    max_prepends.min(u8::MAX as usize) as u8
+}
+
+// An AS path has a loop if (1) an ASN reappears after another ASN,
+// (2) an ASN is prepended more than the threshold or (3) it contains one of our ASNs
+pub fn has_loop(path: &[u32], loop_detection: &LoopDetectionConfig) -> bool {
+    if path.iter().any(|asn| loop_detection.asns.contains(asn)) {
+        return true;
+    }
+
+    let mut seen = Vec::new();
+    let mut prepends = 0;
+    for (i, asn) in path.iter().enumerate() {
+        if i > 0 && path[i - 1] == *asn {
+            prepends += 1;
+            if prepends > loop_detection.max_prepends {
+                return true;
+            }
+            continue;
+        }
+        if seen.contains(asn) {
+            return true;
+        }
+        seen.push(*asn);
+        prepends = 0;
+    }
+    false
+}
+
+// Route leak detection with the Only to Customer attribute
+// https://datatracker.ietf.org/doc/html/rfc9234#section-5
+pub fn is_otc_leak(role: Option<PeerRole>, only_to_customer: Option<u32>, peer_asn: u32) -> bool {
+    match (role, only_to_customer) {
+        // Received from a customer or a route server client with the OTC attribute
+        (Some(PeerRole::Customer | PeerRole::RsClient), Some(_)) => true,
+        // Received from a peer with an OTC attribute not set to the peer ASN
+        (Some(PeerRole::Peer), Some(otc)) => otc != peer_asn,
+        _ => false,
+    }
+}
+
+// Canonical order of the communities, regardless of their order on the wire
+// Standard communities come first, by value, then the extended and the large ones
+pub fn community_sort_key(community: &MetaCommunity) -> (u8, [u32; 3], String) {
+    match community {
+        MetaCommunity::Plain(community) => {
+            let value = match community {
+                Community::NoExport => 0xFFFFFF01,
+                Community::NoAdvertise => 0xFFFFFF02,
+                Community::NoExportSubConfed => 0xFFFFFF03,
+                Community::Custom(asn, value) => (asn.to_u32() << 16) | *value as u32,
+            };
+            (0, [value, 0, 0], String::new())
+        }
+        MetaCommunity::Extended(community) => (1, [0; 3], community.to_string()),
+        MetaCommunity::Ipv6Extended(community) => (2, [0; 3], community.to_string()),
+        MetaCommunity::Large(community) => (
+            3,
+            [
+                community.global_admin,
+                community.local_data[0],
+                community.local_data[1],
+            ],
+            String::new(),
+        ),
+    }
+}
+
 pub fn construct_communities(communities: &[MetaCommunity]) -> Vec<(u32, u16)> {
     let mut constructed_communities = Vec::new();
     for community in communities {
-        match community {
-            MetaCommunity::Plain(community) => match community {
-                bgpkit_parser::models::Community::Custom(asn, value) => {
-                    constructed_communities.push((asn.to_u32(), *value));
-                }
-                _ => (), // TODO
-            },
-            _ => (), // TODO
+        // TODO: handle the other community types
+        if let MetaCommunity::Plain(Community::Custom(asn, value)) = community {
+            constructed_communities.push((asn.to_u32(), *value));
         }
     }
     constructed_communities
 }
 
-fn map_to_ipv6(ip: IpAddr) -> IpAddr {
-    if ip.is_ipv4() {
-        format!("::ffff:{}", ip).parse().unwrap()
-    } else {
-        ip
+// Route Target and Route Origin subtypes of the transitive extended communities
+// https://datatracker.ietf.org/doc/html/rfc4360#section-4
+// https://datatracker.ietf.org/doc/html/rfc5701#section-3
+const EXT_COMMUNITY_ROUTE_TARGET: u8 = 0x02;
+const EXT_COMMUNITY_ROUTE_ORIGIN: u8 = 0x03;
+const EXT_COMMUNITY_TRANSITIVE_IPV6: u8 = 0x00;
+
+fn route_target_or_origin(subtype: u8, global_admin: String, local_admin: u32) -> Option<String> {
+    let kind = match subtype {
+        EXT_COMMUNITY_ROUTE_TARGET => "rt",
+        EXT_COMMUNITY_ROUTE_ORIGIN => "ro",
+        _ => return None,
+    };
+    Some(format!("{}:{}:{}", kind, global_admin, local_admin))
+}
+
+// Route Targets and Route Origins are rendered as `rt:admin:value` and `ro:admin:value`,
+// the administrator being an ASN, an IPv4 or a bracketed IPv6 address
+// The other extended communities are kept in the textual form of the BGP parser
+pub fn construct_ext_communities(communities: &[MetaCommunity]) -> Vec<String> {
+    let mut constructed_communities = Vec::new();
+    for community in communities {
+        let typed = match community {
+            MetaCommunity::Extended(ExtendedCommunity::TransitiveTwoOctetAs(ec)) => {
+                route_target_or_origin(
+                    ec.subtype,
+                    ec.global_admin.to_string(),
+                    u32::from_be_bytes(ec.local_admin),
+                )
+            }
+            MetaCommunity::Extended(ExtendedCommunity::TransitiveIpv4Addr(ec)) => {
+                route_target_or_origin(
+                    ec.subtype,
+                    ec.global_admin.to_string(),
+                    u16::from_be_bytes(ec.local_admin) as u32,
+                )
+            }
+            MetaCommunity::Extended(ExtendedCommunity::TransitiveFourOctetAs(ec)) => {
+                route_target_or_origin(
+                    ec.subtype,
+                    ec.global_admin.to_string(),
+                    u16::from_be_bytes(ec.local_admin) as u32,
+                )
+            }
+            MetaCommunity::Ipv6Extended(ec)
+                if u8::from(ec.community_type) == EXT_COMMUNITY_TRANSITIVE_IPV6 =>
+            {
+                // Bracketed, for the administrator to be told apart from the value
+                route_target_or_origin(
+                    ec.subtype,
+                    format!("[{}]", ec.global_admin),
+                    u16::from_be_bytes(ec.local_admin) as u32,
+                )
+            }
+            MetaCommunity::Extended(_) | MetaCommunity::Ipv6Extended(_) => None,
+            MetaCommunity::Plain(_) | MetaCommunity::Large(_) => continue,
+        };
+        constructed_communities.push(typed.unwrap_or_else(|| community.to_string()));
     }
+    constructed_communities
 }
 
-// Returns a CSV line corresponding to this schema
-// timestamp,router_addr,router_port,peer_addr,peer_bgp_id,peer_asn,prefix_addr,prefix_len,announced,is_post_policy,is_adj_rib_out,origin,path,communities,synthetic
-pub fn format_update(
-    router_addr: IpAddr,
-    router_port: u16,
-    peer: &Peer,
-    update: &mut Update,
-) -> String {
-    let as_path_str = construct_as_path(update.path.clone())
+// Well-known communities are rendered by name, custom ones as `asn:value`
+// https://www.iana.org/assignments/bgp-well-known-communities
+pub fn construct_communities_names(communities: &[MetaCommunity]) -> Vec<String> {
+    let mut constructed_communities = Vec::new();
+    for community in communities {
+        // TODO: handle the other community types
+        if let MetaCommunity::Plain(community) = community {
+            constructed_communities.push(match community {
+                Community::NoExport => "no-export".to_string(),
+                Community::NoAdvertise => "no-advertise".to_string(),
+                Community::NoExportSubConfed => "no-export-subconfed".to_string(),
+                Community::Custom(asn, value) => format!("{}:{}", asn, value),
+            });
+        }
+    }
+    constructed_communities
+}
+
+pub fn format_peer_type(peer_type: BmpPeerType) -> &'static str {
+    match peer_type {
+        BmpPeerType::Global => "global",
+        BmpPeerType::RD => "rd",
+        BmpPeerType::Local => "local",
+        BmpPeerType::LocalRib => "local-rib",
+    }
+}
+
+// Route Distinguisher of the peer, as administrator:assigned number
+// There is none for a zero distinguisher, or for an unknown type
+// https://datatracker.ietf.org/doc/html/rfc4364#section-4.2
+pub fn format_rd(peer_distinguisher: u64) -> Option<String> {
+    if peer_distinguisher == 0 {
+        return None;
+    }
+
+    let b = peer_distinguisher.to_be_bytes();
+    match u16::from_be_bytes([b[0], b[1]]) {
+        0 => Some(format!(
+            "{}:{}",
+            u16::from_be_bytes([b[2], b[3]]),
+            u32::from_be_bytes([b[4], b[5], b[6], b[7]])
+        )),
+        1 => Some(format!(
+            "{}:{}",
+            Ipv4Addr::new(b[2], b[3], b[4], b[5]),
+            u16::from_be_bytes([b[6], b[7]])
+        )),
+        2 => Some(format!(
+            "{}:{}",
+            u32::from_be_bytes([b[2], b[3], b[4], b[5]]),
+            u16::from_be_bytes([b[6], b[7]])
+        )),
+        _ => None,
+    }
+}
+
+// Map IPv4 addresses to IPv4-mapped IPv6 addresses
+// IPv6 addresses, including already mapped ones, are returned as is
+pub fn map_to_ipv6(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V6(ip.to_ipv6_mapped()),
+        IpAddr::V6(_) => ip,
+    }
+}
+
+// Hash stable across builds and restarts (the first 8 bytes of the SHA-256), unlike the hasher
+// of the standard library, for the decisions to be consistent from one collector to another
+pub fn stable_hash(data: &[u8]) -> u64 {
+    let hash = Sha256::digest(data);
+    u64::from_be_bytes(hash[..8].try_into().unwrap())
+}
+
+// Keyed hash (HMAC-SHA256) of an IPv4 address, into another IPv4 address
+pub fn anonymize_ipv4(ip: Ipv4Addr, key: &[u8]) -> Ipv4Addr {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(&ip.octets());
+    let hash = mac.finalize().into_bytes();
+    Ipv4Addr::new(hash[0], hash[1], hash[2], hash[3])
+}
+
+// Keyed hash of an address, keeping its address family
+pub fn anonymize_ip(ip: IpAddr, key: &[u8]) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(anonymize_ipv4(ip, key)),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(anonymize_ipv4(ip, key)),
+            None => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+                mac.update(&ip.octets());
+                let hash: [u8; 16] = mac.finalize().into_bytes()[..16].try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(hash))
+            }
+        },
+    }
+}
+
+// Router and peer addresses as output, anonymized if configured
+pub fn output_addr(ip: IpAddr, output: &OutputConfig) -> IpAddr {
+    let ip = match &output.anonymize_key {
+        Some(key) => anonymize_ip(ip, key),
+        None => ip,
+    };
+    output_family(ip, output)
+}
+
+// Addresses are emitted either all in IPv6, or in their native family
+pub fn output_family(ip: IpAddr, output: &OutputConfig) -> IpAddr {
+    match output.ipv4_mapped {
+        true => map_to_ipv6(ip),
+        false => ip.to_canonical(),
+    }
+}
+
+pub fn output_bgp_id(bgp_id: Ipv4Addr, output: &OutputConfig) -> Ipv4Addr {
+    match &output.anonymize_key {
+        Some(key) => anonymize_ipv4(bgp_id, key),
+        None => bgp_id,
+    }
+}
+
+// IPv4-mapped IPv6 addresses are IPv4 addresses as well
+pub fn is_ipv4(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(_) => true,
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().is_some(),
+    }
+}
+
+// Format an ASN in asplain or asdot notation
+// https://datatracker.ietf.org/doc/html/rfc5396
+pub fn format_asn(asn: u32, asn_format: AsnFormat) -> String {
+    match asn_format {
+        AsnFormat::Asdot if asn > u16::MAX as u32 => format!("{}.{}", asn >> 16, asn & 0xFFFF),
+        _ => asn.to_string(),
+    }
+}
+
+// Hex encoding of the raw BMP message
+pub fn format_raw_pdu(raw_pdu: &[u8]) -> String {
+    raw_pdu.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Returns a human-readable line, for troubleshooting
+pub fn format_update_text(update: &Update) -> String {
+    let path = construct_as_path(update.path.clone())
         .iter()
-        .map(|x| x.to_string())
+        .map(|asn| asn.to_string())
         .collect::<Vec<String>>()
-        .join(",");
-    let as_path_str = format!("\"[{}]\"", as_path_str);
-
-    let communities_str = construct_communities(update.communities.as_ref())
+        .join(" ");
+    let communities = construct_communities(&update.communities)
         .iter()
-        .map(|x| format!("({},{})", x.0, x.1))
+        .map(|(asn, value)| format!("{}:{}", asn, value))
         .collect::<Vec<String>>()
-        .join(",");
-    let communities_str = format!("\"[{}]\"", communities_str);
+        .join(" ");
+
+    format!(
+        "{} {} - {} AS{} - {} {} - path [{}] - communities [{}]",
+        update.timestamp.to_rfc3339(),
+        update.router_addr,
+        update.peer_addr,
+        update.peer_asn,
+        if update.announced { "A" } else { "W" },
+        update.prefix,
+        path,
+        communities
+    )
+}
+
+// Fields of the CSV schema, in order, also the keys of the JSON lines
+pub const UPDATE_FIELDS: [&str; 33] = [
+    "timestamp",
+    "router_addr",
+    "router_port",
+    "peer_addr",
+    "peer_bgp_id",
+    "peer_asn",
+    "prefix_addr",
+    "prefix_len",
+    "is_post_policy",
+    "is_adj_rib_out",
+    "announced",
+    "origin",
+    "path",
+    "communities",
+    "synthetic",
+    "peer_type",
+    "peer_distinguisher",
+    "rpki_status",
+    "as_path_has_loop",
+    "header_time_valid",
+    "otc_leak_suspect",
+    "raw_pdu",
+    "as_path_truncated",
+    "prefix_sid_label_index",
+    "prefix_sid_srv6_sid",
+    "seq",
+    "seq_epoch",
+    "peer_rd",
+    "as_path_prepend_count",
+    "ext_communities",
+    "med",
+    "local_pref",
+    "only_to_customer",
+];
+
+// Returns a JSON line with the same fields as the CSV schema
+pub fn format_update_json(update: &Update, output: &OutputConfig) -> String {
+    // ASNs are rendered as strings in asdot notation
+    let json_asn = |asn: u32| match output.asn_format {
+        AsnFormat::Asplain => serde_json::Value::from(asn),
+        AsnFormat::Asdot => serde_json::Value::from(format_asn(asn, output.asn_format)),
+    };
+
+    let mut line = serde_json::json!({
+        "timestamp": update.timestamp.timestamp_millis(),
+        "router_addr": output_addr(update.router_addr, output),
+        "router_port": update.router_port,
+        "peer_addr": output_addr(update.peer_addr, output),
+        "peer_bgp_id": output_bgp_id(update.peer_bgp_id, output),
+        "peer_asn": json_asn(update.peer_asn.to_u32()),
+        "prefix_addr": output_family(update.prefix.prefix.addr(), output),
+        "prefix_len": update.prefix.prefix.prefix_len(),
+        "is_post_policy": update.is_post_policy,
+        "is_adj_rib_out": update.is_adj_rib_out,
+        "announced": update.announced,
+        "origin": update.origin.to_string(),
+        "path": construct_as_path(update.path.clone())
+            .into_iter()
+            .map(json_asn)
+            .collect::<Vec<_>>(),
+        "communities": construct_communities_names(&update.communities),
+        "synthetic": update.synthetic,
+        "peer_type": format_peer_type(update.peer_type),
+        "peer_distinguisher": update.peer_distinguisher,
+        "rpki_status": update.rpki_status.map(|status| status.as_str()),
+        "as_path_has_loop": update.as_path_has_loop,
+        "as_path_truncated": update.as_path_truncated,
+        "header_time_valid": update.header_time_valid,
+        "otc_leak_suspect": update.otc_leak_suspect,
+        "raw_pdu": update.raw_pdu.as_deref().map(format_raw_pdu),
+        "prefix_sid_label_index": update.prefix_sid.and_then(|sid| sid.label_index),
+        "prefix_sid_srv6_sid": update.prefix_sid.and_then(|sid| sid.srv6_sid),
+        "seq": update.seq,
+        "seq_epoch": update.seq_epoch.timestamp_millis(),
+        "peer_rd": format_rd(update.peer_distinguisher),
+        "as_path_prepend_count": update.as_path_prepend_count,
+        "ext_communities": construct_ext_communities(&update.communities),
+        "med": update.med,
+        "local_pref": update.local_pref,
+        "only_to_customer": update.only_to_customer.map(json_asn),
+    });
+    if let Some(fields) = &output.fields {
+        line.as_object_mut()
+            .unwrap()
+            .retain(|field, _| fields.contains(field));
+    }
+    line.to_string()
+}
+
+// Split a CSV line into its columns, the quoted columns (AS path, communities) included
+fn csv_columns(line: &str) -> Vec<&str> {
+    let mut columns = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                columns.push(&line[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    columns.push(&line[start..]);
+    columns
+}
+
+// Parse a CSV line back against the schema, to catch a serializer out of sync with it
+// The inconsistencies are logged and counted, the line is still produced
+pub fn check_update(update: &Update, line: &str, output: &OutputConfig) {
+    let expected = output
+        .fields
+        .as_ref()
+        .map_or(UPDATE_FIELDS.len(), |fields| fields.len());
+    let columns = csv_columns(line);
+
+    let inconsistency = if line.contains('\n') {
+        Some("line break in the record".to_string())
+    } else if !line.matches('"').count().is_multiple_of(2) {
+        Some("unbalanced quotes".to_string())
+    } else if columns.len() != expected {
+        Some(format!("{} columns instead of {}", columns.len(), expected))
+    } else {
+        None
+    };
+
+    if let Some(inconsistency) = inconsistency {
+        error!(
+            "update - inconsistent serialization of {} from {}: {}",
+            update.prefix.prefix, update.peer_addr, inconsistency
+        );
+        counter!("risotto_serialization_errors_total").increment(1);
+    }
+}
+
+// Returns a CSV line corresponding to this schema
+// timestamp,router_addr,router_port,peer_addr,peer_bgp_id,peer_asn,prefix_addr,prefix_len,is_post_policy,is_adj_rib_out,announced,origin,path,communities,synthetic,peer_type,peer_distinguisher,rpki_status,as_path_has_loop,header_time_valid,otc_leak_suspect,raw_pdu,as_path_truncated,prefix_sid_label_index,prefix_sid_srv6_sid,seq,seq_epoch,peer_rd,as_path_prepend_count,ext_communities,med,local_pref,only_to_customer
+// Only the configured fields are kept, in the same order
+pub fn format_update(update: &Update, output: &OutputConfig) -> String {
+    let includes = |field: &str| output.fields.as_ref().is_none_or(|f| f.contains(field));
+
+    // The AS path and the communities are not even formatted if left out
+    let mut as_path_str = String::new();
+    if includes("path") {
+        // ASNs in asdot notation are quoted to be parsed as an array of strings
+        let asns = construct_as_path(update.path.clone())
+            .iter()
+            .map(|x| match output.asn_format {
+                AsnFormat::Asplain => x.to_string(),
+                AsnFormat::Asdot => format!("'{}'", format_asn(*x, output.asn_format)),
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        as_path_str = format!("\"[{}]\"", asns);
+    }
+
+    let mut communities_str = String::new();
+    if includes("communities") {
+        let communities = construct_communities(update.communities.as_ref())
+            .iter()
+            .map(|x| format!("({},{})", x.0, x.1))
+            .collect::<Vec<String>>()
+            .join(",");
+        communities_str = format!("\"[{}]\"", communities);
+    }
+
+    let mut ext_communities_str = String::new();
+    if includes("ext_communities") {
+        let communities = construct_ext_communities(update.communities.as_ref())
+            .iter()
+            .map(|x| format!("'{}'", x))
+            .collect::<Vec<String>>()
+            .join(",");
+        ext_communities_str = format!("\"[{}]\"", communities);
+    }
 
     let mut row: Vec<String> = Vec::new();
     row.push(format!("{}", update.timestamp.timestamp_millis()));
-    row.push(format!("{}", map_to_ipv6(router_addr)));
-    row.push(format!("{}", router_port));
-    row.push(format!("{}", map_to_ipv6(peer.peer_address)));
-    row.push(format!("{}", peer.peer_bgp_id));
-    row.push(format!("{}", peer.peer_asn));
-    row.push(format!("{}", map_to_ipv6(update.prefix.prefix.addr())));
+    row.push(format!("{}", output_addr(update.router_addr, output)));
+    row.push(format!("{}", update.router_port));
+    row.push(format!("{}", output_addr(update.peer_addr, output)));
+    row.push(format!("{}", output_bgp_id(update.peer_bgp_id, output)));
+    row.push(format_asn(update.peer_asn.to_u32(), output.asn_format));
+    row.push(format!(
+        "{}",
+        output_family(update.prefix.prefix.addr(), output)
+    ));
     row.push(format!("{}", update.prefix.prefix.prefix_len()));
     row.push(format!("{}", update.is_post_policy));
     row.push(format!("{}", update.is_adj_rib_out));
     row.push(format!("{}", update.announced));
     row.push(format!("{}", update.origin));
-    row.push(format!("{}", as_path_str));
-    row.push(format!("{}", communities_str));
+    row.push(as_path_str);
+    row.push(communities_str);
     row.push(format!("{}", update.synthetic));
+    row.push(format_peer_type(update.peer_type).to_string());
+    row.push(format!("{}", update.peer_distinguisher));
+    row.push(
+        update
+            .rpki_status
+            .map(|status| status.as_str())
+            .unwrap_or_default()
+            .to_string(),
+    );
+    row.push(format!("{}", update.as_path_has_loop));
+    row.push(format!("{}", update.header_time_valid));
+    row.push(format!("{}", update.otc_leak_suspect));
+    row.push(
+        update
+            .raw_pdu
+            .as_deref()
+            .map(format_raw_pdu)
+            .unwrap_or_default(),
+    );
+    row.push(format!("{}", update.as_path_truncated));
+    let prefix_sid = update.prefix_sid.unwrap_or(PrefixSid {
+        label_index: None,
+        srv6_sid: None,
+    });
+    row.push(
+        prefix_sid
+            .label_index
+            .map(|label_index| label_index.to_string())
+            .unwrap_or_default(),
+    );
+    row.push(
+        prefix_sid
+            .srv6_sid
+            .map(|sid| sid.to_string())
+            .unwrap_or_default(),
+    );
+    row.push(format!("{}", update.seq));
+    row.push(format!("{}", update.seq_epoch.timestamp_millis()));
+    row.push(format_rd(update.peer_distinguisher).unwrap_or_default());
+    row.push(format!("{}", update.as_path_prepend_count));
+    row.push(ext_communities_str);
+    // The optional attributes are left empty when absent, to not be confused with a zero
+    row.push(update.med.map(|med| med.to_string()).unwrap_or_default());
+    row.push(
+        update
+            .local_pref
+            .map(|local_pref| local_pref.to_string())
+            .unwrap_or_default(),
+    );
+    row.push(
+        update
+            .only_to_customer
+            .map(|asn| format_asn(asn, output.asn_format))
+            .unwrap_or_default(),
+    );
 
+    if output.fields.is_some() {
+        row = row
+            .into_iter()
+            .zip(UPDATE_FIELDS)
+            .filter(|(_, field)| includes(field))
+            .map(|(value, _)| value)
+            .collect();
+    }
     row.join(",")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::OutputConfig;
+    use bgpkit_parser::parser::bgp::parse_bgp_message;
+
+    #[test]
+    fn map_to_ipv6_ipv4() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let expected: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        assert_eq!(map_to_ipv6(ip), expected);
+    }
+
+    #[test]
+    fn map_to_ipv6_ipv6() {
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(map_to_ipv6(ip), ip);
+    }
+
+    #[test]
+    fn map_to_ipv6_ipv4_mapped() {
+        // Already mapped, so left as is rather than mapped twice
+        let ip: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        assert_eq!(map_to_ipv6(ip), ip);
+        assert_eq!(map_to_ipv6(ip), map_to_ipv6("192.0.2.1".parse().unwrap()));
+    }
+
+    fn header() -> UpdateHeader {
+        UpdateHeader {
+            router_addr: "10.0.0.1".parse().unwrap(),
+            router_port: 0,
+            peer: Peer::new(
+                Ipv4Addr::new(192, 0, 2, 254),
+                "192.0.2.1".parse().unwrap(),
+                Asn::new_32bit(65001),
+            ),
+            peer_type: BmpPeerType::Global,
+            peer_distinguisher: 0,
+            timestamp: None,
+            is_post_policy: false,
+            is_adj_rib_out: false,
+            raw: Bytes::new(),
+            received_at: Instant::now(),
+        }
+    }
+
+    fn decode(bgp_update: BgpUpdateMessage) -> Vec<Update> {
+        let message = RouteMonitoring {
+            bgp_message: BgpMessage::Update(bgp_update),
+        };
+        let decode = crate::settings::get_decode_config(&config::Config::default()).unwrap();
+        decode_updates(message, header(), None, &decode).unwrap()
+    }
+
+    #[test]
+    fn conflicting_nlri() {
+        let p1: NetworkPrefix = "198.51.100.0/24".parse().unwrap();
+        let p2: NetworkPrefix = "203.0.113.0/24".parse().unwrap();
+        let p3: NetworkPrefix = "2001:db8::/32".parse().unwrap();
+        let updates = decode(BgpUpdateMessage {
+            withdrawn_prefixes: vec![p1],
+            attributes: Attributes::from_iter([
+                AttributeValue::Origin(Origin::IGP),
+                AttributeValue::MpReachNlri(Nlri::new_reachable(
+                    p3,
+                    Some("2001:db8::1".parse().unwrap()),
+                )),
+                AttributeValue::MpUnreachNlri(Nlri::new_unreachable(p3)),
+            ]),
+            announced_prefixes: vec![p1, p2],
+        });
+
+        // The prefixes both announced and withdrawn are only withdrawn
+        let mut decoded: Vec<(IpNet, bool)> = updates
+            .iter()
+            .map(|update| (update.prefix.prefix, update.announced))
+            .collect();
+        decoded.sort();
+        assert_eq!(
+            decoded,
+            [(p1.prefix, false), (p2.prefix, true), (p3.prefix, false)]
+        );
+    }
+
+    // BGP Update announcing 198.51.100.0/24 with an IPv6 Address Specific Extended Community
+    fn ipv6_ext_community_update(subtype: u8) -> BgpUpdateMessage {
+        // ORIGIN IGP, AS_PATH 65001 and NEXT_HOP 192.0.2.1
+        let mut attributes = vec![0x40, 1, 1, 0];
+        attributes.extend([0x40, 2, 6, 2, 1, 0, 0, 0xfd, 0xe9]);
+        attributes.extend([0x40, 3, 4, 192, 0, 2, 1]);
+        // Transitive IPv6 Address Specific Extended Community, 2001:db8::1 and 100
+        attributes.extend([0xc0, 25, 20, EXT_COMMUNITY_TRANSITIVE_IPV6, subtype]);
+        attributes.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        attributes.extend(100u16.to_be_bytes());
+
+        let mut body = vec![0, 0];
+        body.extend((attributes.len() as u16).to_be_bytes());
+        body.extend(attributes);
+        body.extend([24, 198, 51, 100]);
+
+        let mut message = vec![0xff; 16];
+        message.extend((19 + body.len() as u16).to_be_bytes());
+        message.push(2);
+        message.extend(body);
+
+        let mut data = Bytes::from(message);
+        match parse_bgp_message(&mut data, false, &AsnLength::Bits32).unwrap() {
+            BgpMessage::Update(bgp_update) => bgp_update,
+            _ => panic!("not a BGP Update"),
+        }
+    }
+
+    #[test]
+    fn ipv6_route_target() {
+        let updates = decode(ipv6_ext_community_update(EXT_COMMUNITY_ROUTE_TARGET));
+        assert_eq!(updates.len(), 1);
+        assert_eq!(
+            construct_ext_communities(&updates[0].communities),
+            ["rt:[2001:db8::1]:100"]
+        );
+
+        let updates = decode(ipv6_ext_community_update(EXT_COMMUNITY_ROUTE_ORIGIN));
+        assert_eq!(
+            construct_ext_communities(&updates[0].communities),
+            ["ro:[2001:db8::1]:100"]
+        );
+    }
+
+    #[test]
+    fn ipv6_ext_community_fallback() {
+        // Other subtypes are kept in the textual form of the BGP parser
+        let updates = decode(ipv6_ext_community_update(0x0b));
+        let communities = construct_ext_communities(&updates[0].communities);
+        assert_eq!(communities, [updates[0].communities[0].to_string()]);
+    }
+
+    // Columns of the first version of the CSV schema, which the older readers index by position
+    const SCHEMA_V1: [&str; 15] = [
+        "timestamp",
+        "router_addr",
+        "router_port",
+        "peer_addr",
+        "peer_bgp_id",
+        "peer_asn",
+        "prefix_addr",
+        "prefix_len",
+        "is_post_policy",
+        "is_adj_rib_out",
+        "announced",
+        "origin",
+        "path",
+        "communities",
+        "synthetic",
+    ];
+
+    fn output_config() -> OutputConfig {
+        OutputConfig {
+            asn_format: AsnFormat::Asplain,
+            fields: None,
+            anonymize_key: None,
+            check_serialization: false,
+            ipv4_mapped: true,
+        }
+    }
+
+    // Update with all the fields set, to a value distinct from their default
+    fn full_update() -> Update {
+        let mut update = decode(ipv6_ext_community_update(EXT_COMMUNITY_ROUTE_TARGET)).remove(0);
+        update.timestamp = Utc.timestamp_millis_opt(1700000000123).unwrap();
+        update.router_port = 4000;
+        update.peer_distinguisher = (65001 << 32) | 100;
+        update.is_post_policy = true;
+        update.path = Some(AsPath::from_sequence([65001, 65002]));
+        update.communities.insert(
+            0,
+            MetaCommunity::Plain(Community::Custom(Asn::new_32bit(65001), 100)),
+        );
+        update.synthetic = true;
+        update.rpki_status = Some(RpkiStatus::Valid);
+        update.as_path_has_loop = true;
+        update.header_time_valid = true;
+        update.otc_leak_suspect = true;
+        update.raw_pdu = Some(Bytes::from_static(&[0xde, 0xad]));
+        update.as_path_truncated = true;
+        update.prefix_sid = Some(PrefixSid {
+            label_index: Some(16),
+            srv6_sid: Some("2001:db8::2".parse().unwrap()),
+        });
+        update.seq = 42;
+        update.seq_epoch = Utc.timestamp_millis_opt(1700000000000).unwrap();
+        update.as_path_prepend_count = 2;
+        update.med = Some(10);
+        update.local_pref = Some(200);
+        update.only_to_customer = Some(65002);
+        update
+    }
+
+    #[test]
+    fn csv_schema_append_only() {
+        // New fields are only appended, and never reuse the name of another one
+        assert_eq!(UPDATE_FIELDS[..SCHEMA_V1.len()], SCHEMA_V1);
+        let names: HashSet<&str> = UPDATE_FIELDS.iter().copied().collect();
+        assert_eq!(names.len(), UPDATE_FIELDS.len());
+    }
+
+    #[test]
+    fn csv_fidelity() {
+        let line = format_update(&full_update(), &output_config());
+        let columns = csv_columns(&line);
+        assert_eq!(columns.len(), UPDATE_FIELDS.len());
+
+        let expected = [
+            "1700000000123",
+            "::ffff:10.0.0.1",
+            "4000",
+            "::ffff:192.0.2.1",
+            "192.0.2.254",
+            "65001",
+            "::ffff:198.51.100.0",
+            "24",
+            "true",
+            "false",
+            "true",
+            "IGP",
+            "\"[65001,65002]\"",
+            "\"[(65001,100)]\"",
+            "true",
+            "global",
+            "279177169207396",
+            "valid",
+            "true",
+            "true",
+            "true",
+            "dead",
+            "true",
+            "16",
+            "2001:db8::2",
+            "42",
+            "1700000000000",
+            "65001:100",
+            "2",
+            "\"['rt:[2001:db8::1]:100']\"",
+            "10",
+            "200",
+            "65002",
+        ];
+        for ((field, column), value) in UPDATE_FIELDS.iter().zip(&columns).zip(expected) {
+            assert_eq!(*column, value, "field {}", field);
+        }
+    }
+
+    #[test]
+    fn csv_older_reader() {
+        // A reader of the first schema parses its columns, ignoring the ones appended since
+        let update = full_update();
+        let line = format_update(&update, &output_config());
+        let columns = csv_columns(&line);
+
+        assert_eq!(
+            columns[0].parse::<i64>().unwrap(),
+            update.timestamp.timestamp_millis()
+        );
+        assert_eq!(
+            columns[1].parse::<IpAddr>().unwrap(),
+            map_to_ipv6(update.router_addr)
+        );
+        assert_eq!(columns[2].parse::<u16>().unwrap(), update.router_port);
+        assert_eq!(
+            columns[3].parse::<IpAddr>().unwrap(),
+            map_to_ipv6(update.peer_addr)
+        );
+        assert_eq!(columns[4].parse::<Ipv4Addr>().unwrap(), update.peer_bgp_id);
+        assert_eq!(columns[5].parse::<u32>().unwrap(), update.peer_asn.to_u32());
+        assert_eq!(
+            columns[6].parse::<IpAddr>().unwrap(),
+            map_to_ipv6(update.prefix.prefix.addr())
+        );
+        assert_eq!(
+            columns[7].parse::<u8>().unwrap(),
+            update.prefix.prefix.prefix_len()
+        );
+        assert_eq!(columns[8].parse::<bool>().unwrap(), update.is_post_policy);
+        assert_eq!(columns[9].parse::<bool>().unwrap(), update.is_adj_rib_out);
+        assert_eq!(columns[10].parse::<bool>().unwrap(), update.announced);
+        assert_eq!(columns[11], update.origin.to_string());
+        assert_eq!(columns[12], "\"[65001,65002]\"");
+        assert_eq!(columns[13], "\"[(65001,100)]\"");
+        assert_eq!(columns[14].parse::<bool>().unwrap(), update.synthetic);
+    }
+
+    #[test]
+    fn csv_selected_fields() {
+        let mut output = output_config();
+        output.fields = Some(HashSet::from([
+            "med".to_string(),
+            "prefix_len".to_string(),
+            "timestamp".to_string(),
+        ]));
+
+        // The selected fields keep the order of the schema
+        let line = format_update(&full_update(), &output);
+        assert_eq!(line, "1700000000123,24,10");
+    }
+
+    #[test]
+    fn json_fidelity() {
+        let line = format_update_json(&full_update(), &output_config());
+        let parsed: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+        // Same fields as the CSV schema, with their types
+        let names: HashSet<&str> = parsed
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|key| key.as_str())
+            .collect();
+        assert_eq!(names, UPDATE_FIELDS.iter().copied().collect());
+        assert_eq!(
+            parsed,
+            serde_json::json!({
+                "timestamp": 1700000000123i64,
+                "router_addr": "::ffff:10.0.0.1",
+                "router_port": 4000,
+                "peer_addr": "::ffff:192.0.2.1",
+                "peer_bgp_id": "192.0.2.254",
+                "peer_asn": 65001,
+                "prefix_addr": "::ffff:198.51.100.0",
+                "prefix_len": 24,
+                "is_post_policy": true,
+                "is_adj_rib_out": false,
+                "announced": true,
+                "origin": "IGP",
+                "path": [65001, 65002],
+                "communities": ["65001:100"],
+                "synthetic": true,
+                "peer_type": "global",
+                "peer_distinguisher": 279177169207396u64,
+                "rpki_status": "valid",
+                "as_path_has_loop": true,
+                "as_path_truncated": true,
+                "header_time_valid": true,
+                "otc_leak_suspect": true,
+                "raw_pdu": "dead",
+                "prefix_sid_label_index": 16,
+                "prefix_sid_srv6_sid": "2001:db8::2",
+                "seq": 42,
+                "seq_epoch": 1700000000000i64,
+                "peer_rd": "65001:100",
+                "as_path_prepend_count": 2,
+                "ext_communities": ["rt:[2001:db8::1]:100"],
+                "med": 10,
+                "local_pref": 200,
+                "only_to_customer": 65002,
+            })
+        );
+    }
+
+    #[test]
+    fn json_older_reader() {
+        // A reader of the first schema, ignoring the fields added since
+        #[derive(serde::Deserialize)]
+        struct UpdateV1 {
+            timestamp: i64,
+            router_addr: IpAddr,
+            router_port: u16,
+            peer_addr: IpAddr,
+            peer_bgp_id: Ipv4Addr,
+            peer_asn: u32,
+            prefix_addr: IpAddr,
+            prefix_len: u8,
+            is_post_policy: bool,
+            is_adj_rib_out: bool,
+            announced: bool,
+            origin: String,
+            path: Vec<u32>,
+            communities: Vec<String>,
+            synthetic: bool,
+        }
+
+        let update = full_update();
+        let line = format_update_json(&update, &output_config());
+        let v1: UpdateV1 = serde_json::from_str(&line).unwrap();
+        assert_eq!(v1.timestamp, update.timestamp.timestamp_millis());
+        assert_eq!(v1.router_addr, map_to_ipv6(update.router_addr));
+        assert_eq!(v1.router_port, update.router_port);
+        assert_eq!(v1.peer_addr, map_to_ipv6(update.peer_addr));
+        assert_eq!(v1.peer_bgp_id, update.peer_bgp_id);
+        assert_eq!(v1.peer_asn, update.peer_asn.to_u32());
+        assert_eq!(v1.prefix_addr, map_to_ipv6(update.prefix.prefix.addr()));
+        assert_eq!(v1.prefix_len, update.prefix.prefix.prefix_len());
+        assert_eq!(v1.is_post_policy, update.is_post_policy);
+        assert_eq!(v1.is_adj_rib_out, update.is_adj_rib_out);
+        assert_eq!(v1.announced, update.announced);
+        assert_eq!(v1.origin, update.origin.to_string());
+        assert_eq!(v1.path, [65001, 65002]);
+        assert_eq!(v1.communities, ["65001:100"]);
+        assert_eq!(v1.synthetic, update.synthetic);
+    }
+}
@@ -16,6 +16,24 @@ CREATE TABLE risotto.from_kafka
 	path Array(UInt32),
 	communities Array(Tuple(UInt32, UInt16)),
 	synthetic bool,
+	peer_type String,
+	peer_distinguisher UInt64,
+	rpki_status LowCardinality(String),
+	as_path_has_loop bool,
+	header_time_valid bool,
+	otc_leak_suspect bool,
+	raw_pdu String,
+	as_path_truncated bool,
+	prefix_sid_label_index Nullable(UInt32),
+	prefix_sid_srv6_sid Nullable(IPv6),
+	seq UInt64,
+	seq_epoch DateTime64,
+	peer_rd LowCardinality(String),
+	as_path_prepend_count UInt8,
+	ext_communities Array(String),
+	med Nullable(UInt32),
+	local_pref Nullable(UInt32),
+	only_to_customer Nullable(UInt32),
 )
 ENGINE = Kafka()
 SETTINGS
@@ -41,6 +59,24 @@ CREATE TABLE risotto.updates
 	path Array(UInt32),
 	communities Array(Tuple(UInt32, UInt16)),
 	synthetic bool,
+	peer_type String,
+	peer_distinguisher UInt64,
+	rpki_status LowCardinality(String),
+	as_path_has_loop bool,
+	header_time_valid bool,
+	otc_leak_suspect bool,
+	raw_pdu String,
+	as_path_truncated bool,
+	prefix_sid_label_index Nullable(UInt32),
+	prefix_sid_srv6_sid Nullable(IPv6),
+	seq UInt64,
+	seq_epoch DateTime64,
+	peer_rd LowCardinality(String),
+	as_path_prepend_count UInt8,
+	ext_communities Array(String),
+	med Nullable(UInt32),
+	local_pref Nullable(UInt32),
+	only_to_customer Nullable(UInt32),
 )
 ENGINE = MergeTree()
 ORDER BY (timestamp, router_addr, peer_addr, prefix_addr, prefix_len)